@@ -0,0 +1,125 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// Client settings of a TOML file, keys are named after the command line options
+///
+/// ```toml
+/// balance = "round-robin"
+///
+/// [[servers]]
+/// address = "example.com:443"
+///
+/// [[servers]]
+/// address = "203.0.113.2:443"
+/// sni = "backup.example.com"
+/// cert = "backup.pem"
+///
+/// [[listeners]]
+/// inbound = "mixed"
+/// listen = "127.0.0.1:1080"
+/// rate-limit = 1000000
+///
+/// [routing]
+/// rules = ["DOMAIN-SUFFIX,example.org,DIRECT", "FINAL,PROXY"]
+///
+/// [auth]
+/// username = "username"
+/// password = "password"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub servers: Vec<Server>,
+    pub balance: Option<String>,
+    pub probe_interval: Option<u64>,
+
+    pub listeners: Vec<Listener>,
+    pub forwards: Vec<String>,
+    pub reverses: Vec<String>,
+    pub tun: Option<Tun>,
+    pub routing: Option<Routing>,
+    pub auth: Option<Auth>,
+
+    pub bind: Option<String>,
+    pub fwmark: Option<u32>,
+    pub tls_sni: Option<String>,
+    pub tls_cert: Option<String>,
+    pub pool_size: Option<usize>,
+    pub connection_max_streams: Option<usize>,
+    pub connection_max_age: Option<u64>,
+    pub connection_max_bytes: Option<u64>,
+    pub request_attempts: Option<usize>,
+    pub request_timeout: Option<u64>,
+    pub drain_timeout: Option<u64>,
+    pub rate_limit: Option<u64>,
+    pub stream_rate_limit: Option<u64>,
+    pub limit_concurrent_instances: Option<usize>,
+    pub initial_congestion_window: Option<u32>,
+    pub tracing_level: Option<String>,
+}
+
+/// Remote server, TLS settings default to the top-level ones
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Server {
+    pub address: String,
+    pub sni: Option<String>,
+    pub cert: Option<String>,
+}
+
+/// Local proxy listener, its rate limit is shared by its streams
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Listener {
+    #[serde(default = "Listener::default_inbound")]
+    pub inbound: String,
+    pub listen: String,
+    pub rate_limit: Option<u64>,
+}
+
+impl Listener {
+    fn default_inbound() -> String {
+        "socks".to_string()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Tun {
+    pub name: String,
+    pub address: Option<String>,
+    pub mtu: Option<usize>,
+}
+
+/// Routing rules, written inline or kept in a rules file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Routing {
+    pub rules: Vec<String>,
+    pub rules_file: Option<String>,
+}
+
+/// Account on the remote server and accounts required from local proxy clients
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Auth {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub proxy_users: Vec<String>,
+    pub proxy_users_file: Option<String>,
+}
+
+impl Config {
+    /// Loads the file, unknown keys are rejected
+    pub fn load<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let content = std::fs::read_to_string(path)?;
+
+        toml::from_str(&content)
+            .map_err(|error| Error::new(ErrorKind::InvalidData, error.to_string()))
+    }
+}
@@ -0,0 +1,237 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::warn;
+
+/// Consecutive connection failures after which a server is considered unhealthy
+const UNHEALTHY_AFTER: u32 = 2;
+
+/// Delay before an unhealthy server is retried, doubled on every further failure
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Weight of a new sample in the latency moving average, in eighths
+const LATENCY_WEIGHT: u32 = 2;
+
+/// How the next connection picks its server among the healthy ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// The first server in order, the following ones are backups
+    Failover,
+
+    /// Every server in turn
+    RoundRobin,
+
+    /// The server with the lowest measured latency, see [`Status::estimated_latency`],
+    /// unmeasured ones are only tried when no other is healthy
+    LowestLatency,
+}
+
+/// Health of a remote server
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub server_name: String,
+    pub server_addr: SocketAddr,
+
+    /// Consecutive connection failures
+    pub failures: u32,
+
+    /// Smoothed latency of the server, from connection handshakes and probes
+    pub latency: Option<Duration>,
+
+    /// Round trip of the last probe
+    pub rtt: Option<Duration>,
+
+    /// QUIC estimate of the round trip on the probing connection
+    pub quic_rtt: Option<Duration>,
+
+    /// When the server is tried again, if unhealthy
+    pub retry_at: Option<Instant>,
+}
+
+impl Status {
+    pub fn is_healthy(&self) -> bool {
+        self.retry_at.is_none()
+    }
+
+    /// QUIC estimate of the round trip when probed, the smoothed latency otherwise
+    pub fn estimated_latency(&self) -> Option<Duration> {
+        self.quic_rtt.or(self.latency)
+    }
+}
+
+struct Inner {
+    servers: Vec<Status>,
+    strategy: Strategy,
+    next: usize,
+}
+
+/// Server selection shared by the connection builder and whoever measures the servers
+#[derive(Clone)]
+pub struct Balancer {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Balancer {
+    pub fn new<I>(servers: I, strategy: Strategy) -> Self
+    where
+        I: IntoIterator<Item = (String, SocketAddr)>,
+    {
+        let servers = servers
+            .into_iter()
+            .map(|(server_name, server_addr)| Status {
+                server_name,
+                server_addr,
+                failures: 0,
+                latency: None,
+                rtt: None,
+                quic_rtt: None,
+                retry_at: None,
+            })
+            .collect();
+
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                servers,
+                strategy,
+                next: 0,
+            })),
+        }
+    }
+
+    /// Index of the server to connect to, or when the first unhealthy one is due for a retry
+    pub fn select(&self) -> Result<usize, Instant> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        for server in inner.servers.iter_mut() {
+            if server.retry_at.is_some_and(|value| value <= now) {
+                server.retry_at = None;
+            }
+        }
+
+        let count = inner.servers.len();
+        let healthy = |index: &usize| inner.servers[*index].is_healthy();
+
+        let selected = match inner.strategy {
+            Strategy::Failover => (0..count).find(healthy),
+            Strategy::RoundRobin => (0..count).map(|i| (inner.next + i) % count).find(healthy),
+            Strategy::LowestLatency => (0..count).filter(healthy).min_by_key(|index| {
+                inner.servers[*index]
+                    .estimated_latency()
+                    .unwrap_or(Duration::MAX)
+            }),
+        };
+
+        match selected {
+            Some(index) => {
+                inner.next = index + 1;
+                Ok(index)
+            }
+            None => Err(inner
+                .servers
+                .iter()
+                .filter_map(|server| server.retry_at)
+                .min()
+                .unwrap_or(now)),
+        }
+    }
+
+    /// Records an established connection and how long it took
+    pub fn success(&self, index: usize, latency: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        let server = &mut inner.servers[index];
+
+        server.failures = 0;
+        server.retry_at = None;
+        update(&mut server.latency, latency);
+    }
+
+    /// Records a probe round trip, a server answering probes is healthy again
+    pub fn probed(&self, index: usize, rtt: Duration, quic_rtt: Option<Duration>) {
+        let mut inner = self.inner.lock().unwrap();
+        let server = &mut inner.servers[index];
+
+        server.failures = 0;
+        server.retry_at = None;
+        server.rtt = Some(rtt);
+        server.quic_rtt = quic_rtt;
+        update(&mut server.latency, rtt);
+    }
+
+    /// Records a failed connection or probe, the server is put aside once it failed too often
+    pub fn failure(&self, index: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        let server = &mut inner.servers[index];
+
+        server.failures += 1;
+
+        if let Some(exponent) = server.failures.checked_sub(UNHEALTHY_AFTER) {
+            let backoff = BACKOFF_MIN
+                .saturating_mul(1 << exponent.min(16))
+                .min(BACKOFF_MAX);
+            server.retry_at = Some(Instant::now() + backoff);
+
+            warn!(
+                "{} ({}) unhealthy after {} failures, retried in {:?}",
+                server.server_name, server.server_addr, server.failures, backoff
+            );
+        }
+    }
+
+    pub fn status(&self) -> Vec<Status> {
+        self.inner.lock().unwrap().servers.clone()
+    }
+}
+
+fn update(average: &mut Option<Duration>, sample: Duration) {
+    *average = Some(match *average {
+        Some(value) => (value * (8 - LATENCY_WEIGHT) + sample * LATENCY_WEIGHT) / 8,
+        None => sample,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balancer(count: usize) -> Balancer {
+        let servers = (0..count).map(|index| {
+            let address = SocketAddr::from(([192, 0, 2, index as u8], 443));
+            (format!("server{}", index), address)
+        });
+
+        Balancer::new(servers, Strategy::LowestLatency)
+    }
+
+    #[test]
+    fn lowest_latency() {
+        let milliseconds = Duration::from_millis;
+
+        // Measured servers are preferred to unmeasured ones
+        let balancer = balancer(3);
+        balancer.success(2, milliseconds(80));
+        assert_eq!(balancer.select(), Ok(2));
+
+        balancer.success(1, milliseconds(20));
+        assert_eq!(balancer.select(), Ok(1));
+
+        // The QUIC estimate of a probe takes precedence over handshake latencies
+        balancer.probed(2, milliseconds(40), Some(milliseconds(10)));
+        assert_eq!(balancer.select(), Ok(2));
+
+        balancer.probed(2, milliseconds(40), None);
+        assert_eq!(balancer.select(), Ok(1));
+
+        // Unmeasured servers are still tried when no other is healthy
+        for index in [1, 2] {
+            for _ in 0..UNHEALTHY_AFTER {
+                balancer.failure(index);
+            }
+        }
+        assert_eq!(balancer.select(), Ok(0));
+    }
+}
@@ -2,14 +2,16 @@ use std::net::SocketAddr;
 
 use quics_protocol::Provider;
 
+use super::balance::{Balancer, Strategy};
+
 pub struct Connection<T> {
     inner: T,
 }
 
 pub struct Builder<T> {
-    client: T,
-    server_name: String,
-    server_addr: SocketAddr,
+    servers: Vec<(T, String, SocketAddr)>,
+    strategy: Strategy,
+    balancer: Option<Balancer>,
 }
 
 mod s2n_quic {
@@ -19,10 +21,11 @@ mod s2n_quic {
     };
     use tokio::sync::mpsc;
     use tokio::sync::mpsc::Receiver;
+    use tokio::time::{sleep_until, Instant};
 
-    use crate::{debug, error};
+    use crate::{debug, error, warn};
 
-    use super::{Builder, Connection, Provider, SocketAddr};
+    use super::{Balancer, Builder, Connection, Provider, SocketAddr, Strategy};
 
     impl<T> Builder<T> {
         pub fn new<U>(client: T, server_name: String, server_addr: U) -> Self
@@ -30,28 +33,86 @@ mod s2n_quic {
             U: Into<SocketAddr>,
         {
             Self {
-                client,
-                server_name,
-                server_addr: server_addr.into(),
+                servers: vec![(client, server_name, server_addr.into())],
+                strategy: Strategy::Failover,
+                balancer: None,
             }
         }
 
-        pub fn build(self) -> impl Provider<NoiseConnection>
+        /// Adds a server, connected to with its own clients
+        pub fn with_server<U>(mut self, client: T, server_name: String, server_addr: U) -> Self
+        where
+            U: Into<SocketAddr>,
+        {
+            self.servers.push((client, server_name, server_addr.into()));
+
+            self
+        }
+
+        pub fn with_strategy(mut self, strategy: Strategy) -> Self {
+            self.strategy = strategy;
+
+            self
+        }
+
+        /// Server selection of the built connections, e.g. for probing, once every server is added
+        pub fn balancer(&mut self) -> Balancer {
+            self.balancer
+                .get_or_insert_with(|| {
+                    Balancer::new(
+                        self.servers.iter().map(|(_, server_name, server_addr)| {
+                            (server_name.clone(), *server_addr)
+                        }),
+                        self.strategy,
+                    )
+                })
+                .clone()
+        }
+
+        pub fn build(mut self) -> impl Provider<NoiseConnection>
         where
             T: IntoIterator<Item = NoiseClient> + Send + 'static,
             <T as IntoIterator>::IntoIter: Clone + Send,
         {
             let (connection_sender, connection_receiver) = mpsc::channel(1usize);
-            let server_name = self.server_name;
-            let server_addr = self.server_addr;
-            let clients = self.client.into_iter().cycle();
+
+            let balancer = self.balancer();
+
+            let mut servers = self
+                .servers
+                .into_iter()
+                .map(|(client, server_name, server_addr)| {
+                    (client.into_iter().cycle(), server_name, server_addr)
+                })
+                .collect::<Vec<_>>();
 
             tokio::spawn(async move {
-                'connection: for client in clients {
-                    if let Some(connection) = connect(&client, &server_name, server_addr).await {
-                        if connection_sender.send(connection).await.is_err() {
-                            break 'connection;
+                'connection: loop {
+                    let index = match balancer.select() {
+                        Ok(index) => index,
+                        Err(retry_at) => {
+                            warn!("no healthy server, waiting for a retry");
+                            sleep_until(retry_at).await;
+                            continue 'connection;
+                        }
+                    };
+
+                    let (clients, server_name, server_addr) = &mut servers[index];
+                    let Some(client) = clients.next() else {
+                        break 'connection;
+                    };
+
+                    let start = Instant::now();
+
+                    match connect(&client, server_name, *server_addr).await {
+                        Some(connection) => {
+                            balancer.success(index, start.elapsed());
+
+                            if connection_sender.send(connection).await.is_err() {
+                                break 'connection;
+                            }
                         }
+                        None => balancer.failure(index),
                     }
                 }
             });
@@ -1,2 +1,5 @@
+pub mod balance;
 pub mod connection;
+pub mod probe;
+pub mod rtt;
 pub mod stream;
@@ -0,0 +1,143 @@
+use std::time::Duration;
+
+use quics_protocol::auth::Authentication;
+
+use super::balance::Balancer;
+
+/// Upper bound of a probe, connection establishment included
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pings every remote server on a connection of its own and records the round
+/// trips in the balancer, so that selection and health follow the probes.
+pub struct Prober<T> {
+    clients: Vec<T>,
+    balancer: Balancer,
+    authentication: Authentication,
+    interval: Duration,
+}
+
+mod s2n_quic {
+    use s2n_quic::{
+        client::{Client as NoiseClient, Connect as NoiseConnect},
+        Connection as NoiseConnection,
+    };
+    use tokio::time::{interval, timeout, MissedTickBehavior};
+
+    use crate::connect::rtt::smoothed_rtt;
+    use crate::{info, warn};
+
+    use super::{Authentication, Balancer, Duration, Prober, PROBE_TIMEOUT};
+
+    impl Prober<NoiseClient> {
+        /// `clients` holds a client for every server of the balancer, in the same order
+        pub fn new(
+            clients: Vec<NoiseClient>,
+            balancer: Balancer,
+            authentication: Authentication,
+            interval: Duration,
+        ) -> Self {
+            Self {
+                clients,
+                balancer,
+                authentication,
+                interval,
+            }
+        }
+
+        pub fn start(self) {
+            for (index, client) in self.clients.into_iter().enumerate() {
+                tokio::spawn(probe(
+                    index,
+                    client,
+                    self.balancer.clone(),
+                    self.authentication.clone(),
+                    self.interval,
+                ));
+            }
+        }
+    }
+
+    async fn probe(
+        index: usize,
+        client: NoiseClient,
+        balancer: Balancer,
+        authentication: Authentication,
+        period: Duration,
+    ) {
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut connection = None;
+
+        loop {
+            ticker.tick().await;
+
+            let Some(status) = balancer.status().into_iter().nth(index) else {
+                return;
+            };
+
+            let result = timeout(
+                PROBE_TIMEOUT,
+                ping(
+                    &client,
+                    &mut connection,
+                    &status.server_name,
+                    status.server_addr,
+                    &authentication,
+                ),
+            )
+            .await;
+
+            match result {
+                Ok(Ok(rtt)) => {
+                    let quic_rtt = connection.as_ref().and_then(smoothed_rtt);
+                    balancer.probed(index, rtt, quic_rtt);
+
+                    info!(
+                        "PROBE {} ({}) rtt {:?} quic rtt {:?}",
+                        status.server_name, status.server_addr, rtt, quic_rtt
+                    );
+                }
+                Ok(Err(_error)) => {
+                    warn!(
+                        "PROBE {} ({}) failed. {}",
+                        status.server_name, status.server_addr, _error
+                    );
+                    connection = None;
+                    balancer.failure(index);
+                }
+                Err(_) => {
+                    warn!(
+                        "PROBE {} ({}) timed out",
+                        status.server_name, status.server_addr
+                    );
+                    connection = None;
+                    balancer.failure(index);
+                }
+            }
+        }
+    }
+
+    /// Pings on the probing connection, established first if needed
+    async fn ping(
+        client: &NoiseClient,
+        connection: &mut Option<NoiseConnection>,
+        server_name: &str,
+        server_addr: std::net::SocketAddr,
+        authentication: &Authentication,
+    ) -> std::io::Result<Duration> {
+        let connection = match connection {
+            Some(value) => value,
+            None => {
+                let connect = NoiseConnect::new(server_addr).with_server_name(server_name);
+                let mut value = client.connect(connect).await?;
+                value.keep_alive(true)?;
+                connection.insert(value)
+            }
+        };
+
+        let mut stream = connection.open_bidirectional_stream().await?;
+
+        quics_protocol::client::ping(&mut stream, authentication).await
+    }
+}
@@ -0,0 +1,42 @@
+use std::time::Duration;
+
+use s2n_quic::provider::event::{events, ConnectionInfo, ConnectionMeta, Subscriber};
+use s2n_quic::Connection as NoiseConnection;
+
+/// Event subscriber keeping the QUIC round trip estimate of every connection
+#[derive(Debug, Default)]
+pub struct RttSubscriber;
+
+#[derive(Debug, Default)]
+pub struct Rtt {
+    smoothed: Option<Duration>,
+}
+
+impl Subscriber for RttSubscriber {
+    type ConnectionContext = Rtt;
+
+    fn create_connection_context(
+        &mut self,
+        _meta: &ConnectionMeta,
+        _info: &ConnectionInfo,
+    ) -> Self::ConnectionContext {
+        Rtt::default()
+    }
+
+    fn on_recovery_metrics(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        event: &events::RecoveryMetrics,
+    ) {
+        context.smoothed = Some(event.smoothed_rtt);
+    }
+}
+
+/// Smoothed round trip of a connection of a client started with [`RttSubscriber`]
+pub fn smoothed_rtt(connection: &NoiseConnection) -> Option<Duration> {
+    connection
+        .query_event_context(|context: &Rtt| context.smoothed)
+        .ok()
+        .flatten()
+}
@@ -1,34 +1,231 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use quics_protocol::shutdown::Shutdown;
 use quics_protocol::Provider;
+use tokio::sync::Notify;
 
 pub struct Stream<T> {
     inner: T,
 }
 
+/// Limits of a pooled connection, checked whenever a stream is placed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Policy {
+    /// Streams in use at the same time on a connection, further ones go to another connection
+    pub max_streams: Option<usize>,
+
+    /// Age after which a connection is replaced, it is drained once its replacement is up
+    pub max_age: Option<Duration>,
+
+    /// Bytes carried in both directions after which a connection is replaced
+    pub max_bytes: Option<u64>,
+}
+
 pub struct Builder<T> {
     connection: T,
 
-    #[cfg(feature = "limit-connection-reuses")]
-    connection_reuses: Option<usize>,
+    /// Receives the streams opened by the server, dropped when nobody asked for them
+    accepted: Option<tokio::sync::mpsc::Sender<::s2n_quic::stream::BidirectionalStream>>,
+
+    /// Warm connections the streams are spread on
+    pool_size: usize,
+
+    policy: Policy,
+
+    shutdown: Shutdown,
 }
 
 impl<T> Builder<T> {
-    #[cfg(feature = "limit-connection-reuses")]
-    pub fn with_connection_reuses(mut self, value: Option<usize>) -> Self {
-        self.connection_reuses = value;
+    pub fn with_pool_size(mut self, value: usize) -> Self {
+        self.pool_size = value.max(1);
+
+        self
+    }
+
+    pub fn with_policy(mut self, value: Policy) -> Self {
+        self.policy = value;
+
+        self
+    }
+
+    /// Pooled connections are closed with `SHUTDOWN_ERROR_CODE` at the end
+    pub fn with_shutdown(mut self, value: Shutdown) -> Self {
+        self.shutdown = value;
 
         self
     }
 }
 
+/// Streams in use and bytes carried on a connection, kept up to date by its streams
+#[derive(Debug)]
+struct Usage {
+    streams: AtomicUsize,
+    bytes: AtomicU64,
+
+    /// Wakes the pool up when a stream is done, a full connection may take another one
+    changed: Arc<Notify>,
+}
+
+impl Usage {
+    fn new(changed: Arc<Notify>) -> Self {
+        Self {
+            streams: AtomicUsize::new(0),
+            bytes: AtomicU64::new(0),
+            changed,
+        }
+    }
+
+    fn streams(&self) -> usize {
+        self.streams.load(Ordering::Relaxed)
+    }
+
+    fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
 mod s2n_quic {
+    use std::io::Result;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+
+    use quics_protocol::shutdown::SHUTDOWN_ERROR_CODE;
+    use s2n_quic::application;
+    use s2n_quic::connection::{Handle, StreamAcceptor};
     use s2n_quic::stream::BidirectionalStream;
     use s2n_quic::Connection as NoiseConnection;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
     use tokio::sync::mpsc;
     use tokio::sync::mpsc::Receiver;
+    use tokio::task::JoinHandle;
+    use tokio::time::{sleep_until, Instant};
 
     use crate::{debug, error};
 
-    use super::{Builder, Provider, Stream};
+    use super::{Builder, Notify, Policy, Provider, Shutdown, Stream, Usage};
+
+    /// Stream opened on a pooled connection, counted in the usage of the connection
+    /// from the time it is being opened
+    pub struct PooledStream {
+        inner: BidirectionalStream,
+        usage: Arc<Usage>,
+    }
+
+    impl Drop for PooledStream {
+        fn drop(&mut self) {
+            self.usage.streams.fetch_sub(1, Ordering::Relaxed);
+            self.usage.changed.notify_one();
+        }
+    }
+
+    impl AsyncRead for PooledStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<Result<()>> {
+            let filled = buf.filled().len();
+            let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+            if let Poll::Ready(Ok(())) = poll {
+                let read = (buf.filled().len() - filled) as u64;
+                self.usage.bytes.fetch_add(read, Ordering::Relaxed);
+            }
+
+            poll
+        }
+    }
+
+    impl AsyncWrite for PooledStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize>> {
+            let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+
+            if let Poll::Ready(Ok(written)) = poll {
+                self.usage
+                    .bytes
+                    .fetch_add(written as u64, Ordering::Relaxed);
+            }
+
+            poll
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Connection of the pool, with what tells whether it should still get streams
+    struct Pooled {
+        handle: Handle,
+        acceptor: JoinHandle<()>,
+        usage: Arc<Usage>,
+        closed: Arc<AtomicBool>,
+        created_at: Instant,
+
+        /// Replaced by a newer connection, only used until the replacement is up
+        retiring: bool,
+    }
+
+    impl Pooled {
+        fn new(
+            connection: NoiseConnection,
+            accepted: Option<mpsc::Sender<BidirectionalStream>>,
+            changed: Arc<Notify>,
+        ) -> Self {
+            let (handle, acceptor) = connection.split();
+            let closed = Arc::new(AtomicBool::new(false));
+
+            let acceptor = tokio::spawn({
+                let closed = closed.clone();
+                let changed = changed.clone();
+                async move {
+                    accept(acceptor, accepted).await;
+                    closed.store(true, Ordering::Relaxed);
+                    changed.notify_one();
+                }
+            });
+
+            Self {
+                handle,
+                acceptor,
+                usage: Arc::new(Usage::new(changed)),
+                closed,
+                created_at: Instant::now(),
+                retiring: false,
+            }
+        }
+
+        fn is_closed(&self) -> bool {
+            self.closed.load(Ordering::Relaxed)
+        }
+
+        /// Whether another stream may be placed on the connection
+        fn is_available(&self, policy: &Policy) -> bool {
+            !self.is_closed()
+                && policy
+                    .max_streams
+                    .is_none_or(|value| self.usage.streams() < value)
+        }
+    }
+
+    impl Drop for Pooled {
+        // Streams are no longer accepted on a connection once it is not used to open any
+        fn drop(&mut self) {
+            self.acceptor.abort();
+        }
+    }
 
     impl<T> Builder<T>
     where
@@ -37,55 +234,116 @@ mod s2n_quic {
         pub fn new(connection: T) -> Self {
             Self {
                 connection,
+                accepted: None,
+                pool_size: 1,
+                policy: Policy::default(),
+                shutdown: Shutdown::default(),
+            }
+        }
+
+        /// Streams opened by the server on the pooled connections, e.g. for reverse port forwarding
+        pub fn accept(&mut self) -> impl Provider<BidirectionalStream> {
+            let (stream_sender, stream_receiver) = mpsc::channel(1usize);
+            self.accepted = Some(stream_sender);
 
-                #[cfg(feature = "limit-connection-reuses")]
-                connection_reuses: None,
+            Stream {
+                inner: stream_receiver,
             }
         }
 
-        pub fn build(mut self) -> impl Provider<BidirectionalStream> {
+        pub fn build(mut self) -> impl Provider<PooledStream> {
             let (stream_sender, stream_receiver) = mpsc::channel(1usize);
 
             tokio::spawn(async move {
-                'connection: while let Some(mut connection) = self.connection.fetch().await {
-                    #[cfg(feature = "limit-connection-reuses")]
-                    let mut connection_reuses = 0;
-
-                    'stream: loop {
-                        #[cfg(feature = "limit-connection-reuses")]
-                        if let Some(value) = self.connection_reuses {
-                            if connection_reuses >= value {
-                                break 'stream;
+                let changed = Arc::new(Notify::new());
+                let shutdown = self.shutdown.clone();
+
+                let mut pool: Vec<Pooled> = Vec::new();
+                let mut draining: Vec<Pooled> = Vec::new();
+                let mut exhausted = false;
+
+                loop {
+                    self.retire(&mut pool);
+
+                    let active = pool.iter().filter(|pooled| !pooled.retiring).count();
+
+                    // Retiring connections are dropped as soon as enough fresh ones are up
+                    if active >= self.pool_size {
+                        let (retired, kept): (Vec<_>, Vec<_>) =
+                            pool.drain(..).partition(|pooled| pooled.retiring);
+                        pool = kept;
+                        draining.extend(retired);
+                    }
+
+                    draining.retain(|pooled| pooled.usage.streams() > 0 && !pooled.is_closed());
+
+                    if pool.is_empty() && exhausted {
+                        break;
+                    }
+
+                    // Beyond the pool size, connections are only dialed when every fresh one is full
+                    let full = !pool
+                        .iter()
+                        .any(|pooled| !pooled.retiring && pooled.is_available(&self.policy));
+                    let dial = (active < self.pool_size || full) && !exhausted;
+
+                    let placeable = least_loaded(&pool, &self.policy).is_some();
+                    let expiry = self.expiry(&pool);
+
+                    tokio::select! {
+                        connection = self.connection.fetch(), if dial => {
+                            match connection {
+                                Some(connection) => {
+                                    debug!(
+                                        "{:?} pool connection {} with {:?}",
+                                        connection.local_addr(),
+                                        connection.id(),
+                                        connection.remote_addr()
+                                    );
+
+                                    let accepted = self.accepted.clone();
+                                    pool.push(Pooled::new(connection, accepted, changed.clone()));
+                                }
+                                None => exhausted = true,
                             }
                         }
 
-                        let stream = match connection.open_bidirectional_stream().await {
-                            Ok(stream) => stream,
-                            Err(_error) => {
-                                error!(
-                                    "connection {} failed to open bidirectional stream. {}",
-                                    connection.id(),
-                                    _error
-                                );
-                                break 'stream;
-                            }
-                        };
+                        permit = stream_sender.clone().reserve_owned(), if placeable => {
+                            let Ok(permit) = permit else {
+                                break;
+                            };
+
+                            let Some(index) = least_loaded(&pool, &self.policy) else {
+                                continue;
+                            };
 
-                        debug!(
-                            "{:?} connection {} open bidirectional stream {}",
-                            connection.local_addr(),
-                            connection.id(),
-                            stream.id()
-                        );
+                            let pooled = &pool[index];
 
-                        if let Err(_error) = stream_sender.send(stream).await {
-                            break 'connection;
+                            // Opened aside, the pool goes on dialing and retiring meanwhile
+                            pooled.usage.streams.fetch_add(1, Ordering::Relaxed);
+
+                            tokio::spawn(open(
+                                pooled.handle.clone(),
+                                pooled.usage.clone(),
+                                pooled.closed.clone(),
+                                permit,
+                            ));
                         }
 
-                        #[cfg(feature = "limit-connection-reuses")]
-                        {
-                            connection_reuses += 1;
+                        _ = changed.notified() => {}
+
+                        _ = shutdown.closing() => {
+                            let error = application::Error::new(SHUTDOWN_ERROR_CODE.into())
+                                .unwrap_or(application::Error::UNKNOWN);
+
+                            for pooled in pool.iter().chain(&draining) {
+                                pooled.handle.close(error);
+                            }
+
+                            break;
                         }
+
+                        _ = sleep_until(expiry.unwrap_or_else(Instant::now)), if expiry.is_some() => {}
                     }
                 }
             });
@@ -94,6 +352,107 @@ mod s2n_quic {
                 inner: stream_receiver,
             }
         }
+
+        /// Drops the closed and the idle extra connections, marks the worn out ones for replacement
+        fn retire(&self, pool: &mut Vec<Pooled>) {
+            pool.retain(|pooled| !pooled.is_closed());
+
+            for pooled in pool.iter_mut() {
+                let aged = self
+                    .policy
+                    .max_age
+                    .is_some_and(|value| pooled.created_at.elapsed() >= value);
+
+                let worn = self
+                    .policy
+                    .max_bytes
+                    .is_some_and(|value| pooled.usage.bytes() >= value);
+
+                if aged || worn {
+                    pooled.retiring = true;
+                }
+            }
+
+            let active = pool.iter().filter(|pooled| !pooled.retiring).count();
+            let mut extra = active.saturating_sub(self.pool_size);
+            pool.retain(|pooled| {
+                let idle = extra > 0 && !pooled.retiring && pooled.usage.streams() == 0;
+                if idle {
+                    extra -= 1;
+                }
+                !idle
+            });
+        }
+
+        /// When the next connection reaches its maximum age
+        fn expiry(&self, pool: &[Pooled]) -> Option<Instant> {
+            let max_age = self.policy.max_age?;
+
+            pool.iter()
+                .filter(|pooled| !pooled.retiring)
+                .map(|pooled| pooled.created_at + max_age)
+                .min()
+        }
+    }
+
+    /// Opens a stream counted in the usage of its connection beforehand, a connection
+    /// failing to open it is closed and the stream is opened again on another one
+    async fn open(
+        mut handle: Handle,
+        usage: Arc<Usage>,
+        closed: Arc<AtomicBool>,
+        permit: mpsc::OwnedPermit<PooledStream>,
+    ) {
+        match handle.open_bidirectional_stream().await {
+            Ok(stream) => {
+                debug!(
+                    "{:?} connection {} open bidirectional stream {}, {} in use",
+                    handle.local_addr(),
+                    handle.id(),
+                    stream.id(),
+                    usage.streams()
+                );
+
+                permit.send(PooledStream {
+                    inner: stream,
+                    usage,
+                });
+            }
+            Err(_error) => {
+                error!(
+                    "connection {} failed to open bidirectional stream. {}",
+                    handle.id(),
+                    _error
+                );
+
+                closed.store(true, Ordering::Relaxed);
+                usage.streams.fetch_sub(1, Ordering::Relaxed);
+                usage.changed.notify_one();
+            }
+        }
+    }
+
+    /// Available connection with the fewest streams in use, fresh ones first
+    fn least_loaded(pool: &[Pooled], policy: &Policy) -> Option<usize> {
+        (0..pool.len())
+            .filter(|index| pool[*index].is_available(policy))
+            .min_by_key(|index| (pool[*index].retiring, pool[*index].usage.streams()))
+    }
+
+    /// Accepts until the connection is closed, streams nobody asked for are dropped
+    async fn accept(
+        mut acceptor: StreamAcceptor,
+        mut accepted: Option<mpsc::Sender<BidirectionalStream>>,
+    ) {
+        while let Ok(Some(stream)) = acceptor.accept_bidirectional_stream().await {
+            debug!("accept bidirectional stream {}", stream.id());
+
+            if let Some(sender) = &accepted {
+                if sender.send(stream).await.is_err() {
+                    accepted = None;
+                }
+            }
+        }
     }
 
     impl Provider<BidirectionalStream> for Stream<Receiver<BidirectionalStream>> {
@@ -101,4 +460,12 @@ mod s2n_quic {
             self.inner.recv().await
         }
     }
+
+    impl Provider<PooledStream> for Stream<Receiver<PooledStream>> {
+        async fn fetch(&mut self) -> Option<PooledStream> {
+            self.inner.recv().await
+        }
+    }
 }
+
+pub use self::s2n_quic::PooledStream;
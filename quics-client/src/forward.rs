@@ -0,0 +1,152 @@
+use std::error::Error;
+use std::io::ErrorKind;
+use std::str::FromStr;
+
+use quics_protocol::{
+    request::{Address, Request},
+    Provider,
+};
+
+use tokio::{
+    io::Result,
+    net::TcpStream,
+    sync::mpsc::{Receiver, Sender},
+};
+
+use crate::info;
+use crate::local::{listen, Handshake, LocalStream};
+
+/// Local port forwarded to a fixed destination, like `ssh -L`
+///
+/// Parsed from `[bind_address:]port:host:hostport`, the local port is bound
+/// on the loopback address when no bind address is given.
+#[derive(Debug, Clone)]
+pub struct Forward {
+    pub listen: String,
+    pub address: Address,
+}
+
+impl FromStr for Forward {
+    type Err = std::io::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let invalid = || {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "invalid forward '{}', expected [bind_address:]port:host:hostport",
+                    value
+                ),
+            )
+        };
+
+        let (rest, host_port) = value.rsplit_once(':').ok_or_else(invalid)?;
+
+        // IPv6 destinations are enclosed in brackets
+        let (listen, host) = match rest.strip_suffix(']') {
+            Some(rest) => {
+                let (listen, host) = rest.rsplit_once('[').ok_or_else(invalid)?;
+                (
+                    listen.strip_suffix(':').ok_or_else(invalid)?,
+                    format!("[{}]", host),
+                )
+            }
+            None => {
+                let (listen, host) = rest.rsplit_once(':').ok_or_else(invalid)?;
+                (listen, host.to_string())
+            }
+        };
+
+        let address = format!("{}:{}", host, host_port)
+            .parse::<Address>()
+            .map_err(|_| invalid())?;
+
+        let listen = match listen.parse::<u16>() {
+            Ok(port) => format!("127.0.0.1:{}", port),
+            Err(_) => {
+                listen.parse::<Address>().map_err(|_| invalid())?;
+                listen.to_string()
+            }
+        };
+
+        Ok(Self { listen, address })
+    }
+}
+
+/// Listener of a [`Forward`], every accepted connection is tunnelled to its destination
+pub struct ForwardServer {
+    inner: Receiver<(LocalStream, Request)>,
+}
+
+impl ForwardServer {
+    pub async fn with(forward: Forward) -> std::result::Result<Self, Box<dyn Error>> {
+        let address = forward.address;
+
+        let inner = listen(forward.listen, move |stream, sender| {
+            Self::handle(stream, sender, address.clone())
+        })
+        .await?;
+
+        Ok(Self { inner })
+    }
+
+    async fn handle(
+        stream: TcpStream,
+        sender: Sender<(LocalStream, Request)>,
+        address: Address,
+    ) -> Result<()> {
+        info!("FORWARD {:?} to {:?}", stream.peer_addr(), address);
+
+        let stream = LocalStream::tcp(stream, Handshake::None);
+        let _ = sender.send((stream, Request::TCPConnect(address))).await;
+
+        Ok(())
+    }
+}
+
+impl Provider<(LocalStream, Request)> for ForwardServer {
+    async fn fetch(&mut self) -> Option<(LocalStream, Request)> {
+        self.inner.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let domain = |domain: &str, port| Address::Domain(domain.to_string(), port);
+        let ip = |address: &str| Address::from(address.parse::<std::net::SocketAddr>().unwrap());
+
+        #[rustfmt::skip]
+        let forwards = [
+            ("8080:example.com:80", Some(("127.0.0.1:8080", domain("example.com", 80)))),
+            ("0.0.0.0:8080:example.com:80", Some(("0.0.0.0:8080", domain("example.com", 80)))),
+            ("localhost:8080:192.0.2.1:22", Some(("localhost:8080", ip("192.0.2.1:22")))),
+            ("8080:[2001:db8::1]:443", Some(("127.0.0.1:8080", ip("[2001:db8::1]:443")))),
+            ("[::1]:8080:example.com:80", Some(("[::1]:8080", domain("example.com", 80)))),
+            ("[::]:8080:[2001:db8::1]:443", Some(("[::]:8080", ip("[2001:db8::1]:443")))),
+            // Missing ports and IPv6 addresses without brackets
+            ("example.com:80", None),
+            ("8080:example.com", None),
+            ("8080:example.com:", None),
+            ("8080:example.com:http", None),
+            ("8080:[2001:db8::1]", None),
+            ("8080:2001:db8::1:443", None),
+            ("8080:[2001:db8::1:443", None),
+            ("::1:8080:example.com:80", None),
+            (":8080:example.com:80", None),
+            ("70000:example.com:80", None),
+        ];
+
+        for (value, expected) in forwards {
+            let forward = value.parse::<Forward>();
+            let forward = forward
+                .as_ref()
+                .map(|forward| (forward.listen.as_str(), forward.address.clone()));
+
+            assert_eq!(forward.ok(), expected, "{}", value);
+        }
+    }
+}
@@ -1,4 +1,16 @@
 mod macros;
 
+pub mod config;
 pub mod connect;
+pub mod forward;
+pub mod http;
+pub mod local;
+pub mod mixed;
+pub mod outbound;
+pub mod reverse;
+pub mod route;
 pub mod socks;
+#[cfg(target_os = "linux")]
+pub mod transparent;
+#[cfg(target_os = "linux")]
+pub mod tun;
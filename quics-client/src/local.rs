@@ -0,0 +1,299 @@
+use std::error::Error;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use quics_protocol::limit::Limit;
+use quics_protocol::request::Request;
+use quics_protocol::response::Response;
+use quics_protocol::{Limited, Provider, Reply};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf, Result};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
+
+use crate::error;
+
+/// Local side of a proxied stream
+pub struct LocalStream {
+    inner: Inner,
+    handshake: Option<Handshake>,
+
+    /// Bytes already read from the local peer, relayed before the stream itself
+    buffered: Bytes,
+
+    /// Rate limit of the listener the stream came from
+    limit: Option<Limit>,
+}
+
+enum Inner {
+    /// Accepted TCP connection, relayed as is
+    Tcp(TcpStream),
+
+    /// In-process pipe, used when the local side is not a single TCP connection (e.g. UDP)
+    Duplex(DuplexStream),
+}
+
+/// Reply owed to the local peer once the remote outcome is known
+pub enum Handshake {
+    /// SOCKS5 reply on the stream itself
+    Socks5,
+
+    /// SOCKS4 reply on the stream itself
+    Socks4,
+
+    /// HTTP CONNECT status line on the stream itself
+    HttpConnect,
+
+    /// HTTP error status on failure only, the forwarded request is relayed on success
+    HttpForward,
+
+    /// Nothing to reply, the stream is closed on failure
+    None,
+
+    /// Outcome handed over to the task owning the local peer
+    Notify(oneshot::Sender<Response>),
+}
+
+impl LocalStream {
+    pub fn tcp(stream: TcpStream, handshake: Handshake) -> Self {
+        Self {
+            inner: Inner::Tcp(stream),
+            handshake: Some(handshake),
+            buffered: Bytes::new(),
+            limit: None,
+        }
+    }
+
+    pub fn duplex(stream: DuplexStream, handshake: Handshake) -> Self {
+        Self {
+            inner: Inner::Duplex(stream),
+            handshake: Some(handshake),
+            buffered: Bytes::new(),
+            limit: None,
+        }
+    }
+
+    pub fn with_buffered(mut self, buffered: Bytes) -> Self {
+        self.buffered = buffered;
+
+        self
+    }
+
+    /// Hands the reply owed and the limit over to a stream on a new pipe, returned
+    /// with the other end of the pipe, so that what goes through can be filtered
+    pub(crate) fn interpose(&mut self, capacity: usize) -> (Self, DuplexStream) {
+        let (stream, pipe) = tokio::io::duplex(capacity);
+
+        let stream = Self {
+            inner: Inner::Duplex(stream),
+            handshake: self.handshake.take(),
+            buffered: Bytes::new(),
+            limit: self.limit.take(),
+        };
+
+        (stream, pipe)
+    }
+}
+
+impl Limited for LocalStream {
+    fn limit(&self) -> Option<Limit> {
+        self.limit.clone()
+    }
+}
+
+impl Reply for LocalStream {
+    async fn reply(&mut self, response: &Response) -> Result<()> {
+        match self.handshake.take() {
+            Some(Handshake::Socks5) => crate::socks::reply(self, response).await,
+            Some(Handshake::Socks4) => crate::socks::socks4::reply(self, response).await,
+            Some(Handshake::HttpConnect) => crate::http::reply(self, response, true).await,
+            Some(Handshake::HttpForward) => crate::http::reply(self, response, false).await,
+            Some(Handshake::Notify(sender)) => {
+                // The owner is gone when the local peer already disconnected
+                let _ = sender.send(response.clone());
+                Ok(())
+            }
+            Some(Handshake::None) | None => Ok(()),
+        }
+    }
+}
+
+impl AsyncRead for LocalStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+
+        if !this.buffered.is_empty() {
+            let size = buf.remaining().min(this.buffered.len());
+            buf.put_slice(&this.buffered.split_to(size));
+
+            return Poll::Ready(Ok(()));
+        }
+
+        match &mut this.inner {
+            Inner::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Inner::Duplex(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for LocalStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        match &mut self.get_mut().inner {
+            Inner::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Inner::Duplex(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match &mut self.get_mut().inner {
+            Inner::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Inner::Duplex(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match &mut self.get_mut().inner {
+            Inner::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Inner::Duplex(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Local streams of several inbound providers, fed to a single client
+pub struct Listeners {
+    sender: Sender<(LocalStream, Request)>,
+    receiver: Receiver<(LocalStream, Request)>,
+}
+
+impl Default for Listeners {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel(1);
+
+        Self { sender, receiver }
+    }
+}
+
+impl Listeners {
+    pub fn push<P>(&mut self, provider: P)
+    where
+        P: Provider<(LocalStream, Request)> + Send + 'static,
+    {
+        self.push_limited(provider, None);
+    }
+
+    /// Adds a provider whose streams share the rate limit
+    pub fn push_limited<P>(&mut self, mut provider: P, limit: Option<Limit>)
+    where
+        P: Provider<(LocalStream, Request)> + Send + 'static,
+    {
+        let sender = self.sender.clone();
+
+        tokio::spawn(async move {
+            while let Some((mut local, request)) = provider.fetch().await {
+                local.limit = limit.clone();
+
+                if sender.send((local, request)).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+impl Provider<(LocalStream, Request)> for Listeners {
+    async fn fetch(&mut self) -> Option<(LocalStream, Request)> {
+        self.receiver.recv().await
+    }
+}
+
+/// Accepts TCP connections on `address` and runs `handle` on each of them,
+/// the handler forwards the proxied streams to the returned receiver.
+pub(crate) async fn listen<H, F>(
+    address: String,
+    handle: H,
+) -> std::result::Result<Receiver<(LocalStream, Request)>, Box<dyn Error>>
+where
+    H: Fn(TcpStream, Sender<(LocalStream, Request)>) -> F + Send + 'static,
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    let address: SocketAddr = address.parse()?;
+    let listener = TcpListener::bind(address).await?;
+
+    Ok(serve(listener, handle))
+}
+
+/// Same as [`listen`] on an already bound listener
+pub(crate) fn serve<H, F>(listener: TcpListener, handle: H) -> Receiver<(LocalStream, Request)>
+where
+    H: Fn(TcpStream, Sender<(LocalStream, Request)>) -> F + Send + 'static,
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(1);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _address)) => {
+                    let handle = handle(stream, sender.clone());
+                    tokio::spawn(async move {
+                        if let Err(_error) = handle.await {
+                            error!("{}", _error);
+                        }
+                    });
+                }
+
+                Err(_error) => {
+                    error!("failed to accept: {:?}", _error);
+                }
+            };
+        }
+    });
+
+    receiver
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use quics_protocol::request::Address;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// Runs `handle` on a loopback connection the request was sent on, returns
+    /// the destination it forwarded and what it answered the peer
+    pub(crate) async fn exchange<H, F>(request: &[u8], handle: H) -> (Option<Address>, Vec<u8>)
+    where
+        H: FnOnce(TcpStream, Sender<(LocalStream, Request)>) -> F,
+        F: Future<Output = Result<()>>,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        peer.write_all(request).await.unwrap();
+        peer.shutdown().await.unwrap();
+
+        let (sender, mut receiver) = mpsc::channel(1);
+        let _ = handle(stream, sender).await;
+
+        let address = match receiver.recv().await {
+            Some((_, Request::TCPConnect(address))) => Some(address),
+            _ => None,
+        };
+
+        // Reset when the request was not read to its end
+        let mut answer = Vec::new();
+        let _ = peer.read_to_end(&mut answer).await;
+
+        (address, answer)
+    }
+}
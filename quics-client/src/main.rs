@@ -1,44 +1,169 @@
 use std::net::ToSocketAddrs;
+use std::str::FromStr;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use quics_client::config::Config;
+#[cfg(unix)]
+use quics_client::connect::balance::Balancer;
+use quics_client::connect::balance::Strategy;
 use quics_client::connect::connection::Builder as ConnectionBuilder;
-use quics_client::connect::stream::Builder as StreamBuilder;
-use quics_client::socks::SocksServer;
+use quics_client::connect::probe::Prober;
+use quics_client::connect::stream::{Builder as StreamBuilder, Policy};
+use quics_client::forward::{Forward, ForwardServer};
+use quics_client::http::HttpProxyServer;
+use quics_client::local::Listeners;
+use quics_client::mixed::MixedServer;
+use quics_client::reverse::{Reverse, ReverseServer};
+use quics_client::route::{Router, Rules};
+use quics_client::socks::{Credentials, SocksServer};
+#[cfg(target_os = "linux")]
+use quics_client::transparent::{Mode, TransparentServer};
+#[cfg(target_os = "linux")]
+use quics_client::tun::{Cidr, TunServer};
+use quics_client::{info, warn};
+use quics_protocol::auth::Authentication;
 use quics_protocol::client::Client;
+use quics_protocol::limit::Limit;
+use quics_protocol::shutdown::Shutdown;
 
 /// QUICS Client
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Remote server IP address or domain name. e.g. example.com:port
-    #[arg(short, long)]
-    remote: String,
+    /// TOML configuration file, options given on the command line take precedence over it
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<String>,
 
-    /// SOCKS server listening address
+    /// Remote server IP address or domain name e.g. example.com:port, may be repeated
+    #[arg(
+        short,
+        long = "remote",
+        value_name = "HOST:PORT[,sni=NAME][,cert=FILE]"
+    )]
+    remotes: Vec<Remote>,
+
+    /// How connections pick a remote server among the healthy ones
+    #[arg(long, value_enum, default_value_t = Balance::Failover)]
+    balance: Balance,
+
+    /// Proxy server listening address
     #[arg(short, long, default_value = "127.0.0.1:1080")]
     listen: String,
 
+    /// Proxy protocol served on the listening address
+    #[arg(long, value_enum, default_value_t = Inbound::Socks)]
+    inbound: Inbound,
+
+    /// Local port forwarded to a fixed destination e.g. 8080:example.com:80, may be repeated
+    #[arg(
+        short = 'L',
+        long = "forward",
+        value_name = "[BIND:]PORT:HOST:HOSTPORT"
+    )]
+    forwards: Vec<Forward>,
+
+    /// Server port forwarded back to a destination reachable from the client e.g. 8080:localhost:80, may be repeated
+    #[arg(
+        short = 'R',
+        long = "reverse",
+        value_name = "[BIND:]PORT:HOST:HOSTPORT"
+    )]
+    reverses: Vec<Reverse>,
+
+    /// TUN interface to create, the TCP and UDP traffic routed to it is tunnelled (Linux only)
+    #[arg(long, value_name = "NAME")]
+    tun: Option<String>,
+
+    /// IPv4 address of the TUN interface e.g. 198.18.0.1/16, if not provided, the interface is left unaddressed
+    #[arg(long, value_name = "ADDRESS/PREFIX", requires = "tun")]
+    tun_address: Option<String>,
+
+    /// MTU of the TUN interface
+    #[arg(long, default_value_t = 1500, requires = "tun")]
+    tun_mtu: usize,
+
+    /// Routing rules file with `TYPE,VALUE,ACTION` lines, if not provided, every connection is tunnelled
+    #[arg(long, value_name = "FILE")]
+    rules: Option<String>,
+
+    /// Account required from local proxy clients e.g. username:password, may be repeated
+    #[arg(long = "proxy-user", value_name = "USERNAME:PASSWORD")]
+    proxy_users: Vec<String>,
+
+    /// Accounts file with `username:password` lines required from local proxy clients
+    #[arg(long, default_value = None)]
+    proxy_users_file: Option<String>,
+
     /// IO provider address for the client
     #[arg(long, default_value = "0.0.0.0:0")]
     bind: String,
 
-    /// TLS SNI, if not provided, remote address will be used
+    /// Firewall mark of the connections to the remote servers and of the DIRECT ones, to route them around the TUN interface (Linux only)
+    #[arg(long, value_name = "MARK")]
+    fwmark: Option<u32>,
+
+    /// TLS SNI of the remote servers without `sni=`, if not provided, remote address will be used
     #[arg(long, default_value = None)]
     tls_sni: Option<String>,
 
-    /// TLS certificate file path
+    /// TLS certificate file path of the remote servers without `cert=`
     #[arg(long, default_value = None)]
     tls_cert: Option<String>,
 
+    /// Username to authenticate with the remote server
+    #[arg(long, default_value = None, requires = "password")]
+    username: Option<String>,
+
+    /// Password to authenticate with the remote server
+    #[arg(long, default_value = None, requires = "username")]
+    password: Option<String>,
+
+    /// Seconds between pings of every remote server, measuring latency and health, if not provided, servers are not probed
+    #[arg(long, value_name = "SECONDS")]
+    probe_interval: Option<u64>,
+
+    /// Warm connections kept open, new streams go to the least loaded one
+    #[arg(long, default_value_t = 1)]
+    pool_size: usize,
+
+    /// Streams in use at the same time on a connection, further ones go to another connection
+    #[arg(long, value_name = "STREAMS")]
+    connection_max_streams: Option<usize>,
+
+    /// Seconds after which a connection is replaced, if not provided, connections are kept until they fail
+    #[arg(long, value_name = "SECONDS")]
+    connection_max_age: Option<u64>,
+
+    /// Bytes carried after which a connection is replaced
+    #[arg(long, value_name = "BYTES")]
+    connection_max_bytes: Option<u64>,
+
+    /// Attempts to send a request on fresh remote streams before the local connection is failed
+    #[arg(long, default_value_t = 3)]
+    request_attempts: usize,
+
+    /// Seconds an attempt of a request may take, getting a remote stream included
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    request_timeout: u64,
+
+    /// Seconds the streams in flight may take to finish on SIGINT or SIGTERM
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    drain_timeout: u64,
+
+    /// Bytes per second tunnelled by all the streams together, in each direction
+    #[arg(long, value_name = "BYTES")]
+    rate_limit: Option<u64>,
+
+    /// Bytes per second tunnelled by every stream on its own, in each direction
+    #[arg(long, value_name = "BYTES")]
+    stream_rate_limit: Option<u64>,
+
     /// Limit the number of concurrent instances of the client
     #[arg(long, default_value = None)]
     limit_concurrent_instances: Option<usize>,
 
-    #[cfg(feature = "limit-connection-reuses")]
-    /// Limit the number of connection reuses
-    #[arg(long, default_value = None)]
-    limit_connection_reuses: Option<usize>,
-
     /// Initial congestion window size in bytes
     #[arg(long, default_value = None)]
     initial_congestion_window: Option<u32>,
@@ -46,11 +171,407 @@ struct Args {
     /// e.g. INFO WARN ERROR
     #[arg(long, default_value = "WARN")]
     tracing_level: tracing::Level,
+
+    /// Listeners of the configuration file with their rate limit, used when no
+    /// listener is given on the command line
+    #[arg(skip)]
+    listeners: Vec<(Inbound, String, Option<u64>)>,
+
+    /// Inline rules of the configuration file
+    #[arg(skip)]
+    routing: Vec<String>,
+}
+
+impl Args {
+    /// Takes from the configuration file what is not given on the command line,
+    /// errors name the offending key
+    fn merge(&mut self, config: Config, matches: &ArgMatches) -> Result<(), String> {
+        let cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        if !cli("remotes") && !config.servers.is_empty() {
+            self.remotes = config
+                .servers
+                .into_iter()
+                .enumerate()
+                .map(|(index, server)| {
+                    match server.address.rsplit_once(':') {
+                        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+                        _ => return Err(format!("servers[{}].address: expected HOST:PORT", index)),
+                    }
+
+                    Ok(Remote {
+                        address: server.address,
+                        tls_sni: server.sni,
+                        tls_cert: server.cert,
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        if let (Some(value), false) = (&config.balance, cli("balance")) {
+            self.balance =
+                Balance::from_str(value, true).map_err(|error| format!("balance: {}", error))?;
+        }
+
+        set(
+            &mut self.probe_interval,
+            config.probe_interval.map(Some),
+            cli("probe_interval"),
+        );
+
+        if !cli("inbound") && !cli("listen") && !config.listeners.is_empty() {
+            self.listeners = config
+                .listeners
+                .into_iter()
+                .enumerate()
+                .map(|(index, listener)| {
+                    Inbound::from_str(&listener.inbound, true)
+                        .map(|inbound| (inbound, listener.listen, listener.rate_limit))
+                        .map_err(|error| format!("listeners[{}].inbound: {}", index, error))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        if !cli("forwards") && !config.forwards.is_empty() {
+            self.forwards = config
+                .forwards
+                .iter()
+                .enumerate()
+                .map(|(index, value)| {
+                    value
+                        .parse::<Forward>()
+                        .map_err(|error| format!("forwards[{}]: {}", index, error))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        if !cli("reverses") && !config.reverses.is_empty() {
+            self.reverses = config
+                .reverses
+                .iter()
+                .enumerate()
+                .map(|(index, value)| {
+                    value
+                        .parse::<Reverse>()
+                        .map_err(|error| format!("reverses[{}]: {}", index, error))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        if let (Some(tun), false) = (config.tun, cli("tun")) {
+            self.tun = Some(tun.name);
+            set(
+                &mut self.tun_address,
+                tun.address.map(Some),
+                cli("tun_address"),
+            );
+            set(&mut self.tun_mtu, tun.mtu, cli("tun_mtu"));
+        }
+
+        if let (Some(routing), false) = (config.routing, cli("rules")) {
+            if !routing.rules.is_empty() && routing.rules_file.is_some() {
+                return Err("routing: rules and rules-file are mutually exclusive".to_string());
+            }
+
+            if !routing.rules.is_empty() {
+                Rules::parse(&routing.rules.join("\n"))
+                    .map_err(|error| format!("routing.rules: {}", error))?;
+            }
+
+            self.rules = routing.rules_file;
+            self.routing = routing.rules;
+        }
+
+        if let Some(auth) = config.auth {
+            if !cli("username") && !cli("password") {
+                if auth.username.is_some() != auth.password.is_some() {
+                    return Err("auth: username and password go together".to_string());
+                }
+
+                set(&mut self.username, auth.username.map(Some), false);
+                set(&mut self.password, auth.password.map(Some), false);
+            }
+
+            if !cli("proxy_users") && !auth.proxy_users.is_empty() {
+                self.proxy_users = auth.proxy_users;
+            }
+
+            set(
+                &mut self.proxy_users_file,
+                auth.proxy_users_file.map(Some),
+                cli("proxy_users_file"),
+            );
+        }
+
+        set(&mut self.bind, config.bind, cli("bind"));
+        set(&mut self.fwmark, config.fwmark.map(Some), cli("fwmark"));
+        set(&mut self.tls_sni, config.tls_sni.map(Some), cli("tls_sni"));
+        set(
+            &mut self.tls_cert,
+            config.tls_cert.map(Some),
+            cli("tls_cert"),
+        );
+        set(&mut self.pool_size, config.pool_size, cli("pool_size"));
+        set(
+            &mut self.connection_max_streams,
+            config.connection_max_streams.map(Some),
+            cli("connection_max_streams"),
+        );
+        set(
+            &mut self.connection_max_age,
+            config.connection_max_age.map(Some),
+            cli("connection_max_age"),
+        );
+        set(
+            &mut self.connection_max_bytes,
+            config.connection_max_bytes.map(Some),
+            cli("connection_max_bytes"),
+        );
+        set(
+            &mut self.request_attempts,
+            config.request_attempts,
+            cli("request_attempts"),
+        );
+        set(
+            &mut self.request_timeout,
+            config.request_timeout,
+            cli("request_timeout"),
+        );
+        set(
+            &mut self.drain_timeout,
+            config.drain_timeout,
+            cli("drain_timeout"),
+        );
+        set(
+            &mut self.rate_limit,
+            config.rate_limit.map(Some),
+            cli("rate_limit"),
+        );
+        set(
+            &mut self.stream_rate_limit,
+            config.stream_rate_limit.map(Some),
+            cli("stream_rate_limit"),
+        );
+        set(
+            &mut self.limit_concurrent_instances,
+            config.limit_concurrent_instances.map(Some),
+            cli("limit_concurrent_instances"),
+        );
+        set(
+            &mut self.initial_congestion_window,
+            config.initial_congestion_window.map(Some),
+            cli("initial_congestion_window"),
+        );
+
+        if let (Some(value), false) = (&config.tracing_level, cli("tracing_level")) {
+            self.tracing_level = value
+                .parse::<tracing::Level>()
+                .map_err(|error| format!("tracing-level: {}", error))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Overwrites the target with the file value unless it was given on the command line
+fn set<T>(target: &mut T, value: Option<T>, cli: bool) {
+    if let (Some(value), false) = (value, cli) {
+        *target = value;
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Inbound {
+    /// SOCKS5, SOCKS4 and SOCKS4a
+    Socks,
+    /// HTTP CONNECT and forward proxy
+    Http,
+    /// SOCKS and HTTP on the same port
+    Mixed,
+    /// Transparent proxy of connections redirected by iptables REDIRECT (Linux only)
+    Redirect,
+    /// Transparent proxy of connections diverted by iptables TPROXY (Linux only)
+    Tproxy,
+    /// No proxy, only the forwarded ports are served
+    None,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Balance {
+    /// The first healthy server in order, the following ones are backups
+    Failover,
+    /// Every healthy server in turn
+    RoundRobin,
+    /// The healthy server with the lowest latency
+    LowestLatency,
+}
+
+/// Remote server with its own TLS settings
+#[derive(Clone, Debug)]
+struct Remote {
+    address: String,
+    tls_sni: Option<String>,
+    tls_cert: Option<String>,
+}
+
+impl FromStr for Remote {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut fields = value.split(',');
+
+        let mut remote = Self {
+            address: fields.next().unwrap_or_default().to_string(),
+            tls_sni: None,
+            tls_cert: None,
+        };
+
+        for field in fields {
+            match field.split_once('=') {
+                Some(("sni", name)) => remote.tls_sni = Some(name.to_string()),
+                Some(("cert", path)) => remote.tls_cert = Some(path.to_string()),
+                _ => return Err(format!("unknown remote option '{}'", field)),
+            }
+        }
+
+        Ok(remote)
+    }
+}
+
+/// Time given to the endpoint to send the CONNECTION_CLOSE frames before exiting
+const CLOSE_DELAY: Duration = Duration::from_millis(100);
+
+/// Arguments merged with the configuration file, as read at startup and on reload
+fn load(matches: &ArgMatches) -> Result<Args, Box<dyn std::error::Error>> {
+    let mut args = Args::from_arg_matches(matches)?;
+
+    if let Some(path) = args.config.clone() {
+        let config = Config::load(&path).map_err(|error| format!("{}: {}", path, error))?;
+
+        args.merge(config, matches)
+            .map_err(|error| format!("{}: {}", path, error))?;
+    }
+
+    if args.remotes.is_empty() {
+        return Err(
+            "no remote server, use --remote or [[servers]] of the configuration file".into(),
+        );
+    }
+
+    Ok(args)
+}
+
+/// Accounts required from local proxy clients
+fn load_credentials(args: &Args) -> Result<Credentials, Box<dyn std::error::Error>> {
+    let credentials = Credentials::parse(args.proxy_users.iter().map(String::as_str))?;
+
+    let credentials = match &args.proxy_users_file {
+        Some(path) => credentials
+            .extend(Credentials::load(path).map_err(|error| format!("{}: {}", path, error))?)
+            .map_err(|error| format!("{}: {}", path, error))?,
+        None => credentials,
+    };
+
+    Ok(credentials)
+}
+
+/// Routing rules of the rules file or of the configuration file
+fn load_rules(args: &Args) -> Result<Rules, Box<dyn std::error::Error>> {
+    let rules = match &args.rules {
+        Some(path) => Rules::load(path).map_err(|error| format!("{}: {}", path, error))?,
+        None if !args.routing.is_empty() => Rules::parse(&args.routing.join("\n"))?,
+        None => Rules::default(),
+    };
+
+    Ok(rules)
+}
+
+/// Reads the routing rules and the accounts of local proxy clients again on
+/// every SIGHUP, other settings need a restart and so does dropping every account
+#[cfg(unix)]
+async fn reload(
+    matches: ArgMatches,
+    credentials: Credentials,
+    rules: Rules,
+) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    while hangup.recv().await.is_some() {
+        let result = load(&matches).and_then(|args| {
+            let reloaded = (load_credentials(&args)?, load_rules(&args)?);
+
+            if reloaded.0.is_empty() && !credentials.is_empty() {
+                return Err("no accounts, restart to open the listeners".into());
+            }
+
+            credentials.replace(reloaded.0);
+            rules.replace(reloaded.1);
+
+            Ok(())
+        });
+
+        match result {
+            Ok(()) => info!("RELOAD configuration reloaded"),
+            Err(_error) => warn!("RELOAD failed, configuration kept: {}", _error),
+        }
+    }
+
+    Ok(())
+}
+
+/// Logs the health and latencies of every remote server on SIGUSR1
+#[cfg(unix)]
+async fn status(balancer: Balancer) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut user_defined = signal(SignalKind::user_defined1())?;
+
+    while user_defined.recv().await.is_some() {
+        for _server in balancer.status() {
+            info!(
+                "STATUS {} ({}) healthy {} failures {} latency {:?} rtt {:?} quic rtt {:?}",
+                _server.server_name,
+                _server.server_addr,
+                _server.is_healthy(),
+                _server.failures,
+                _server.latency,
+                _server.rtt,
+                _server.quic_rtt
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Starts draining on SIGINT or SIGTERM
+async fn terminate(shutdown: Shutdown) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    shutdown.drain();
+
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = load(&matches)?;
 
     #[cfg(feature = "trace")]
     tracing_subscriber::fmt()
@@ -58,41 +579,200 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with_max_level(args.tracing_level)
         .init();
 
-    let client = match args.limit_concurrent_instances {
-        Some(num) => (0..num)
-            .map(|_| s2n_quic_client::build(&args))
-            .collect::<Result<Vec<_>, _>>()?,
-
-        None => vec![s2n_quic_client::build(&args)?],
+    let strategy = match args.balance {
+        Balance::Failover => Strategy::Failover,
+        Balance::RoundRobin => Strategy::RoundRobin,
+        Balance::LowestLatency => Strategy::LowestLatency,
     };
 
-    let server_name = match args.tls_sni {
-        Some(value) => value,
-        None => {
-            let address = args.remote.clone();
-            let pos = address.rfind(':').ok_or("invalid remote address")?;
-            address[..pos].to_string()
+    let authentication = match (&args.username, &args.password) {
+        (Some(username), Some(password)) => {
+            if username.len() > 255 || password.len() > 255 {
+                return Err("username and password must be at most 255 bytes".into());
+            }
+
+            Authentication::Password(username.clone(), password.clone())
         }
+        _ => Authentication::None,
     };
 
-    let server_address = args
-        .remote
-        .to_socket_addrs()?
-        .nth(0)
-        .ok_or(format!("unable to resolve address {}", args.remote))?;
+    let mut connection_builder = None;
+    let mut probe_clients = Vec::new();
+
+    for remote in &args.remotes {
+        let tls_cert = remote.tls_cert.as_ref().or(args.tls_cert.as_ref());
 
-    let connection = ConnectionBuilder::new(client, server_name, server_address).build();
+        let client = match args.limit_concurrent_instances {
+            Some(num) => (0..num)
+                .map(|_| s2n_quic_client::build(&args, tls_cert))
+                .collect::<Result<Vec<_>, _>>()?,
 
-    let stream_builder = StreamBuilder::new(connection);
+            None => vec![s2n_quic_client::build(&args, tls_cert)?],
+        };
+
+        if args.probe_interval.is_some() {
+            probe_clients.push(s2n_quic_client::build(&args, tls_cert)?);
+        }
+
+        let server_name = match remote.tls_sni.as_ref().or(args.tls_sni.as_ref()) {
+            Some(value) => value.clone(),
+            None => {
+                let address = remote.address.clone();
+                let pos = address.rfind(':').ok_or("invalid remote address")?;
+                address[..pos].to_string()
+            }
+        };
 
-    #[cfg(feature = "limit-connection-reuses")]
-    let stream_builder = stream_builder.with_connection_reuses(args.limit_connection_reuses);
+        let server_address = remote
+            .address
+            .to_socket_addrs()?
+            .nth(0)
+            .ok_or(format!("unable to resolve address {}", remote.address))?;
+
+        connection_builder = Some(match connection_builder {
+            Some(builder) => builder.with_server(client, server_name, server_address),
+            None => ConnectionBuilder::new(client, server_name, server_address),
+        });
+    }
+
+    let mut connection_builder = connection_builder
+        .ok_or("no remote server")?
+        .with_strategy(strategy);
+
+    let balancer = connection_builder.balancer();
+
+    if let Some(seconds) = args.probe_interval {
+        let interval = Duration::from_secs(seconds.max(1));
+
+        Prober::new(
+            probe_clients,
+            balancer.clone(),
+            authentication.clone(),
+            interval,
+        )
+        .start();
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(status(balancer));
+
+    let connection = connection_builder.build();
+
+    let shutdown = Shutdown::default();
+
+    let mut stream_builder = StreamBuilder::new(connection)
+        .with_shutdown(shutdown.clone())
+        .with_pool_size(args.pool_size)
+        .with_policy(Policy {
+            max_streams: args.connection_max_streams,
+            max_age: args.connection_max_age.map(Duration::from_secs),
+            max_bytes: args.connection_max_bytes,
+        });
+
+    let accepted = (!args.reverses.is_empty()).then(|| stream_builder.accept());
 
     let stream = stream_builder.build();
 
-    let socks_server = SocksServer::with(args.listen).await?;
+    let credentials = load_credentials(&args)?;
+    let rules = load_rules(&args)?;
+
+    tokio::spawn(terminate(shutdown.clone()));
+
+    #[cfg(unix)]
+    tokio::spawn(reload(matches, credentials.clone(), rules.clone()));
+
+    let mut listeners = Listeners::default();
+
+    let inbounds = match args.listeners.is_empty() {
+        true => vec![(args.inbound, args.listen, None)],
+        false => args.listeners,
+    };
+
+    for (inbound, listen, rate) in inbounds {
+        let credentials = credentials.clone();
+        let limit = rate.map(Limit::new);
+
+        match inbound {
+            Inbound::Socks => {
+                listeners.push_limited(SocksServer::with(listen, credentials).await?, limit)
+            }
+            Inbound::Http => {
+                listeners.push_limited(HttpProxyServer::with(listen, credentials).await?, limit)
+            }
+            Inbound::Mixed => {
+                listeners.push_limited(MixedServer::with(listen, credentials).await?, limit)
+            }
+            #[cfg(target_os = "linux")]
+            Inbound::Redirect => listeners.push_limited(
+                TransparentServer::with(listen, Mode::Redirect).await?,
+                limit,
+            ),
+            #[cfg(target_os = "linux")]
+            Inbound::Tproxy => {
+                listeners.push_limited(TransparentServer::with(listen, Mode::Tproxy).await?, limit)
+            }
+            #[cfg(not(target_os = "linux"))]
+            Inbound::Redirect | Inbound::Tproxy => {
+                return Err("transparent proxy is only supported on Linux".into())
+            }
+            Inbound::None => {}
+        };
+    }
+
+    for forward in args.forwards {
+        listeners.push(ForwardServer::with(forward).await?);
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(name) = args.tun {
+        let address = match &args.tun_address {
+            Some(value) => Some(value.parse::<Cidr>()?),
+            None => None,
+        };
+
+        listeners.push(TunServer::with(name, address, args.tun_mtu)?);
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    if args.tun.is_some() {
+        return Err("TUN is only supported on Linux".into());
+    }
+
+    if let Some(accepted) = accepted {
+        listeners.push(ReverseServer::with(args.reverses, accepted));
+    }
+
+    let router = Router::with(listeners, rules)
+        .with_mark(args.fwmark)
+        .with_shutdown(shutdown.clone());
+
+    let mut client = Client::with(router, stream)
+        .with_authentication(authentication)
+        .with_attempts(args.request_attempts)
+        .with_timeout(Duration::from_secs(args.request_timeout));
 
-    Client::with(socks_server, stream).start().await;
+    if let Some(rate) = args.rate_limit {
+        client = client.with_rate_limit(rate);
+    }
+
+    if let Some(rate) = args.stream_rate_limit {
+        client = client.with_stream_rate_limit(rate);
+    }
+
+    client.start(shutdown.clone()).await;
+
+    let drain_timeout = Duration::from_secs(args.drain_timeout);
+    info!("SHUTDOWN draining for up to {:?}", drain_timeout);
+
+    if tokio::time::timeout(drain_timeout, shutdown.idle())
+        .await
+        .is_err()
+    {
+        warn!("SHUTDOWN streams still in flight are cut");
+    }
+
+    shutdown.close();
+    tokio::time::sleep(CLOSE_DELAY).await;
 
     Ok(())
 }
@@ -100,13 +780,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 mod s2n_quic_client {
     use std::error::Error;
 
-    use s2n_quic::provider::congestion_controller;
+    use quics_client::connect::rtt::RttSubscriber;
+    use quics_client::outbound;
+    use s2n_quic::provider::{congestion_controller, io};
     use s2n_quic::Client as NoiseClient;
     use std::path::Path;
 
     use super::Args;
 
-    pub fn build(args: &Args) -> Result<NoiseClient, Box<dyn Error>> {
+    pub fn build(args: &Args, tls_cert: Option<&String>) -> Result<NoiseClient, Box<dyn Error>> {
         let controller = {
             let controller = congestion_controller::bbr::Builder::default();
             let controller = match args.initial_congestion_window {
@@ -116,11 +798,25 @@ mod s2n_quic_client {
             controller.build()
         };
 
+        // The socket is made here only to be marked, s2n-quic binds it otherwise
+        let io = match args.fwmark {
+            Some(mark) => {
+                let socket = std::net::UdpSocket::bind(args.bind.as_str())?;
+                outbound::set_mark(&socket, mark)?;
+
+                io::Default::builder().with_rx_socket(socket)?.build()?
+            }
+            None => io::Default::builder()
+                .with_receive_address(args.bind.parse()?)?
+                .build()?,
+        };
+
         let client = NoiseClient::builder()
-            .with_io(args.bind.as_str())?
-            .with_congestion_controller(controller)?;
+            .with_io(io)?
+            .with_congestion_controller(controller)?
+            .with_event(RttSubscriber)?;
 
-        let client = match &args.tls_cert {
+        let client = match tls_cert {
             Some(path) => client.with_tls(Path::new(path.as_str()))?.start()?,
             None => client.start()?,
         };
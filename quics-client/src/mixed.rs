@@ -0,0 +1,59 @@
+use std::error::Error;
+
+use quics_protocol::{request::Request, Provider};
+
+use tokio::{
+    io::Result,
+    net::TcpStream,
+    sync::mpsc::{Receiver, Sender},
+};
+
+use crate::http::HttpProxyServer;
+use crate::local::{listen, LocalStream};
+use crate::socks::{Credentials, SocksServer};
+
+/// SOCKS and HTTP proxy sharing one listening port, told apart by the first byte
+pub struct MixedServer {
+    inner: Receiver<(LocalStream, Request)>,
+}
+
+impl MixedServer {
+    pub async fn with(
+        address: String,
+        credentials: Credentials,
+    ) -> std::result::Result<Self, Box<dyn Error>> {
+        let inner = listen(address, move |stream, sender| {
+            Self::handle(stream, sender, credentials.clone())
+        })
+        .await?;
+
+        Ok(Self { inner })
+    }
+
+    async fn handle(
+        stream: TcpStream,
+        sender: Sender<(LocalStream, Request)>,
+        credentials: Credentials,
+    ) -> Result<()> {
+        const SOCKS4_VERSION: u8 = 0x04;
+        const SOCKS5_VERSION: u8 = 0x05;
+
+        let mut version = [0u8; 1];
+        if stream.peek(&mut version).await? == 0 {
+            return Ok(());
+        }
+
+        match version[0] {
+            SOCKS4_VERSION | SOCKS5_VERSION => {
+                SocksServer::handle(stream, sender, credentials).await
+            }
+            _ => HttpProxyServer::handle(stream, sender, credentials).await,
+        }
+    }
+}
+
+impl Provider<(LocalStream, Request)> for MixedServer {
+    async fn fetch(&mut self) -> Option<(LocalStream, Request)> {
+        self.inner.recv().await
+    }
+}
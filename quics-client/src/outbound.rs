@@ -0,0 +1,69 @@
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+
+use tokio::io::Result;
+use tokio::net::{TcpSocket, TcpStream};
+
+/// Connects to `host:port` from a socket carrying the firewall mark, if any,
+/// trying every address the host resolves to
+pub async fn connect(address: &str, mark: Option<u32>) -> Result<TcpStream> {
+    let mark = match mark {
+        Some(value) => value,
+        None => return TcpStream::connect(address).await,
+    };
+
+    let mut last = None;
+
+    for address in tokio::net::lookup_host(address).await? {
+        let socket = match address {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+
+        set_mark(&socket, mark)?;
+
+        match socket.connect(address).await {
+            Ok(stream) => return Ok(stream),
+            Err(error) => last = Some(error),
+        }
+    }
+
+    Err(last.unwrap_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} resolved to no address", address),
+        )
+    }))
+}
+
+/// Marks the packets of a socket, so that policy routing keeps them off a TUN
+/// interface, requires `CAP_NET_ADMIN`
+#[cfg(target_os = "linux")]
+pub fn set_mark<S>(socket: &S, mark: u32) -> Result<()>
+where
+    S: std::os::fd::AsRawFd,
+{
+    // SAFETY: the option value is a live `u32` of the given length
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &mark as *const u32 as *const libc::c_void,
+            std::mem::size_of::<u32>() as libc::socklen_t,
+        )
+    };
+
+    match result {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_mark<S>(_socket: &S, _mark: u32) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "firewall marks are only supported on Linux",
+    ))
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use quics_protocol::{
+    incoming::Incoming,
+    request::{Address, Request},
+    response::Response,
+    Provider, Streamable,
+};
+
+use tokio::{
+    io::{copy_bidirectional, duplex, AsyncReadExt, AsyncWriteExt, Result},
+    net::TcpStream,
+    sync::mpsc::{self, Receiver, Sender},
+    sync::oneshot,
+};
+
+use crate::forward::Forward;
+use crate::local::{Handshake, LocalStream};
+use crate::{error, info, warn};
+
+/// Delay before asking the server to listen again, after it refused or the listener closed
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Destinations of the server listeners, by listening address
+type Targets = Arc<Mutex<HashMap<Address, Address>>>;
+
+/// Server port forwarded back to a destination reachable from the client, like `ssh -R`
+///
+/// Parsed from `[bind_address:]port:host:hostport`, the server port is bound
+/// on its loopback address when no bind address is given.
+#[derive(Debug, Clone)]
+pub struct Reverse {
+    pub bind: Address,
+    pub address: Address,
+}
+
+impl FromStr for Reverse {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let forward = value.parse::<Forward>()?;
+
+        Ok(Self {
+            bind: forward.listen.parse()?,
+            address: forward.address,
+        })
+    }
+}
+
+/// Keeps a `TCPBind` stream open for every [`Reverse`], and connects the
+/// streams opened by the server to their destinations.
+pub struct ReverseServer {
+    inner: Receiver<(LocalStream, Request)>,
+}
+
+impl ReverseServer {
+    pub fn with<A, S>(reverses: Vec<Reverse>, accepted: A) -> Self
+    where
+        A: Provider<S> + Send + 'static,
+        S: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
+    {
+        let (sender, inner) = mpsc::channel(1);
+        let targets = Targets::default();
+
+        for reverse in reverses {
+            tokio::spawn(Self::bind(reverse, sender.clone(), targets.clone()));
+        }
+
+        tokio::spawn(Self::accept(accepted, targets));
+
+        Self { inner }
+    }
+
+    async fn bind(reverse: Reverse, sender: Sender<(LocalStream, Request)>, targets: Targets) {
+        loop {
+            let (local, mut control) = duplex(64);
+            let (notify, outcome) = oneshot::channel();
+
+            let local = LocalStream::duplex(local, Handshake::Notify(notify));
+            let request = Request::TCPBind(reverse.bind.clone());
+
+            if sender.send((local, request)).await.is_err() {
+                return;
+            }
+
+            match outcome.await {
+                Ok(Response::Connected(listener)) => {
+                    info!("REVERSE {} to {}", listener, reverse.address);
+
+                    targets
+                        .lock()
+                        .unwrap()
+                        .insert(listener.clone(), reverse.address.clone());
+
+                    // Nothing is relayed on the stream, it is only watched for closing
+                    let mut buffer = [0u8; 64];
+                    while let Ok(1..) = control.read(&mut buffer).await {}
+
+                    targets.lock().unwrap().remove(&listener);
+
+                    warn!("REVERSE {} closed", listener);
+                }
+
+                Ok(_response) => {
+                    warn!("REVERSE {} refused: {:?}", reverse.bind, _response);
+                }
+
+                Err(_) => {}
+            }
+
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    }
+
+    async fn accept<A, S>(mut accepted: A, targets: Targets)
+    where
+        A: Provider<S>,
+        S: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
+    {
+        while let Some(stream) = accepted.fetch().await {
+            let targets = targets.clone();
+
+            tokio::spawn(async move {
+                if let Err(_error) = Self::forward(stream, targets).await {
+                    error!("{}", _error);
+                }
+            });
+        }
+    }
+
+    async fn forward<S>(mut stream: S, targets: Targets) -> Result<()>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin + Send,
+    {
+        let incoming = <Incoming as Streamable>::read(&mut stream).await?;
+
+        let address = targets
+            .lock()
+            .unwrap()
+            .get(&incoming.listener)
+            .cloned()
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("no reverse forwarding for {}", incoming.listener),
+                )
+            })?;
+
+        info!(
+            "REVERSE {} from {} to {}",
+            incoming.listener, incoming.peer, address
+        );
+
+        let mut connection = TcpStream::connect(address.to_string()).await?;
+
+        copy_bidirectional(&mut stream, &mut connection).await?;
+
+        Ok(())
+    }
+}
+
+impl Provider<(LocalStream, Request)> for ReverseServer {
+    async fn fetch(&mut self) -> Option<(LocalStream, Request)> {
+        self.inner.recv().await
+    }
+}
@@ -0,0 +1,654 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use quics_protocol::{
+    limit::Shaped,
+    request::{Address, Request},
+    response::Response,
+    shutdown::Shutdown,
+    Limited, Provider, Reply,
+};
+use regex::Regex;
+use tokio::io::copy_bidirectional;
+use tokio::time::timeout;
+
+use crate::local::LocalStream;
+use crate::outbound;
+use crate::{debug, error};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Capacity of the pipe between a UDP association and the client
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// What is done with a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Tunnelled through the remote server
+    Proxy,
+
+    /// Connected from the client itself
+    Direct,
+
+    /// Refused, the local peer is told the connection is not allowed
+    Reject,
+}
+
+impl FromStr for Action {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_ascii_uppercase().as_str() {
+            "PROXY" => Ok(Self::Proxy),
+            "DIRECT" => Ok(Self::Direct),
+            "REJECT" => Ok(Self::Reject),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "unknown action '{}', expected PROXY, DIRECT or REJECT",
+                    value
+                ),
+            )),
+        }
+    }
+}
+
+enum Matcher {
+    Domain(String),
+    DomainSuffix(String),
+    DomainKeyword(String),
+    DomainRegex(Regex),
+    IpCidr(IpAddr, u8),
+    Port(u16, u16),
+}
+
+impl Matcher {
+    fn matches(&self, address: &Address) -> bool {
+        let (domain, ip, port) = match address {
+            Address::Domain(domain, port) => (Some(domain.to_ascii_lowercase()), None, *port),
+            Address::IPv4(addr) => (None, Some(IpAddr::V4(*addr.ip())), addr.port()),
+            Address::IPv6(addr) => (None, Some(IpAddr::V6(*addr.ip())), addr.port()),
+        };
+
+        match (self, domain, ip) {
+            (Self::Domain(value), Some(domain), _) => domain == *value,
+            (Self::DomainSuffix(value), Some(domain), _) => {
+                domain == *value || domain.ends_with(&format!(".{}", value))
+            }
+            (Self::DomainKeyword(value), Some(domain), _) => domain.contains(value.as_str()),
+            (Self::DomainRegex(regex), Some(domain), _) => regex.is_match(&domain),
+            (Self::IpCidr(network, prefix), _, Some(ip)) => contains(*network, *prefix, ip),
+            (Self::Port(start, end), _, _) => (*start..=*end).contains(&port),
+            _ => false,
+        }
+    }
+}
+
+/// Ordered routing rules, the first matching rule decides
+///
+/// Loaded from `TYPE,VALUE,ACTION` lines, where `TYPE` is one of `DOMAIN`,
+/// `DOMAIN-SUFFIX`, `DOMAIN-KEYWORD`, `DOMAIN-REGEX`, `IP-CIDR` or `DST-PORT`
+/// and `ACTION` one of `PROXY`, `DIRECT` or `REJECT`. A `FINAL,ACTION` line sets
+/// the action of unmatched connections, `PROXY` by default.
+///
+/// Domains are never resolved to match `IP-CIDR` rules. Clones share the rules,
+/// see [`Rules::replace`].
+#[derive(Clone, Default)]
+pub struct Rules(Arc<RwLock<Table>>);
+
+struct Table {
+    rules: Vec<(Matcher, Action)>,
+    last: Action,
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            last: Action::Proxy,
+        }
+    }
+}
+
+impl Rules {
+    /// Loads the rules of a file, see [`Rules::parse`]
+    pub fn load<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parses rule lines, empty lines and lines starting with `#` are ignored
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+        let mut last = Action::Proxy;
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |message: String| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("line {}: {}", number + 1, message),
+                )
+            };
+
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+
+            let (kind, value, action) = match fields.as_slice() {
+                [kind, action] if kind.eq_ignore_ascii_case("FINAL") => {
+                    last = action
+                        .parse()
+                        .map_err(|error: Error| invalid(error.to_string()))?;
+                    continue;
+                }
+                [kind, value, action] => (kind.to_ascii_uppercase(), *value, *action),
+                _ => return Err(invalid("expected `TYPE,VALUE,ACTION`".to_string())),
+            };
+
+            let action = action
+                .parse()
+                .map_err(|error: Error| invalid(error.to_string()))?;
+
+            let matcher = match kind.as_str() {
+                "DOMAIN" => Matcher::Domain(value.to_ascii_lowercase()),
+                "DOMAIN-SUFFIX" => {
+                    Matcher::DomainSuffix(value.trim_start_matches('.').to_ascii_lowercase())
+                }
+                "DOMAIN-KEYWORD" => Matcher::DomainKeyword(value.to_ascii_lowercase()),
+                "DOMAIN-REGEX" => Matcher::DomainRegex(
+                    Regex::new(value).map_err(|error| invalid(error.to_string()))?,
+                ),
+                "IP-CIDR" | "IP-CIDR6" => {
+                    let (network, prefix) = parse_cidr(value)
+                        .ok_or_else(|| invalid(format!("invalid network '{}'", value)))?;
+                    Matcher::IpCidr(network, prefix)
+                }
+                "DST-PORT" => {
+                    let (start, end) = parse_ports(value)
+                        .ok_or_else(|| invalid(format!("invalid port '{}'", value)))?;
+                    Matcher::Port(start, end)
+                }
+                _ => return Err(invalid(format!("unknown rule type '{}'", kind))),
+            };
+
+            rules.push((matcher, action));
+        }
+
+        Ok(Self(Arc::new(RwLock::new(Table { rules, last }))))
+    }
+
+    /// Swaps in other rules, every clone of these decides with them from now on
+    pub fn replace(&self, other: Self) {
+        let table = std::mem::take(&mut *other.0.write().unwrap());
+
+        *self.0.write().unwrap() = table;
+    }
+
+    pub fn action(&self, address: &Address) -> Action {
+        let table = self.0.read().unwrap();
+
+        table
+            .rules
+            .iter()
+            .find(|(matcher, _)| matcher.matches(address))
+            .map(|(_, action)| *action)
+            .unwrap_or(table.last)
+    }
+}
+
+/// Routes the streams of an inbound provider, only the proxied ones are handed
+/// over to the client, direct ones are connected here and rejected ones refused.
+///
+/// The datagrams of UDP associations are routed one by one, by their destination.
+///
+/// Direct connections are tracked by the shutdown and shaped by the limit their
+/// local stream comes with, like proxied ones.
+pub struct Router<P> {
+    inner: P,
+    rules: Rules,
+    mark: Option<u32>,
+    shutdown: Shutdown,
+}
+
+impl<P> Router<P>
+where
+    P: Provider<(LocalStream, Request)>,
+{
+    pub fn with(inner: P, rules: Rules) -> Self {
+        Self {
+            inner,
+            rules,
+            mark: None,
+            shutdown: Shutdown::default(),
+        }
+    }
+
+    /// Firewall mark of the direct connections, see [`outbound::set_mark`]
+    pub fn with_mark(mut self, value: Option<u32>) -> Self {
+        self.mark = value;
+
+        self
+    }
+
+    pub fn with_shutdown(mut self, value: Shutdown) -> Self {
+        self.shutdown = value;
+
+        self
+    }
+}
+
+impl<P> Provider<(LocalStream, Request)> for Router<P>
+where
+    P: Provider<(LocalStream, Request)> + Send,
+{
+    async fn fetch(&mut self) -> Option<(LocalStream, Request)> {
+        loop {
+            let (mut local, request) = self.inner.fetch().await?;
+
+            // UDP associations have a destination per datagram, routed as they go
+            let address = match &request {
+                Request::TCPConnect(address) => address,
+                Request::UDPAssociate => {
+                    let (stream, pipe) = local.interpose(PIPE_CAPACITY);
+                    let (rules, mark) = (self.rules.clone(), self.mark);
+                    let task = self.shutdown.track();
+
+                    tokio::spawn(async move {
+                        let _task = task;
+
+                        if let Err(_error) = udp::relay(local, pipe, rules, mark).await {
+                            debug!("UDP {}", _error);
+                        }
+                    });
+
+                    return Some((stream, request));
+                }
+                _ => return Some((local, request)),
+            };
+
+            let action = self.rules.action(address);
+
+            debug!("ROUTE {} {:?}", address, action);
+
+            match action {
+                Action::Proxy => return Some((local, request)),
+
+                Action::Direct => {
+                    let (address, mark) = (address.clone(), self.mark);
+                    let task = self.shutdown.track();
+
+                    tokio::spawn(async move {
+                        let _task = task;
+
+                        if let Err(_error) = direct(local, address, mark).await {
+                            error!("{}", _error);
+                        }
+                    });
+                }
+
+                Action::Reject => {
+                    tokio::spawn(async move { local.reply(&Response::NotAllowed).await });
+                }
+            }
+        }
+    }
+}
+
+async fn direct(mut local: LocalStream, address: Address, mark: Option<u32>) -> Result<()> {
+    let address = address.to_string();
+
+    let connect = match timeout(CONNECT_TIMEOUT, outbound::connect(&address, mark)).await {
+        Ok(value) => value,
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, "connect timed out")),
+    };
+
+    let mut connect = match connect {
+        Ok(value) => value,
+        Err(error) => {
+            local.reply(&Response::from(&error)).await?;
+            return Err(error);
+        }
+    };
+
+    local
+        .reply(&Response::Connected(connect.local_addr()?.into()))
+        .await?;
+
+    let limits = local.limit().into_iter().collect();
+    let mut local = Shaped::new(local, limits);
+    copy_bidirectional(&mut local, &mut connect).await?;
+
+    Ok(())
+}
+
+mod udp {
+    use std::net::SocketAddr;
+
+    use bytes::Bytes;
+    use quics_protocol::datagram::Datagram;
+    use quics_protocol::Streamable;
+    use tokio::io::{split, DuplexStream, ReadHalf, WriteHalf};
+    use tokio::net::UdpSocket;
+    use tokio::sync::Mutex;
+
+    use super::*;
+
+    /// Prefer a dual stack socket, IPv4 destinations are then sent as IPv4-mapped addresses
+    async fn bind(mark: Option<u32>) -> Result<UdpSocket> {
+        let socket = match UdpSocket::bind("[::]:0").await {
+            Ok(socket) => socket,
+            Err(_) => UdpSocket::bind("0.0.0.0:0").await?,
+        };
+
+        if let Some(mark) = mark {
+            outbound::set_mark(&socket, mark)?;
+        }
+
+        Ok(socket)
+    }
+
+    /// First address of the destination the socket can send to
+    async fn resolve(socket: &UdpSocket, address: &Address) -> Result<SocketAddr> {
+        let ipv6 = socket.local_addr()?.is_ipv6();
+
+        tokio::net::lookup_host(address.to_string())
+            .await?
+            .find_map(|address| match (address, ipv6) {
+                (SocketAddr::V4(addr), true) => Some(SocketAddr::new(
+                    addr.ip().to_ipv6_mapped().into(),
+                    addr.port(),
+                )),
+                (SocketAddr::V6(_), false) => None,
+                _ => Some(address),
+            })
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} resolved to no reachable address", address),
+                )
+            })
+    }
+
+    async fn handle_local(
+        mut local: ReadHalf<LocalStream>,
+        mut remote: WriteHalf<DuplexStream>,
+        socket: &UdpSocket,
+        rules: Rules,
+    ) -> Result<()> {
+        loop {
+            let datagram = <Datagram as Streamable>::read(&mut local).await?;
+
+            match rules.action(&datagram.address) {
+                Action::Proxy => Streamable::write(&datagram, &mut remote).await?,
+
+                // A single unresolvable destination must not tear down the association
+                Action::Direct => match resolve(socket, &datagram.address).await {
+                    Ok(address) => {
+                        socket.send_to(&datagram.data, address).await?;
+                    }
+                    Err(_error) => {
+                        debug!("UDP {}", _error);
+                    }
+                },
+
+                Action::Reject => {
+                    debug!("UDP {} rejected", datagram.address);
+                }
+            }
+        }
+    }
+
+    async fn handle_remote(
+        mut remote: ReadHalf<DuplexStream>,
+        local: &Mutex<WriteHalf<LocalStream>>,
+    ) -> Result<()> {
+        loop {
+            let datagram = <Datagram as Streamable>::read(&mut remote).await?;
+
+            Streamable::write(&datagram, &mut *local.lock().await).await?;
+        }
+    }
+
+    async fn handle_direct(
+        socket: &UdpSocket,
+        local: &Mutex<WriteHalf<LocalStream>>,
+    ) -> Result<()> {
+        let mut buffer = vec![0u8; 65535];
+
+        loop {
+            let (size, address) = socket.recv_from(&mut buffer).await?;
+            let datagram = Datagram::new(address.into(), Bytes::copy_from_slice(&buffer[..size]));
+
+            Streamable::write(&datagram, &mut *local.lock().await).await?;
+        }
+    }
+
+    /// Relays the datagrams of a local association by their destination, proxied
+    /// ones through the pipe to the client, direct ones from a socket of its own
+    /// carrying the firewall mark, rejected ones are dropped. Ends with either side.
+    pub async fn relay(
+        local: LocalStream,
+        remote: DuplexStream,
+        rules: Rules,
+        mark: Option<u32>,
+    ) -> Result<()> {
+        let socket = bind(mark).await?;
+
+        let (local_reader, local_writer) = split(local);
+        let (remote_reader, remote_writer) = split(remote);
+        let local_writer = Mutex::new(local_writer);
+
+        tokio::select! {
+            result = handle_local(local_reader, remote_writer, &socket, rules) => result,
+            result = handle_remote(remote_reader, &local_writer) => result,
+            result = handle_direct(&socket, &local_writer) => result,
+        }
+    }
+}
+
+fn parse_cidr(value: &str) -> Option<(IpAddr, u8)> {
+    let (network, prefix) = match value.split_once('/') {
+        Some((network, prefix)) => (network.parse::<IpAddr>().ok()?, prefix.parse().ok()?),
+        None => {
+            let network = value.parse::<IpAddr>().ok()?;
+            (network, if network.is_ipv4() { 32 } else { 128 })
+        }
+    };
+
+    match network {
+        IpAddr::V4(_) if prefix <= 32 => Some((network, prefix)),
+        IpAddr::V6(_) if prefix <= 128 => Some((network, prefix)),
+        _ => None,
+    }
+}
+
+fn parse_ports(value: &str) -> Option<(u16, u16)> {
+    let (start, end) = match value.split_once('-') {
+        Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+        None => {
+            let port = value.parse().ok()?;
+            (port, port)
+        }
+    };
+
+    (start <= end).then_some((start, end))
+}
+
+fn contains(network: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actions() {
+        let rules = Rules::parse(
+            "# Comment\n\
+             \n\
+             DOMAIN,Exact.example.com,REJECT\n\
+             DOMAIN-SUFFIX,.example.com,DIRECT\n\
+             domain-keyword,tracker,reject\n\
+             DOMAIN-REGEX,^cdn[0-9]+\\.,DIRECT\n\
+             IP-CIDR,10.0.0.0/8,DIRECT\n\
+             IP-CIDR,192.0.2.1,REJECT\n\
+             IP-CIDR6,2001:db8::/32,DIRECT\n\
+             FINAL,REJECT",
+        )
+        .unwrap();
+
+        #[rustfmt::skip]
+        let addresses = [
+            ("exact.example.com:443", Action::Reject),
+            ("EXACT.example.com:443", Action::Reject),
+            ("example.com:443", Action::Direct),
+            ("www.example.com:443", Action::Direct),
+            ("notexample.com:443", Action::Reject),
+            ("ads.tracker.net:80", Action::Reject),
+            ("cdn42.example.org:443", Action::Direct),
+            ("www.cdn42.example.org:443", Action::Reject),
+            ("10.1.2.3:22", Action::Direct),
+            ("11.1.2.3:22", Action::Reject),
+            ("192.0.2.1:80", Action::Reject),
+            ("192.0.2.2:80", Action::Reject),
+            ("[2001:db8::1]:443", Action::Direct),
+            ("[2001:db9::1]:443", Action::Reject),
+            ("[::ffff:10.1.2.3]:22", Action::Direct),
+        ];
+
+        for (address, expected) in addresses {
+            assert_eq!(
+                rules.action(&address.parse().unwrap()),
+                expected,
+                "{}",
+                address
+            );
+        }
+
+        // Domains are never resolved, unmatched ones are proxied by default
+        let rules = Rules::parse("IP-CIDR,0.0.0.0/0,DIRECT").unwrap();
+        assert_eq!(
+            rules.action(&"localhost:80".parse().unwrap()),
+            Action::Proxy
+        );
+        assert_eq!(
+            rules.action(&"127.0.0.1:80".parse().unwrap()),
+            Action::Direct
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        for line in [
+            "DOMAIN,example.com",
+            "DOMAIN,example.com,DIRECT,PROXY",
+            "DOMAIN,example.com,ALLOW",
+            "HOST,example.com,DIRECT",
+            "DOMAIN-REGEX,(,DIRECT",
+            "IP-CIDR,10.0.0.0/33,DIRECT",
+            "IP-CIDR6,2001:db8::/129,DIRECT",
+            "IP-CIDR,example.com,DIRECT",
+            "FINAL,ALLOW",
+            "FINAL",
+        ] {
+            assert!(Rules::parse(line).is_err(), "{}", line);
+        }
+
+        let error = Rules::parse("# Comment\nDOMAIN,example.com,DIRECT\nFINAL,ALLOW")
+            .err()
+            .unwrap();
+        assert!(error.to_string().starts_with("line 3: "), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn udp_datagrams() {
+        use bytes::Bytes;
+        use quics_protocol::datagram::Datagram;
+        use quics_protocol::Streamable;
+        use tokio::io::duplex;
+        use tokio::net::UdpSocket;
+
+        use crate::local::Handshake;
+
+        let rules = Rules::parse("IP-CIDR,127.0.0.1,DIRECT\nDST-PORT,9,REJECT").unwrap();
+
+        let (local, mut client) = duplex(PIPE_CAPACITY);
+        let mut local = LocalStream::duplex(local, Handshake::None);
+        let (mut stream, pipe) = local.interpose(PIPE_CAPACITY);
+        tokio::spawn(udp::relay(local, pipe, rules, None));
+
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_address: Address = echo.local_addr().unwrap().into();
+
+        for (address, data) in [
+            (echo_address.clone(), "direct"),
+            ("example.com:9".parse().unwrap(), "rejected"),
+            ("example.com:53".parse().unwrap(), "proxied"),
+        ] {
+            let datagram = Datagram::new(address, Bytes::from(data));
+            Streamable::write(&datagram, &mut client).await.unwrap();
+        }
+
+        // Direct datagrams are sent from the client, their answers come back too
+        let mut buffer = [0u8; 64];
+        let (size, from) = echo.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..size], b"direct");
+        echo.send_to(b"answer", from).await.unwrap();
+
+        let datagram = <Datagram as Streamable>::read(&mut client).await.unwrap();
+        assert_eq!(datagram.address, echo_address);
+        assert_eq!(&datagram.data[..], b"answer");
+
+        // Only the proxied ones reach the client, rejected ones are dropped
+        let datagram = <Datagram as Streamable>::read(&mut stream).await.unwrap();
+        assert_eq!(datagram.address, "example.com:53".parse().unwrap());
+        assert_eq!(&datagram.data[..], b"proxied");
+
+        let answer = Datagram::new(datagram.address, Bytes::from("answer"));
+        Streamable::write(&answer, &mut stream).await.unwrap();
+
+        let datagram = <Datagram as Streamable>::read(&mut client).await.unwrap();
+        assert_eq!(datagram.address, "example.com:53".parse().unwrap());
+        assert_eq!(&datagram.data[..], b"answer");
+    }
+
+    #[test]
+    fn port_ranges() {
+        let rules = Rules::parse("DST-PORT,8000-8080,DIRECT\nDST-PORT,22,REJECT").unwrap();
+        let action = |address: &str| rules.action(&address.parse().unwrap());
+
+        assert_eq!(action("example.com:8000"), Action::Direct);
+        assert_eq!(action("192.0.2.1:8080"), Action::Direct);
+        assert_eq!(action("example.com:22"), Action::Reject);
+        assert_eq!(action("example.com:8081"), Action::Proxy);
+
+        for line in [
+            "DST-PORT,8080-8000,DIRECT",
+            "DST-PORT,80-,DIRECT",
+            "DST-PORT,65536,DIRECT",
+        ] {
+            assert!(Rules::parse(line).is_err(), "{}", line);
+        }
+    }
+}
@@ -1,64 +1,66 @@
-use std::{error::Error, future::Future, net::SocketAddr};
+use std::{
+    collections::HashMap,
+    error::Error,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
 
 use quics_protocol::{
     request::{Address, Request},
+    response::Response,
     Provider,
 };
 
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, Result},
     net::TcpStream,
-    sync::mpsc::{self, Receiver},
+    sync::mpsc::{Receiver, Sender},
+    sync::oneshot,
 };
 
-use crate::{error, info};
+use crate::local::{listen, Handshake, LocalStream};
+use crate::{info, warn};
 
 pub struct SocksServer {
-    inner: Receiver<(TcpStream, Request)>,
+    inner: Receiver<(LocalStream, Request)>,
 }
 
 impl SocksServer {
-    pub async fn with(address: String) -> std::result::Result<Self, Box<dyn Error>> {
-        use tokio::net::TcpListener;
-
-        let address: SocketAddr = address.parse()?;
-        let listener = TcpListener::bind(address).await?;
-
-        let (sender, receiver) = mpsc::channel(1);
-
-        tokio::spawn(async move {
-            loop {
-                let sender = sender.clone();
-                match listener.accept().await {
-                    Ok((stream, _address)) => {
-                        tokio::spawn(async move {
-                            let request = match Self::handle(stream).await {
-                                Ok(value) => value,
-                                Err(_error) => {
-                                    error!("{}", _error);
-                                    return;
-                                }
-                            };
-
-                            if let Some(value) = request {
-                                if sender.send(value).await.is_err() {
-                                    return;
-                                }
-                            }
-                        });
-                    }
+    pub async fn with(
+        address: String,
+        credentials: Credentials,
+    ) -> std::result::Result<Self, Box<dyn Error>> {
+        let inner = listen(address, move |stream, sender| {
+            Self::handle(stream, sender, credentials.clone())
+        })
+        .await?;
+
+        Ok(Self { inner })
+    }
 
-                    Err(_error) => {
-                        error!("failed to accept: {:?}", _error);
-                    }
-                };
-            }
-        });
+    /// Serves SOCKS5, or SOCKS4 and SOCKS4a as told by the version byte
+    pub(crate) async fn handle(
+        stream: TcpStream,
+        sender: Sender<(LocalStream, Request)>,
+        credentials: Credentials,
+    ) -> Result<()> {
+        let mut version = [0u8; 1];
+        if stream.peek(&mut version).await? == 0 {
+            return Ok(());
+        }
 
-        Ok(Self { inner: receiver })
+        match version[0] {
+            socks4::VERSION => socks4::handle(stream, sender, credentials).await,
+            _ => Self::handle_socks5(stream, sender, credentials).await,
+        }
     }
 
-    async fn handle(mut stream: TcpStream) -> Result<Option<(TcpStream, Request)>> {
+    async fn handle_socks5(
+        mut stream: TcpStream,
+        sender: Sender<(LocalStream, Request)>,
+        credentials: Credentials,
+    ) -> Result<()> {
         use socks::socks5::{
             Address as SocksAddress, Method as Socks5Method, Request as Socks5Request,
             Response as Socks5Response,
@@ -69,12 +71,23 @@ impl SocksServer {
         let methods = <Vec<Socks5Method> as Streamable>::read(&mut stream).await?;
 
         // Authentication
-        let method = <Self as Authentication>::select(methods).await?;
+        let method = credentials.select(methods).await?;
         <Socks5Method as Streamable>::write(&method, &mut stream).await?;
 
+        if matches!(method, Socks5Method::NoAcceptableMethod) {
+            warn!(
+                "SOCKS5 {:?} offered no acceptable authentication method",
+                stream.peer_addr()
+            );
+            return Ok(());
+        }
+
         // Process Authentication
         if !matches!(method, Socks5Method::NoAuthentication) {
-            <Self as Authentication>::process(&mut stream).await?;
+            if let Err(error) = credentials.process(&mut stream).await {
+                warn!("SOCKS5 {:?} authentication rejected", stream.peer_addr());
+                return Err(error);
+            }
         }
 
         // Read Request
@@ -84,124 +97,462 @@ impl SocksServer {
 
         match request {
             Socks5Request::Connect(address) => {
-                Socks5Response::unspecified_success()
-                    .write(&mut stream)
-                    .await?;
+                let request = Request::TCPConnect(to_address(address));
 
-                let address = match address {
-                    SocksAddress::IPv4(value) => Address::IPv4(value),
-                    SocksAddress::IPv6(value) => Address::IPv6(value),
-                    SocksAddress::Domain(domain, port) => Address::Domain(domain, port),
-                };
+                // Replied by the client once the remote server connected
+                let stream = LocalStream::tcp(stream, Handshake::Socks5);
+                let _ = sender.send((stream, request)).await;
 
-                return Ok(Some((stream, Request::TCPConnect(address))));
+                Ok(())
             }
 
-            Socks5Request::Associate(address) => {
+            Socks5Request::Associate(_address) => {
+                use tokio::io::duplex;
                 use tokio::net::UdpSocket;
 
-                // IPV4 & IPV6
-                let socket_addr = match socks5_udp::to_socket_address(address).await? {
-                    SocketAddr::V4(_) => "0.0.0.0:0",
-                    SocketAddr::V6(_) => "[::]:0",
-                };
-
+                // Relay on the interface the SOCKS client reached us through
+                let socket_addr = SocketAddr::new(stream.local_addr()?.ip(), 0);
                 let inbound = UdpSocket::bind(socket_addr).await?;
-                let outbound = UdpSocket::bind(socket_addr).await?;
 
-                let address = SocksAddress::from_socket_address(inbound.local_addr()?);
-                Socks5Response::Success(address).write(&mut stream).await?;
+                // Datagrams are framed through the pipe and tunnelled by the QUIC client
+                let (local, remote) = duplex(socks5_udp::PIPE_CAPACITY);
+
+                let (notify, outcome) = oneshot::channel();
+                let local = LocalStream::duplex(local, Handshake::Notify(notify));
+
+                if sender.send((local, Request::UDPAssociate)).await.is_err() {
+                    return Ok(());
+                }
+
+                match outcome.await {
+                    Ok(response) if response.is_success() => {
+                        let address = SocksAddress::from_socket_address(inbound.local_addr()?);
+                        Socks5Response::Success(address).write(&mut stream).await?;
+                    }
+                    Ok(response) => {
+                        to_socks_response(&response).write(&mut stream).await?;
+                        return Err(std::io::Error::other(format!(
+                            "remote server replied {:?} to UDP associate",
+                            response
+                        )));
+                    }
+                    Err(_) => {
+                        Socks5Response::GeneralFailure.write(&mut stream).await?;
+                        return Err(std::io::Error::other("UDP associate was dropped"));
+                    }
+                }
 
                 tokio::select! {
                     // TCP Stream closed
                     _ = stream.read_u8() => {}
 
                     // UDP Transfer
-                    _ = socks5_udp::relay(inbound, outbound) => {}
+                    _ = socks5_udp::relay(inbound, remote) => {}
                 }
 
-                return Ok(None);
+                Ok(())
             }
             _ => {
                 Socks5Response::CommandNotSupported
                     .write(&mut stream)
                     .await?;
 
-                return Err(std::io::Error::other("unsupported socks command"));
+                Err(std::io::Error::other("unsupported socks command"))
             }
-        };
+        }
     }
 }
 
-impl Authentication for SocksServer {
-    async fn select(_methods: Vec<socks::socks5::Method>) -> Result<socks::socks5::Method> {
-        Ok(socks::socks5::Method::NoAuthentication)
+/// Username/password accounts of the local listener, RFC 1929 for SOCKS5
+/// and `Proxy-Authorization: Basic` for HTTP. Not required when there is none.
+/// Clones share the accounts, see [`Credentials::replace`].
+#[derive(Clone, Default)]
+pub struct Credentials(Arc<RwLock<HashMap<String, String>>>);
+
+impl Credentials {
+    /// Parses `username:password` accounts, usernames are unique
+    pub fn parse<'a, I>(accounts: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        use std::io::{Error, ErrorKind};
+
+        let mut credentials = HashMap::new();
+
+        for account in accounts {
+            let (username, password) = account.split_once(':').ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    "expected `username:password` account",
+                )
+            })?;
+
+            if username.is_empty() || username.len() > 255 || password.len() > 255 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "account '{}': username and password must be 1 to 255 bytes",
+                        username
+                    ),
+                ));
+            }
+
+            if credentials
+                .insert(username.to_string(), password.to_string())
+                .is_some()
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("duplicate account '{}'", username),
+                ));
+            }
+        }
+
+        Ok(Self(Arc::new(RwLock::new(credentials))))
     }
 
-    async fn process<T>(_stream: &mut T) -> Result<()>
+    /// Loads `username:password` lines, empty lines and lines starting with `#` are ignored.
+    /// A file without accounts is rejected rather than leaving the listeners open.
+    pub fn load(path: &str) -> Result<Self> {
+        use std::io::{Error, ErrorKind};
+
+        let content = std::fs::read_to_string(path)?;
+
+        let credentials = Self::parse(
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#')),
+        )?;
+
+        if credentials.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "no accounts"));
+        }
+
+        Ok(credentials)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.read().unwrap().is_empty()
+    }
+
+    pub fn verify(&self, username: &[u8], password: &[u8]) -> bool {
+        use quics_protocol::auth::constant_time_eq;
+
+        let credentials = self.0.read().unwrap();
+
+        std::str::from_utf8(username)
+            .ok()
+            .and_then(|username| credentials.get(username))
+            .is_some_and(|expected| constant_time_eq(expected.as_bytes(), password))
+    }
+
+    /// Adds the accounts of another, usernames stay unique
+    pub fn extend(self, other: Self) -> Result<Self> {
+        use std::io::{Error, ErrorKind};
+
+        let accounts = std::mem::take(&mut *other.0.write().unwrap());
+
+        {
+            let mut credentials = self.0.write().unwrap();
+
+            for (username, password) in accounts {
+                if credentials.contains_key(&username) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("duplicate account '{}'", username),
+                    ));
+                }
+
+                credentials.insert(username, password);
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Swaps in other accounts, every clone of these verifies with them from now on
+    pub fn replace(&self, other: Self) {
+        let accounts = std::mem::take(&mut *other.0.write().unwrap());
+
+        *self.0.write().unwrap() = accounts;
+    }
+}
+
+impl Authentication for Credentials {
+    async fn select(&self, methods: Vec<socks::socks5::Method>) -> Result<socks::socks5::Method> {
+        use socks::socks5::Method;
+
+        if self.is_empty() {
+            return Ok(Method::NoAuthentication);
+        }
+
+        match methods
+            .iter()
+            .any(|method| matches!(method, Method::UsernamePassword))
+        {
+            true => Ok(Method::UsernamePassword),
+            false => Ok(Method::NoAcceptableMethod),
+        }
+    }
+
+    async fn process<T>(&self, stream: &mut T) -> Result<()>
     where
         T: AsyncReadExt + AsyncWriteExt + Unpin + Send,
     {
-        Ok(())
+        use std::io::{Error, ErrorKind};
+
+        const VERSION: u8 = 0x01;
+        const SUCCEEDED: u8 = 0x00;
+        const FAILED: u8 = 0x01;
+
+        async fn read_field<T>(stream: &mut T) -> Result<Vec<u8>>
+        where
+            T: AsyncReadExt + Unpin + Send,
+        {
+            let length = stream.read_u8().await? as usize;
+
+            let mut buffer = vec![0u8; length];
+            stream.read_exact(&mut buffer).await?;
+
+            Ok(buffer)
+        }
+
+        let version = stream.read_u8().await?;
+        if version != VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported username/password version {}", version),
+            ));
+        }
+
+        let username = read_field(stream).await?;
+        let password = read_field(stream).await?;
+
+        let accepted = self.verify(&username, &password);
+
+        let status = if accepted { SUCCEEDED } else { FAILED };
+        stream.write_all(&[VERSION, status]).await?;
+
+        match accepted {
+            true => Ok(()),
+            false => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "invalid SOCKS5 username or password",
+            )),
+        }
     }
 }
 
 pub trait Authentication {
     fn select(
+        &self,
         methods: Vec<socks::socks5::Method>,
     ) -> impl Future<Output = Result<socks::socks5::Method>> + Send;
-    fn process<S>(stream: &mut S) -> impl Future<Output = Result<()>> + Send
+    fn process<S>(&self, stream: &mut S) -> impl Future<Output = Result<()>> + Send
     where
         S: AsyncReadExt + AsyncWriteExt + Unpin + Send;
 }
 
-impl Provider<(TcpStream, Request)> for SocksServer {
-    async fn fetch(&mut self) -> Option<(TcpStream, Request)> {
+/// Sends the SOCKS5 reply for the outcome reported by the remote server
+pub async fn reply<S>(stream: &mut S, response: &Response) -> Result<()>
+where
+    S: AsyncWriteExt + Unpin + Send,
+{
+    use socks::Streamable;
+
+    to_socks_response(response).write(stream).await
+}
+
+/// SOCKS5 reply matching the outcome reported by the remote server
+pub fn to_socks_response(response: &Response) -> socks::socks5::Response {
+    use socks::socks5::Response as Socks5Response;
+
+    match response {
+        Response::Succeed => Socks5Response::unspecified_success(),
+        Response::Connected(address) => Socks5Response::Success(to_socks_address(address.clone())),
+        Response::AuthenticationFailed | Response::NotAllowed | Response::QuotaExceeded => {
+            Socks5Response::ConnectionNotAllowed
+        }
+        Response::NetworkUnreachable => Socks5Response::NetworkUnreachable,
+        Response::HostUnreachable | Response::HostUnresolved => Socks5Response::HostUnreachable,
+        Response::ConnectionRefused => Socks5Response::ConnectionRefused,
+        Response::TimedOut => Socks5Response::TtlExpired,
+        Response::NoAcceptableMethod => Socks5Response::CommandNotSupported,
+        Response::GeneralFailure => Socks5Response::GeneralFailure,
+    }
+}
+
+fn to_address(address: socks::socks5::Address) -> Address {
+    use socks::socks5::Address as SocksAddress;
+
+    match address {
+        SocksAddress::IPv4(value) => Address::IPv4(value),
+        SocksAddress::IPv6(value) => Address::IPv6(value),
+        SocksAddress::Domain(domain, port) => Address::Domain(domain, port),
+    }
+}
+
+fn to_socks_address(address: Address) -> socks::socks5::Address {
+    use socks::socks5::Address as SocksAddress;
+
+    match address {
+        Address::IPv4(value) => SocksAddress::IPv4(value),
+        Address::IPv6(value) => SocksAddress::IPv6(value),
+        Address::Domain(domain, port) => SocksAddress::Domain(domain, port),
+    }
+}
+
+impl Provider<(LocalStream, Request)> for SocksServer {
+    async fn fetch(&mut self) -> Option<(LocalStream, Request)> {
         self.inner.recv().await
     }
 }
 
-mod socks5_udp {
-    use socks::socks5::{Address, UdpPacket};
-    use socks::{Streamable, ToBytes};
-    use tokio::net::UdpSocket;
+pub(crate) mod socks4 {
+    use std::io::{Error, ErrorKind};
+    use std::net::{Ipv4Addr, SocketAddrV4};
 
     use super::*;
 
-    pub async fn to_socket_address(address: Address) -> Result<SocketAddr> {
-        use std::io::Error;
+    pub const VERSION: u8 = 0x04;
+
+    const COMMAND_CONNECT: u8 = 0x01;
+
+    const REPLY_VERSION: u8 = 0x00;
+    const REQUEST_GRANTED: u8 = 0x5A;
+    const REQUEST_REJECTED: u8 = 0x5B;
+
+    const MAX_FIELD_LENGTH: usize = 255;
+
+    /// ## Bytes
+    /// ```text
+    ///          +----+----+---------+--------+----------+------+
+    ///          | VN | CD | DSTPORT | DSTIP  |  USERID  | NULL |
+    ///          +----+----+---------+--------+----------+------+
+    ///          | 1  | 1  |    2    |   4    | Variable |  1   |
+    ///          +----+----+---------+--------+----------+------+
+    /// ```
+    ///
+    /// SOCKS4a sets `DSTIP` to `0.0.0.x` with `x` non-zero and appends a
+    /// null terminated domain name after the `USERID`.
+    ///
+    pub async fn handle(
+        mut stream: TcpStream,
+        sender: Sender<(LocalStream, Request)>,
+        credentials: Credentials,
+    ) -> Result<()> {
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header).await?;
+
+        let command = header[1];
+        let port = u16::from_be_bytes([header[2], header[3]]);
+        let ip = Ipv4Addr::new(header[4], header[5], header[6], header[7]);
+
+        let _user_id = read_null_terminated(&mut stream).await?;
+
+        let address = match ip.octets() {
+            [0, 0, 0, x] if x != 0 => {
+                let domain = read_null_terminated(&mut stream).await?;
+                let domain = String::from_utf8(domain)
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid SOCKS4a domain"))?;
+
+                Address::Domain(domain, port)
+            }
+            _ => Address::IPv4(SocketAddrV4::new(ip, port)),
+        };
+
+        info!("SOCKS4 command {} {:?}", command, address);
+
+        // No password to check against the accounts
+        if !credentials.is_empty() {
+            warn!(
+                "SOCKS4 {:?} rejected, authentication is required",
+                stream.peer_addr()
+            );
+            stream.write_all(&reply_bytes(REQUEST_REJECTED)).await?;
+            return Ok(());
+        }
+
+        if command != COMMAND_CONNECT {
+            stream.write_all(&reply_bytes(REQUEST_REJECTED)).await?;
+            return Err(Error::other("unsupported socks4 command"));
+        }
+
+        // Replied by the client once the remote server connected
+        let stream = LocalStream::tcp(stream, Handshake::Socks4);
+        let _ = sender.send((stream, Request::TCPConnect(address))).await;
+
+        Ok(())
+    }
+
+    /// Sends the SOCKS4 reply for the outcome reported by the remote server
+    pub async fn reply<S>(stream: &mut S, response: &Response) -> Result<()>
+    where
+        S: AsyncWriteExt + Unpin + Send,
+    {
+        let mut bytes = match response.is_success() {
+            true => reply_bytes(REQUEST_GRANTED),
+            false => reply_bytes(REQUEST_REJECTED),
+        };
+
+        if let Response::Connected(Address::IPv4(address)) = response {
+            bytes[2..4].copy_from_slice(&address.port().to_be_bytes());
+            bytes[4..8].copy_from_slice(&address.ip().octets());
+        }
 
-        match address {
-            Address::Domain(domain, port) => {
-                use tokio::net::lookup_host;
-                let response = lookup_host((domain.as_str(), port)).await?;
-                let address = response.into_iter().next().ok_or_else(|| {
-                    Error::other(format!("could not resolve domain '{}'", domain))
-                })?;
+        stream.write_all(&bytes).await
+    }
 
-                Ok(address)
+    fn reply_bytes(status: u8) -> [u8; 8] {
+        [REPLY_VERSION, status, 0, 0, 0, 0, 0, 0]
+    }
+
+    async fn read_null_terminated(stream: &mut TcpStream) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+
+        loop {
+            match stream.read_u8().await? {
+                0 => return Ok(buffer),
+                _ if buffer.len() >= MAX_FIELD_LENGTH => {
+                    return Err(Error::new(ErrorKind::InvalidData, "SOCKS4 field too long"))
+                }
+                byte => buffer.push(byte),
             }
-            Address::IPv4(addr) => Ok(addr.into()),
-            Address::IPv6(addr) => Ok(addr.into()),
         }
     }
+}
 
-    async fn handle_udp_response(inbound: &UdpSocket, outbound: &UdpSocket) -> Result<()> {
-        let mut buffer = vec![0u8; 8192];
+mod socks5_udp {
+    use quics_protocol::datagram::Datagram;
+    use socks::socks5::UdpPacket;
+    use socks::{Streamable, ToBytes};
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+    use tokio::net::UdpSocket;
 
+    use super::*;
+
+    pub const PIPE_CAPACITY: usize = 64 * 1024;
+
+    async fn handle_udp_response(
+        inbound: &UdpSocket,
+        mut remote: ReadHalf<DuplexStream>,
+    ) -> Result<()> {
         loop {
-            let (size, remote_addr) = outbound.recv_from(&mut buffer).await?;
+            let datagram = <Datagram as quics_protocol::Streamable>::read(&mut remote).await?;
 
-            let data = (&buffer[..size]).into();
-            let address = Address::from_socket_address(remote_addr);
-            let packet = UdpPacket::un_frag(address, data);
+            let address = to_socks_address(datagram.address);
+            let packet = UdpPacket::un_frag(address, (&datagram.data[..]).into());
 
             inbound.send(&packet.to_bytes()).await?;
         }
     }
 
-    async fn handle_udp_request(inbound: &UdpSocket, outbound: &UdpSocket) -> Result<()> {
-        let mut buffer = vec![0u8; 8192];
+    async fn handle_udp_request(
+        inbound: &UdpSocket,
+        mut remote: WriteHalf<DuplexStream>,
+    ) -> Result<()> {
+        let mut buffer = vec![0u8; 65535];
 
         loop {
             let (size, client_addr) = inbound.recv_from(&mut buffer).await?;
@@ -209,23 +560,111 @@ mod socks5_udp {
             inbound.connect(client_addr).await?;
 
             let packet = UdpPacket::read(&mut &buffer[..size]).await?;
-            let address = to_socket_address(packet.address).await?;
+            let datagram = Datagram::new(to_address(packet.address), packet.data.to_vec().into());
 
-            outbound.send_to(&packet.data, address).await?;
+            quics_protocol::Streamable::write(&datagram, &mut remote).await?;
         }
     }
 
-    pub async fn relay(inbound: UdpSocket, outbound: UdpSocket) -> Result<()> {
+    pub async fn relay(inbound: UdpSocket, remote: DuplexStream) -> Result<()> {
+        use tokio::io::split;
         use tokio::try_join;
 
-        match try_join!(
-            handle_udp_request(&inbound, &outbound),
-            handle_udp_response(&inbound, &outbound)
-        ) {
-            Ok(_) => {}
-            Err(error) => return Err(error),
-        }
+        let (reader, writer) = split(remote);
+
+        try_join!(
+            handle_udp_request(&inbound, writer),
+            handle_udp_response(&inbound, reader)
+        )?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::*;
+    use crate::local::tests::exchange;
+
+    /// Address requested by a SOCKS4 request, with the reply to it
+    async fn socks4(request: &[u8], credentials: Credentials) -> (Option<Address>, Vec<u8>) {
+        exchange(request, |stream, sender| {
+            SocksServer::handle(stream, sender, credentials)
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn socks4_requests() {
+        let ipv4 = |a, b, c, d, port| {
+            Some(Address::IPv4(SocketAddrV4::new(
+                Ipv4Addr::new(a, b, c, d),
+                port,
+            )))
+        };
+        let domain = |domain: &str, port| Some(Address::Domain(domain.to_string(), port));
+        let rejected = vec![0x00, 0x5B, 0, 0, 0, 0, 0, 0];
+        let long = [&[4, 1, 0, 80, 192, 0, 2, 1][..], &[b'u'; 256], &[0]].concat();
+
+        #[rustfmt::skip]
+        let requests: [(&[u8], Option<Address>, Vec<u8>); 9] = [
+            (b"\x04\x01\x00\x50\xc0\x00\x02\x01\x00", ipv4(192, 0, 2, 1, 80), vec![]),
+            (b"\x04\x01\x01\xbb\xc0\x00\x02\x01user\x00", ipv4(192, 0, 2, 1, 443), vec![]),
+            // SOCKS4a, only 0.0.0.x with x non-zero stands for a domain
+            (b"\x04\x01\x01\xbb\x00\x00\x00\x01user\x00example.com\x00", domain("example.com", 443), vec![]),
+            (b"\x04\x01\x00\x50\x00\x00\x00\xff\x00example.com\x00", domain("example.com", 80), vec![]),
+            (b"\x04\x01\x00\x50\x00\x00\x00\x00\x00", ipv4(0, 0, 0, 0, 80), vec![]),
+            // BIND, invalid and truncated domains, user ids over 255 bytes
+            (b"\x04\x02\x00\x50\xc0\x00\x02\x01\x00", None, rejected.clone()),
+            (b"\x04\x01\x00\x50\x00\x00\x00\x01\x00\xff\xfe\x00", None, vec![]),
+            (b"\x04\x01\x00\x50\x00\x00\x00\x01\x00example.com", None, vec![]),
+            (&long, None, vec![]),
+        ];
+
+        for (request, expected, reply) in requests {
+            assert_eq!(
+                socks4(request, Credentials::default()).await,
+                (expected, reply),
+                "{:?}",
+                request
+            );
+        }
+
+        // Without a password, SOCKS4 cannot authenticate
+        let credentials = Credentials::parse(["alice:secret"]).unwrap();
+        assert_eq!(
+            socks4(b"\x04\x01\x00\x50\xc0\x00\x02\x01alice\x00", credentials).await,
+            (None, rejected)
+        );
+    }
+
+    #[test]
+    fn credentials() {
+        let credentials = Credentials::parse(["alice:secret", "bob:pass:word"]).unwrap();
+
+        assert!(credentials.verify(b"alice", b"secret"));
+        assert!(credentials.verify(b"bob", b"pass:word"));
+        assert!(!credentials.verify(b"alice", b"secreT"));
+        assert!(!credentials.verify(b"alice", b"secret2"));
+        assert!(!credentials.verify(b"carol", b"secret"));
+        assert!(!credentials.verify(&[0xff], b"secret"));
+    }
+
+    #[test]
+    fn credentials_rejected() {
+        for accounts in [&["alice"][..], &[":secret"], &["alice:a", "alice:b"]] {
+            assert!(
+                Credentials::parse(accounts.iter().copied()).is_err(),
+                "{:?}",
+                accounts
+            );
+        }
+
+        let credentials = Credentials::parse(["alice:a"]).unwrap();
+        assert!(credentials
+            .extend(Credentials::parse(["alice:b"]).unwrap())
+            .is_err());
+    }
+}
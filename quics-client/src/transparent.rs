@@ -0,0 +1,167 @@
+use std::error::Error;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::AsRawFd;
+
+use quics_protocol::{request::Request, Provider};
+
+use tokio::{
+    io::Result,
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::mpsc::{Receiver, Sender},
+};
+
+use crate::info;
+use crate::local::{serve, Handshake, LocalStream};
+
+/// How connections are diverted to the listener by the firewall
+#[derive(Debug, Clone, Copy)]
+pub enum Mode {
+    /// `iptables -j REDIRECT`, the destination is recovered with `SO_ORIGINAL_DST`
+    Redirect,
+
+    /// `iptables -j TPROXY`, the destination is the local address of the accepted connection
+    Tproxy,
+}
+
+/// Transparent proxy of TCP connections diverted by iptables (Linux only)
+pub struct TransparentServer {
+    inner: Receiver<(LocalStream, Request)>,
+}
+
+impl TransparentServer {
+    pub async fn with(address: String, mode: Mode) -> std::result::Result<Self, Box<dyn Error>> {
+        let address: SocketAddr = address.parse()?;
+
+        let listener = match mode {
+            Mode::Redirect => TcpListener::bind(address).await?,
+            Mode::Tproxy => bind_transparent(address)?,
+        };
+
+        let inner = serve(listener, move |stream, sender| {
+            Self::handle(stream, sender, mode)
+        });
+
+        Ok(Self { inner })
+    }
+
+    async fn handle(
+        stream: TcpStream,
+        sender: Sender<(LocalStream, Request)>,
+        mode: Mode,
+    ) -> Result<()> {
+        let destination = match mode {
+            Mode::Redirect => original_destination(&stream)?,
+            Mode::Tproxy => stream.local_addr()?,
+        };
+
+        info!("TRANSPARENT {:?} to {}", stream.peer_addr(), destination);
+
+        let stream = LocalStream::tcp(stream, Handshake::None);
+        let _ = sender
+            .send((stream, Request::TCPConnect(destination.into())))
+            .await;
+
+        Ok(())
+    }
+}
+
+impl Provider<(LocalStream, Request)> for TransparentServer {
+    async fn fetch(&mut self) -> Option<(LocalStream, Request)> {
+        self.inner.recv().await
+    }
+}
+
+/// Listener accepting connections to any address, requires `CAP_NET_ADMIN`
+fn bind_transparent(address: SocketAddr) -> Result<TcpListener> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    let (level, name) = match address {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+    };
+
+    let enable: libc::c_int = 1;
+
+    // SAFETY: the option value is a live `c_int` of the given length
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    socket.set_reuseaddr(true)?;
+    socket.bind(address)?;
+    socket.listen(1024)
+}
+
+/// Destination of a connection before it was rewritten by REDIRECT
+fn original_destination(stream: &TcpStream) -> Result<SocketAddr> {
+    // IPv4 connections accepted on a dual stack listener are tracked by the IPv4 table
+    let ipv6 = match stream.local_addr()? {
+        SocketAddr::V4(_) => false,
+        SocketAddr::V6(addr) => addr.ip().to_ipv4_mapped().is_none(),
+    };
+
+    if ipv6 {
+        // SAFETY: all zeros is a valid `sockaddr_in6`
+        let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+        getsockopt(
+            stream,
+            libc::SOL_IPV6,
+            libc::IP6T_SO_ORIGINAL_DST,
+            &mut addr,
+        )?;
+
+        Ok(SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::from(addr.sin6_addr.s6_addr),
+            u16::from_be(addr.sin6_port),
+            0,
+            0,
+        )))
+    } else {
+        // SAFETY: all zeros is a valid `sockaddr_in`
+        let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+        getsockopt(stream, libc::SOL_IP, libc::SO_ORIGINAL_DST, &mut addr)?;
+
+        Ok(SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+            u16::from_be(addr.sin_port),
+        )))
+    }
+}
+
+fn getsockopt<T>(
+    stream: &TcpStream,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &mut T,
+) -> Result<()> {
+    let mut length = std::mem::size_of::<T>() as libc::socklen_t;
+
+    // SAFETY: the kernel writes at most `length` bytes into `value`
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            value as *mut T as *mut libc::c_void,
+            &mut length,
+        )
+    };
+
+    match result {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
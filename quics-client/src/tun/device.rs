@@ -0,0 +1,109 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Read, Write};
+use std::net::{Ipv4Addr, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+
+use tokio::io::unix::AsyncFd;
+use tokio::io::{Interest, Result};
+
+use super::Cidr;
+
+/// Linux TUN interface carrying raw IP packets, without the packet information header
+pub struct Device {
+    inner: AsyncFd<File>,
+}
+
+impl Device {
+    /// Attaches to the interface called `name`, or creates it, then brings it up
+    pub fn create(name: &str, address: Option<Cidr>, mtu: usize) -> Result<Self> {
+        if name.is_empty() || name.len() >= libc::IFNAMSIZ {
+            return Err(Error::other(format!("invalid interface name '{}'", name)));
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open("/dev/net/tun")?;
+
+        let mut request = ifreq(name);
+        request.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+        ioctl(file.as_raw_fd(), libc::TUNSETIFF as _, &mut request)?;
+
+        // Interface settings go through any socket of the address family
+        let control = UdpSocket::bind("0.0.0.0:0")?;
+
+        let mut request = ifreq(name);
+        request.ifr_ifru.ifru_mtu = mtu as libc::c_int;
+        ioctl(control.as_raw_fd(), libc::SIOCSIFMTU as _, &mut request)?;
+
+        if let Some(Cidr(ip, prefix)) = address {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+
+            let mut request = ifreq(name);
+            request.ifr_ifru.ifru_addr = sockaddr(ip);
+            ioctl(control.as_raw_fd(), libc::SIOCSIFADDR as _, &mut request)?;
+
+            let mut request = ifreq(name);
+            request.ifr_ifru.ifru_netmask = sockaddr(Ipv4Addr::from(mask));
+            ioctl(control.as_raw_fd(), libc::SIOCSIFNETMASK as _, &mut request)?;
+        }
+
+        let mut request = ifreq(name);
+        ioctl(control.as_raw_fd(), libc::SIOCGIFFLAGS as _, &mut request)?;
+
+        // SAFETY: `ifru_flags` was just filled in by the kernel
+        unsafe {
+            request.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short
+        };
+        ioctl(control.as_raw_fd(), libc::SIOCSIFFLAGS as _, &mut request)?;
+
+        Ok(Self {
+            inner: AsyncFd::new(file)?,
+        })
+    }
+
+    /// Reads a single packet
+    pub async fn recv(&self, buffer: &mut [u8]) -> Result<usize> {
+        self.inner
+            .async_io(Interest::READABLE, |mut file| file.read(buffer))
+            .await
+    }
+
+    /// Writes a single packet
+    pub async fn send(&self, packet: &[u8]) -> Result<usize> {
+        self.inner
+            .async_io(Interest::WRITABLE, |mut file| file.write(packet))
+            .await
+    }
+}
+
+fn ifreq(name: &str) -> libc::ifreq {
+    // SAFETY: all zeros is a valid `ifreq`
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+
+    for (target, byte) in request.ifr_name.iter_mut().zip(name.bytes()) {
+        *target = byte as libc::c_char;
+    }
+
+    request
+}
+
+fn sockaddr(ip: Ipv4Addr) -> libc::sockaddr {
+    // SAFETY: all zeros is a valid `sockaddr_in`
+    let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    addr.sin_family = libc::AF_INET as libc::sa_family_t;
+    addr.sin_addr.s_addr = u32::from(ip).to_be();
+
+    // SAFETY: `sockaddr_in` and `sockaddr` have the same size
+    unsafe { std::mem::transmute::<libc::sockaddr_in, libc::sockaddr>(addr) }
+}
+
+fn ioctl(fd: libc::c_int, request: libc::Ioctl, value: &mut libc::ifreq) -> Result<()> {
+    // SAFETY: every request used here reads or writes a single `ifreq`
+    match unsafe { libc::ioctl(fd, request, value as *mut libc::ifreq) } {
+        -1 => Err(Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
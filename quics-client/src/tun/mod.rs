@@ -0,0 +1,78 @@
+use std::error::Error;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use quics_protocol::{request::Request, Provider};
+
+use tokio::sync::mpsc::{self, Receiver};
+
+use crate::error;
+use crate::local::LocalStream;
+
+mod device;
+mod stack;
+mod udp;
+
+/// Upper bound of the in-process pipe of a single flow
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// IPv4 address of the TUN interface with its prefix length e.g. `198.18.0.1/16`
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr(pub Ipv4Addr, pub u8);
+
+impl FromStr for Cidr {
+    type Err = std::io::Error;
+
+    fn from_str(value: &str) -> std::io::Result<Self> {
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid address '{}', expected address/prefix", value),
+            )
+        };
+
+        let (ip, prefix) = value.split_once('/').ok_or_else(invalid)?;
+        let ip = ip.parse::<Ipv4Addr>().map_err(|_| invalid())?;
+
+        match prefix.parse::<u8>() {
+            Ok(prefix @ 0..=32) => Ok(Self(ip, prefix)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Full-system tunnelling through a TUN interface (Linux only)
+///
+/// A userspace TCP/IP stack terminates the TCP connections routed to the
+/// interface, each of them becomes a `TCPConnect`, and the UDP packets of
+/// every local source port are carried by a `UDPAssociate`.
+pub struct TunServer {
+    inner: Receiver<(LocalStream, Request)>,
+}
+
+impl TunServer {
+    pub fn with(
+        name: String,
+        address: Option<Cidr>,
+        mtu: usize,
+    ) -> std::result::Result<Self, Box<dyn Error>> {
+        let device = device::Device::create(&name, address, mtu)
+            .map_err(|error| format!("TUN {}: {}", name, error))?;
+
+        let (sender, inner) = mpsc::channel(1);
+
+        tokio::spawn(async move {
+            if let Err(_error) = stack::run(device, mtu, sender).await {
+                error!("TUN {} stopped. {}", name, _error);
+            }
+        });
+
+        Ok(Self { inner })
+    }
+}
+
+impl Provider<(LocalStream, Request)> for TunServer {
+    async fn fetch(&mut self) -> Option<(LocalStream, Request)> {
+        self.inner.recv().await
+    }
+}
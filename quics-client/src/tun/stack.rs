@@ -0,0 +1,462 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Buf, Bytes};
+use quics_protocol::request::Request;
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::socket::tcp;
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    HardwareAddress, IpAddress, IpCidr, IpProtocol, Ipv4Address, Ipv4Packet, Ipv6Address,
+    Ipv6Packet, TcpPacket, UdpPacket,
+};
+use tokio::io::DuplexStream;
+use tokio::io::{duplex, split, AsyncReadExt, AsyncWriteExt, ReadHalf, Result, WriteHalf};
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::Notify;
+
+use super::device::Device;
+use super::udp::Udp;
+use super::PIPE_CAPACITY;
+use crate::local::{Handshake, LocalStream};
+use crate::{debug, error};
+
+/// Receive and send buffer size of every TCP socket of the stack
+const SOCKET_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Chunks in flight between the stack and a single flow
+pub(super) const CHANNEL_CAPACITY: usize = 16;
+
+/// Dead local peers are detected by keep-alive probes left unanswered
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Sockets of a SYN whose handshake never completed are reclaimed after this
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Reads packets from the TUN interface until it fails, TCP segments are handed to
+/// the userspace stack and UDP packets are tunnelled as is.
+pub async fn run(device: Device, mtu: usize, sender: Sender<(LocalStream, Request)>) -> Result<()> {
+    let mut queue = Queue {
+        rx: VecDeque::new(),
+        tx: VecDeque::new(),
+        mtu,
+    };
+
+    let mut interface = interface(&mut queue);
+    let mut sockets = SocketSet::new(Vec::new());
+    let mut connections: HashMap<(SocketAddr, SocketAddr), Connection> = HashMap::new();
+
+    let wakeup = Arc::new(Notify::new());
+    let (packet_sender, mut packets) = mpsc::channel(CHANNEL_CAPACITY);
+    let mut udp = Udp::new(sender.clone(), packet_sender);
+
+    let mut buffer = vec![0u8; mtu];
+
+    loop {
+        let delay = interface
+            .poll_delay(Instant::now(), &sockets)
+            .map(Duration::from)
+            .unwrap_or(Duration::from_secs(1));
+
+        tokio::select! {
+            read = device.recv(&mut buffer) => {
+                let packet = &buffer[..read?];
+
+                match parse(packet) {
+                    Some((IpProtocol::Udp, source, destination, payload)) => {
+                        udp.send(source, destination, payload);
+                        continue;
+                    }
+
+                    Some((IpProtocol::Tcp, source, destination, segment)) if is_syn(segment) => {
+                        connections.entry((source, destination)).or_insert_with(|| {
+                            let (sender, wakeup) = (sender.clone(), wakeup.clone());
+                            Connection::accept(&mut sockets, destination, sender, wakeup)
+                        });
+                    }
+
+                    _ => {}
+                }
+
+                queue.rx.push_back(packet.to_vec());
+            }
+
+            Some(packet) = packets.recv() => {
+                device.send(&packet).await?;
+                continue;
+            }
+
+            _ = wakeup.notified() => {}
+
+            _ = tokio::time::sleep(delay) => {}
+        }
+
+        let now = Instant::now();
+
+        interface.poll(now, &mut queue, &mut sockets);
+        connections.retain(|_, connection| connection.pump(&mut sockets));
+        interface.poll(now, &mut queue, &mut sockets);
+
+        while let Some(packet) = queue.tx.pop_front() {
+            device.send(&packet).await?;
+        }
+    }
+}
+
+/// Accepts any destination, every address is routed to the interface itself
+fn interface(queue: &mut Queue) -> Interface {
+    let mut config = Config::new(HardwareAddress::Ip);
+    config.random_seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|value| value.as_nanos() as u64)
+        .unwrap_or_default();
+
+    let mut interface = Interface::new(config, queue, Instant::now());
+    interface.set_any_ip(true);
+
+    interface.update_ip_addrs(|addresses| {
+        let _ = addresses.push(IpCidr::new(IpAddress::v4(0, 0, 0, 1), 0));
+        let _ = addresses.push(IpCidr::new(IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 1), 0));
+    });
+
+    let routes = interface.routes_mut();
+    let _ = routes.add_default_ipv4_route(Ipv4Address::new(0, 0, 0, 1));
+    let _ = routes.add_default_ipv6_route(Ipv6Address::new(0, 0, 0, 0, 0, 0, 0, 1));
+
+    interface
+}
+
+/// Transport protocol, source, destination and payload of an IP packet,
+/// the payload is the whole segment for TCP and the data only for UDP.
+fn parse(packet: &[u8]) -> Option<(IpProtocol, SocketAddr, SocketAddr, &[u8])> {
+    let (protocol, source, destination, payload) = match packet.first()? >> 4 {
+        4 => {
+            let packet = Ipv4Packet::new_checked(packet).ok()?;
+
+            // Fragments are left to the stack
+            if packet.more_frags() || packet.frag_offset() != 0 {
+                return None;
+            }
+
+            let range = packet.header_len() as usize..packet.total_len() as usize;
+            let (protocol, source, destination) =
+                (packet.next_header(), packet.src_addr(), packet.dst_addr());
+
+            (
+                protocol,
+                IpAddr::V4(source),
+                IpAddr::V4(destination),
+                &packet.into_inner()[range],
+            )
+        }
+
+        6 => {
+            let packet = Ipv6Packet::new_checked(packet).ok()?;
+            let range = packet.header_len()..packet.total_len();
+            let (protocol, source, destination) =
+                (packet.next_header(), packet.src_addr(), packet.dst_addr());
+
+            (
+                protocol,
+                IpAddr::V6(source),
+                IpAddr::V6(destination),
+                &packet.into_inner()[range],
+            )
+        }
+
+        _ => return None,
+    };
+
+    let (source_port, destination_port, payload) = match protocol {
+        IpProtocol::Tcp => {
+            let segment = TcpPacket::new_checked(payload).ok()?;
+            (segment.src_port(), segment.dst_port(), payload)
+        }
+
+        IpProtocol::Udp => {
+            let datagram = UdpPacket::new_checked(payload).ok()?;
+            (
+                datagram.src_port(),
+                datagram.dst_port(),
+                &payload[8..datagram.len() as usize],
+            )
+        }
+
+        _ => return None,
+    };
+
+    Some((
+        protocol,
+        SocketAddr::new(source, source_port),
+        SocketAddr::new(destination, destination_port),
+        payload,
+    ))
+}
+
+/// Opening segment of a new TCP connection
+fn is_syn(segment: &[u8]) -> bool {
+    match TcpPacket::new_checked(segment) {
+        Ok(segment) => segment.syn() && !segment.ack(),
+        Err(_) => false,
+    }
+}
+
+/// TCP connection terminated by the stack, relayed through a pipe to the client
+struct Connection {
+    handle: SocketHandle,
+
+    /// Received from the local peer, dropped once the peer sent its FIN
+    upload: Option<Sender<Bytes>>,
+
+    /// Sent by the remote server
+    download: Receiver<Bytes>,
+
+    /// Part of a downloaded chunk the socket had no room for yet
+    pending: Bytes,
+
+    /// Nothing more comes from the remote server
+    finished: bool,
+
+    opened: Instant,
+}
+
+impl Connection {
+    fn accept(
+        sockets: &mut SocketSet<'static>,
+        destination: SocketAddr,
+        sender: Sender<(LocalStream, Request)>,
+        wakeup: Arc<Notify>,
+    ) -> Self {
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0u8; SOCKET_BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0u8; SOCKET_BUFFER_SIZE]),
+        );
+
+        socket.set_nagle_enabled(false);
+        socket.set_ack_delay(None);
+        socket.set_keep_alive(Some(KEEP_ALIVE.into()));
+        socket.set_timeout(Some(TIMEOUT.into()));
+
+        if let Err(_error) = socket.listen(destination) {
+            error!("TUN failed to listen on {}. {:?}", destination, _error);
+        }
+
+        let handle = sockets.add(socket);
+
+        let (upload, upload_receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let (download_sender, download) = mpsc::channel(CHANNEL_CAPACITY);
+
+        tokio::spawn(async move {
+            let (local, pipe) = duplex(PIPE_CAPACITY);
+
+            debug!("TUN TCP to {}", destination);
+
+            let local = LocalStream::duplex(local, Handshake::None);
+            if sender
+                .send((local, Request::TCPConnect(destination.into())))
+                .await
+                .is_err()
+            {
+                return;
+            }
+
+            let _ = relay(pipe, upload_receiver, download_sender, wakeup).await;
+        });
+
+        Self {
+            handle,
+            upload: Some(upload),
+            download,
+            pending: Bytes::new(),
+            finished: false,
+            opened: Instant::now(),
+        }
+    }
+
+    /// Moves data between the socket and the relay, returns `false` once the connection is over
+    fn pump(&mut self, sockets: &mut SocketSet<'static>) -> bool {
+        let socket = sockets.get_mut::<tcp::Socket>(self.handle);
+
+        while let (true, Some(upload)) = (socket.can_recv(), &self.upload) {
+            match upload.try_reserve() {
+                Ok(permit) => {
+                    if let Ok(data) = socket.recv(|data| (data.len(), Bytes::copy_from_slice(data)))
+                    {
+                        permit.send(data);
+                    }
+                }
+                Err(TrySendError::Full(_)) => break,
+                Err(TrySendError::Closed(_)) => {
+                    socket.abort();
+                    break;
+                }
+            }
+        }
+
+        // Data received before the FIN of the peer may still wait for room in the channel
+        if !socket.can_recv()
+            && matches!(
+                socket.state(),
+                tcp::State::CloseWait
+                    | tcp::State::LastAck
+                    | tcp::State::Closing
+                    | tcp::State::TimeWait
+            )
+        {
+            self.upload = None;
+        }
+
+        while !self.finished {
+            if self.pending.is_empty() {
+                match self.download.try_recv() {
+                    Ok(data) => self.pending = data,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        self.finished = true;
+                        socket.close();
+                        break;
+                    }
+                }
+            }
+
+            match socket.send_slice(&self.pending) {
+                Ok(size) if size > 0 => self.pending.advance(size),
+                _ => break,
+            }
+        }
+
+        if matches!(socket.state(), tcp::State::Listen | tcp::State::SynReceived)
+            && Instant::now() - self.opened > HANDSHAKE_TIMEOUT.into()
+        {
+            socket.abort();
+        }
+
+        if socket.state() == tcp::State::Closed {
+            sockets.remove(self.handle);
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Relays between the pipe to the client and the channels to the stack
+async fn relay(
+    pipe: DuplexStream,
+    upload: Receiver<Bytes>,
+    download: Sender<Bytes>,
+    wakeup: Arc<Notify>,
+) -> Result<()> {
+    let (reader, writer) = split(pipe);
+
+    tokio::try_join!(
+        relay_upload(writer, upload, wakeup.clone()),
+        relay_download(reader, download, wakeup)
+    )?;
+
+    Ok(())
+}
+
+async fn relay_upload(
+    mut writer: WriteHalf<DuplexStream>,
+    mut upload: Receiver<Bytes>,
+    wakeup: Arc<Notify>,
+) -> Result<()> {
+    while let Some(data) = upload.recv().await {
+        // Room was made for the stack to read more from the socket
+        wakeup.notify_one();
+
+        writer.write_all(&data).await?;
+    }
+
+    writer.shutdown().await
+}
+
+async fn relay_download(
+    mut reader: ReadHalf<DuplexStream>,
+    download: Sender<Bytes>,
+    wakeup: Arc<Notify>,
+) -> Result<()> {
+    let mut buffer = vec![0u8; SOCKET_BUFFER_SIZE];
+
+    loop {
+        let size = reader.read(&mut buffer).await?;
+        if size == 0 {
+            break;
+        }
+
+        download
+            .send(Bytes::copy_from_slice(&buffer[..size]))
+            .await
+            .map_err(|_| std::io::Error::from(ErrorKind::BrokenPipe))?;
+
+        wakeup.notify_one();
+    }
+
+    drop(download);
+    wakeup.notify_one();
+
+    Ok(())
+}
+
+/// In-memory device between the TUN interface and the stack
+struct Queue {
+    rx: VecDeque<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+    mtu: usize,
+}
+
+impl phy::Device for Queue {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+
+        Some((RxToken(packet), TxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<TxToken<'_>> {
+        Some(TxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ip;
+        capabilities.max_transmission_unit = self.mtu;
+
+        capabilities
+    }
+}
+
+struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0u8; len];
+        let result = f(&mut packet);
+        self.0.push_back(packet);
+
+        result
+    }
+}
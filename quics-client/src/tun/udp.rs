@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::Bytes;
+use quics_protocol::datagram::Datagram;
+use quics_protocol::request::{Address, Request};
+use quics_protocol::Streamable;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::{IpAddress, IpProtocol, IpRepr, UdpPacket, UdpRepr};
+use tokio::io::{duplex, split, Result};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::time::timeout;
+
+use super::stack::CHANNEL_CAPACITY;
+use super::PIPE_CAPACITY;
+use crate::debug;
+use crate::local::{Handshake, LocalStream};
+
+/// An association is closed once its local source sent nothing for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// UDP associations, one for every local source address
+pub struct Udp {
+    flows: HashMap<SocketAddr, Sender<Datagram>>,
+    sender: Sender<(LocalStream, Request)>,
+
+    /// Packets to write to the TUN interface
+    packets: Sender<Vec<u8>>,
+}
+
+impl Udp {
+    pub fn new(sender: Sender<(LocalStream, Request)>, packets: Sender<Vec<u8>>) -> Self {
+        Self {
+            flows: HashMap::new(),
+            sender,
+            packets,
+        }
+    }
+
+    /// Tunnels a packet sent by `source`, dropped when its association is congested
+    pub fn send(&mut self, source: SocketAddr, destination: SocketAddr, payload: &[u8]) {
+        let datagram = Datagram::new(destination.into(), Bytes::copy_from_slice(payload));
+
+        let datagram = match self.flows.get(&source) {
+            Some(flow) => match flow.try_send(datagram) {
+                Ok(()) | Err(TrySendError::Full(_)) => return,
+                Err(TrySendError::Closed(datagram)) => datagram,
+            },
+            None => datagram,
+        };
+
+        self.flows.retain(|_, flow| !flow.is_closed());
+
+        let (flow, datagrams) = mpsc::channel(CHANNEL_CAPACITY);
+        let _ = flow.try_send(datagram);
+        self.flows.insert(source, flow);
+
+        tokio::spawn(associate(
+            source,
+            datagrams,
+            self.sender.clone(),
+            self.packets.clone(),
+        ));
+    }
+}
+
+async fn associate(
+    source: SocketAddr,
+    mut datagrams: Receiver<Datagram>,
+    sender: Sender<(LocalStream, Request)>,
+    packets: Sender<Vec<u8>>,
+) -> Result<()> {
+    let (local, pipe) = duplex(PIPE_CAPACITY);
+
+    debug!("TUN UDP from {}", source);
+
+    let local = LocalStream::duplex(local, Handshake::None);
+    if sender.send((local, Request::UDPAssociate)).await.is_err() {
+        return Ok(());
+    }
+
+    let (mut reader, mut writer) = split(pipe);
+
+    let outbound = async {
+        while let Ok(Some(datagram)) = timeout(IDLE_TIMEOUT, datagrams.recv()).await {
+            Streamable::write(&datagram, &mut writer).await?;
+        }
+
+        Ok(())
+    };
+
+    let inbound = async {
+        loop {
+            let datagram = <Datagram as Streamable>::read(&mut reader).await?;
+
+            if let Some(packet) = packet(datagram, source) {
+                if packets.send(packet).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        result = outbound => result,
+        result = inbound => result,
+    }
+}
+
+/// IP packet carrying a datagram of the remote server back to the local `destination`
+fn packet(datagram: Datagram, destination: SocketAddr) -> Option<Vec<u8>> {
+    let source: SocketAddr = match datagram.address {
+        Address::IPv4(addr) => addr.into(),
+        Address::IPv6(addr) => addr.into(),
+        Address::Domain(..) => return None,
+    };
+
+    if source.is_ipv4() != destination.is_ipv4() {
+        return None;
+    }
+
+    let udp = UdpRepr {
+        src_port: source.port(),
+        dst_port: destination.port(),
+    };
+
+    let ip = IpRepr::new(
+        IpAddress::from(source.ip()),
+        IpAddress::from(destination.ip()),
+        IpProtocol::Udp,
+        udp.header_len() + datagram.data.len(),
+        64,
+    );
+
+    let checksum = ChecksumCapabilities::default();
+    let mut buffer = vec![0u8; ip.buffer_len()];
+
+    ip.emit(&mut buffer[..], &checksum);
+    udp.emit(
+        &mut UdpPacket::new_unchecked(&mut buffer[ip.header_len()..]),
+        &ip.src_addr(),
+        &ip.dst_addr(),
+        datagram.data.len(),
+        |payload| payload.copy_from_slice(&datagram.data),
+        &checksum,
+    );
+
+    Some(buffer)
+}
@@ -0,0 +1,123 @@
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+
+use bytes::{BufMut, BytesMut};
+use tokio::io::AsyncReadExt;
+
+use crate::{Streamable, ToBytes};
+
+/// Application error code of the CONNECTION_CLOSE sent once a client failed to authenticate
+pub const AUTHENTICATION_ERROR_CODE: u32 = 0x02;
+
+#[rustfmt::skip]
+mod consts {
+    pub const AUTHENTICATION_TYPE_NONE:         u8 = 0x00;
+    pub const AUTHENTICATION_TYPE_PASSWORD:     u8 = 0x01;
+}
+
+/// Sent by the client at the start of every stream, before the [`Request`](crate::request::Request).
+///
+/// ## Bytes
+/// ```text
+///          +------+------+----------+------+----------+
+///          | AUTH | ULEN |  UNAME   | PLEN |  PASSWD  |
+///          +------+------+----------+------+----------+
+///          |  1   |  1   | Variable |  1   | Variable |
+///          +------+------+----------+------+----------+
+/// ```
+///
+#[derive(Debug, Clone, Default)]
+pub enum Authentication {
+    #[default]
+    None,
+    Password(String, String),
+}
+
+impl ToBytes for Authentication {
+    fn to_bytes(&self) -> BytesMut {
+        let mut bytes = BytesMut::new();
+
+        match self {
+            Self::None => {
+                bytes.put_u8(consts::AUTHENTICATION_TYPE_NONE);
+            }
+            Self::Password(username, password) => {
+                bytes.put_u8(consts::AUTHENTICATION_TYPE_PASSWORD);
+                bytes.put_u8(username.len() as u8);
+                bytes.extend_from_slice(username.as_bytes());
+                bytes.put_u8(password.len() as u8);
+                bytes.extend_from_slice(password.as_bytes());
+            }
+        };
+
+        bytes
+    }
+}
+
+impl Streamable for Authentication {
+    async fn read<T>(stream: &mut T) -> Result<Self>
+    where
+        T: AsyncReadExt + Unpin + Send,
+    {
+        async fn read_string<T>(stream: &mut T) -> Result<String>
+        where
+            T: AsyncReadExt + Unpin + Send,
+        {
+            let length = stream.read_u8().await? as usize;
+
+            let mut buffer = vec![0u8; length];
+            stream.read_exact(&mut buffer).await?;
+
+            String::from_utf8(buffer).map_err(|_| Error::other("invalid credentials encoding"))
+        }
+
+        let authentication_type = stream.read_u8().await?;
+
+        let authentication = match authentication_type {
+            consts::AUTHENTICATION_TYPE_NONE => Self::None,
+            consts::AUTHENTICATION_TYPE_PASSWORD => {
+                let username = read_string(stream).await?;
+                let password = read_string(stream).await?;
+
+                Self::Password(username, password)
+            }
+
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unsupported authentication type {}", authentication_type),
+                ))
+            }
+        };
+
+        Ok(authentication)
+    }
+}
+
+/// Authenticated user, the default one is anonymous
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct User(Arc<str>);
+
+impl User {
+    pub fn new(name: &str) -> Self {
+        Self(name.into())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Compares secrets in a time independent of where they differ, only their
+/// lengths may leak
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,17 +1,43 @@
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt, Result};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
 
+use crate::auth::Authentication;
+use crate::limit::{Limit, Shaped};
 use crate::request::Request;
-use crate::Provider;
+use crate::response::Response;
+use crate::shutdown::Shutdown;
+use crate::{Limited, Provider, Reply};
 
+/// Attempts to get an answer to a request before the local stream is failed
+const REQUEST_ATTEMPTS: usize = 3;
+
+/// Upper bound of a single attempt, getting a remote stream included. Longer
+/// than the server takes to connect to a destination, so that its answer arrives.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A request is sent again on another remote stream when it could not be sent,
+/// e.g. because the connection of the stream broke, until the attempts run out.
+/// Once sent it is never repeated, the remote server may have acted on it.
+///
+/// The relay of a local stream is shaped by the limit of the client, shared by
+/// all the streams, the one the local stream comes with and its own.
 pub struct Client<L, R, LS, RS>
 where
     L: Provider<(LS, Request)>,
     R: Provider<RS>,
 {
     local: L,
-    remote: R,
+    remote: Option<R>,
+    authentication: Arc<Authentication>,
+    attempts: usize,
+    timeout: Duration,
+    limit: Option<Limit>,
+    stream_rate: Option<u64>,
     _local_stream: PhantomData<LS>,
     _remote_stream: PhantomData<RS>,
 }
@@ -19,41 +45,421 @@ where
 impl<L, R, LS, RS> Client<L, R, LS, RS>
 where
     L: Provider<(LS, Request)>,
-    R: Provider<RS>,
-    LS: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
+    R: Provider<RS> + Send + 'static,
+    LS: AsyncReadExt + AsyncWriteExt + Reply + Limited + Unpin + Send + 'static,
     RS: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
 {
     pub fn with(local: L, remote: R) -> Self {
         Self {
             local,
-            remote,
+            remote: Some(remote),
+            authentication: Arc::default(),
+            attempts: REQUEST_ATTEMPTS,
+            timeout: REQUEST_TIMEOUT,
+            limit: None,
+            stream_rate: None,
             _local_stream: PhantomData,
             _remote_stream: PhantomData,
         }
     }
 
-    pub async fn start(&mut self) {
-        while let Some((local, request)) = self.local.fetch().await {
-            if let Some(remote) = self.remote.fetch().await {
-                tokio::spawn(async move { Self::handle(local, remote, request).await });
-            }
+    pub fn with_authentication(mut self, authentication: Authentication) -> Self {
+        self.authentication = Arc::new(authentication);
+
+        self
+    }
+
+    /// Attempts of a request, at least one
+    pub fn with_attempts(mut self, value: usize) -> Self {
+        self.attempts = value.max(1);
+
+        self
+    }
+
+    /// Upper bound of a single attempt
+    pub fn with_timeout(mut self, value: Duration) -> Self {
+        self.timeout = value;
+
+        self
+    }
+
+    /// Bytes per second of all the streams together, in each direction
+    pub fn with_rate_limit(mut self, rate: u64) -> Self {
+        self.limit = Some(Limit::new(rate));
+
+        self
+    }
+
+    /// Bytes per second of every stream on its own, in each direction
+    pub fn with_stream_rate_limit(mut self, rate: u64) -> Self {
+        self.stream_rate = Some(rate);
+
+        self
+    }
+
+    /// Serves local streams until the shutdown drains, the streams in flight are
+    /// tracked by it
+    pub async fn start(&mut self, shutdown: Shutdown) {
+        let remote = match self.remote.take() {
+            Some(value) => streams(value),
+            None => return,
+        };
+
+        loop {
+            let (local, request) = tokio::select! {
+                fetched = self.local.fetch() => match fetched {
+                    Some(value) => value,
+                    None => break,
+                },
+                _ = shutdown.draining() => break,
+            };
+
+            let remote = remote.clone();
+            let authentication = self.authentication.clone();
+            let attempts = self.attempts;
+            let timeout = self.timeout;
+            let task = shutdown.track();
+
+            let limits = [
+                self.limit.clone(),
+                local.limit(),
+                self.stream_rate.map(Limit::new),
+            ];
+            let limits = limits.into_iter().flatten().collect();
+
+            tokio::spawn(async move {
+                let _task = task;
+
+                Self::handle(
+                    local,
+                    remote,
+                    request,
+                    authentication,
+                    attempts,
+                    timeout,
+                    limits,
+                )
+                .await
+            });
         }
     }
 
-    async fn handle(mut local: LS, mut remote: RS, request: Request) -> Result<()> {
+    async fn handle(
+        mut local: LS,
+        remote: mpsc::Sender<oneshot::Sender<RS>>,
+        request: Request,
+        authentication: Arc<Authentication>,
+        attempts: usize,
+        timeout: Duration,
+        limits: Vec<Limit>,
+    ) -> Result<()> {
+        use std::io::{Error, ErrorKind};
+
         use tokio::io::copy_bidirectional;
 
-        use crate::response::Response;
         use crate::Streamable;
 
-        Streamable::write(&request, &mut remote).await?;
+        let mut error = Error::new(ErrorKind::NotConnected, "no remote stream");
+
+        for attempt in 1..=attempts {
+            let deadline = Instant::now() + timeout;
+            let timed_out = || {
+                Error::new(
+                    ErrorKind::TimedOut,
+                    format!("attempt {} of {:?} timed out", attempt, request),
+                )
+            };
+
+            let sent = tokio::time::timeout_at(deadline, async {
+                let (sender, receiver) = oneshot::channel();
+
+                if remote.send(sender).await.is_err() {
+                    return Ok(None);
+                }
+
+                let mut stream = match receiver.await {
+                    Ok(value) => value,
+                    Err(_) => return Ok(None),
+                };
 
-        let response = <Response as Streamable>::read(&mut remote).await?;
+                Self::send(&mut stream, &request, &authentication).await?;
 
-        if let Response::Succeed = response {
-            copy_bidirectional(&mut local, &mut remote).await?;
+                Ok(Some(stream))
+            })
+            .await;
+
+            let mut stream = match sent {
+                Ok(Ok(Some(value))) => value,
+                Ok(Ok(None)) => break,
+                Ok(Err(value)) => {
+                    error = value;
+                    continue;
+                }
+                Err(_) => {
+                    error = timed_out();
+                    continue;
+                }
+            };
+
+            let response =
+                tokio::time::timeout_at(deadline, <Response as Streamable>::read(&mut stream))
+                    .await;
+
+            // Not sent again, the remote server may have acted on it
+            let response = match response {
+                Ok(Ok(value)) => value,
+                Ok(Err(value)) => {
+                    error = value;
+                    break;
+                }
+                Err(_) => {
+                    error = timed_out();
+                    break;
+                }
+            };
+
+            local.reply(&response).await?;
+
+            if !response.is_success() {
+                return Err(Error::new(
+                    ErrorKind::from(&response),
+                    format!("remote server replied {:?} to {:?}", response, request),
+                ));
+            }
+
+            let mut local = Shaped::new(local, limits);
+            copy_bidirectional(&mut local, &mut stream).await?;
+
+            return Ok(());
+        }
+
+        local.reply(&Response::from(&error)).await?;
+
+        Err(error)
+    }
+
+    async fn send(
+        remote: &mut RS,
+        request: &Request,
+        authentication: &Authentication,
+    ) -> Result<()> {
+        use crate::Streamable;
+
+        Streamable::write(authentication, remote).await?;
+        Streamable::write(request, remote).await
+    }
+}
+
+/// Hands the remote streams out to the requests one at a time, a stream whose
+/// request gave up waiting goes to the next one
+fn streams<R, RS>(mut remote: R) -> mpsc::Sender<oneshot::Sender<RS>>
+where
+    R: Provider<RS> + Send + 'static,
+    RS: Send + 'static,
+{
+    let (sender, mut receiver) = mpsc::channel::<oneshot::Sender<RS>>(1);
+
+    tokio::spawn(async move {
+        let mut spare = None;
+
+        while let Some(request) = receiver.recv().await {
+            let stream = match spare.take() {
+                Some(value) => value,
+                None => match remote.fetch().await {
+                    Some(value) => value,
+                    None => break,
+                },
+            };
+
+            if let Err(stream) = request.send(stream) {
+                spare = Some(stream);
+            }
+        }
+    });
+
+    sender
+}
+
+/// Round trip of a `Ping` on the remote stream, authentication included
+pub async fn ping<RS>(remote: &mut RS, authentication: &Authentication) -> Result<Duration>
+where
+    RS: AsyncReadExt + AsyncWriteExt + Unpin + Send,
+{
+    use std::io::{Error, ErrorKind};
+
+    use crate::Streamable;
+
+    let start = Instant::now();
+
+    Streamable::write(authentication, remote).await?;
+    Streamable::write(&Request::Ping, remote).await?;
+
+    let response = <Response as Streamable>::read(remote).await?;
+
+    if !response.is_success() {
+        return Err(Error::new(
+            ErrorKind::from(&response),
+            format!("remote server replied {:?} to ping", response),
+        ));
+    }
+
+    Ok(start.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll};
+
+    use tokio::io::{duplex, AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+
+    use super::*;
+    use crate::request::Address;
+    use crate::Streamable;
+
+    /// Local stream telling its replies
+    struct Local {
+        inner: DuplexStream,
+        replies: mpsc::UnboundedSender<Response>,
+    }
+
+    impl Reply for Local {
+        async fn reply(&mut self, response: &Response) -> Result<()> {
+            let _ = self.replies.send(response.clone());
+
+            Ok(())
+        }
+    }
+
+    impl Limited for Local {
+        fn limit(&self) -> Option<Limit> {
+            None
+        }
+    }
+
+    impl AsyncRead for Local {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Local {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize>> {
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Hands out queued items, counting the ones fetched
+    struct Queue<T> {
+        items: Vec<T>,
+        fetched: Arc<AtomicUsize>,
+    }
+
+    impl<T: Send> Provider<T> for Queue<T> {
+        async fn fetch(&mut self) -> Option<T> {
+            self.fetched.fetch_add(1, Ordering::Relaxed);
+
+            match self.items.pop() {
+                Some(value) => Some(value),
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    /// Replies of a request served by remote streams, with the streams fetched
+    async fn serve(remotes: Vec<DuplexStream>) -> (Vec<Response>, usize) {
+        let (local, _peer) = duplex(64);
+        let (replies, mut received) = mpsc::unbounded_channel();
+        let request = Request::TCPConnect(Address::Domain("example.com".to_string(), 443));
+
+        let fetched = Arc::new(AtomicUsize::new(0));
+        let remote = Queue {
+            items: remotes.into_iter().rev().collect(),
+            fetched: fetched.clone(),
+        };
+        let local = Queue {
+            items: vec![(
+                Local {
+                    inner: local,
+                    replies,
+                },
+                request,
+            )],
+            fetched: Arc::default(),
         };
 
-        Ok(())
+        let mut client = Client::with(local, remote).with_timeout(Duration::from_secs(1));
+        let _ = tokio::time::timeout(
+            Duration::from_millis(200),
+            client.start(Shutdown::default()),
+        )
+        .await;
+
+        received.close();
+
+        let mut responses = Vec::new();
+        while let Some(response) = received.recv().await {
+            responses.push(response);
+        }
+
+        (responses, fetched.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test]
+    async fn retried_until_sent() {
+        // Broken before the request is written
+        let (broken, peer) = duplex(64);
+        drop(peer);
+
+        let (working, mut peer) = duplex(64);
+
+        tokio::spawn(async move {
+            let _ = <Authentication as Streamable>::read(&mut peer).await?;
+            let _ = <Request as Streamable>::read(&mut peer).await?;
+
+            Streamable::write(&Response::Succeed, &mut peer).await?;
+            std::future::pending::<Result<()>>().await
+        });
+
+        let (responses, fetched) = serve(vec![broken, working]).await;
+
+        assert!(matches!(responses[..], [Response::Succeed]));
+        assert_eq!(fetched, 2);
+    }
+
+    #[tokio::test]
+    async fn not_retried_once_sent() {
+        // Closed once the request is read, without an answer
+        let (first, mut peer) = duplex(64);
+
+        tokio::spawn(async move {
+            let _ = <Authentication as Streamable>::read(&mut peer).await?;
+            <Request as Streamable>::read(&mut peer).await
+        });
+
+        let (second, _peer) = duplex(64);
+
+        let (responses, fetched) = serve(vec![first, second]).await;
+
+        assert_eq!(responses.len(), 1);
+        assert!(!responses[0].is_success());
+        assert_eq!(fetched, 1);
     }
 }
@@ -0,0 +1,61 @@
+use std::io::Result;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::io::AsyncReadExt;
+
+use crate::request::Address;
+use crate::{Streamable, ToBytes};
+
+/// ## Bytes
+/// ```text
+///          +------+----------+------+------+----------+
+///          | ATYP |   ADDR   | PORT | LEN  |   DATA   |
+///          +------+----------+------+------+----------+
+///          |  1   | Variable |  2   |  2   | Variable |
+///          +------+----------+------+------+----------+
+/// ```
+///
+/// The address is the destination when sent by the client, and the source
+/// when sent by the server.
+///
+#[derive(Debug, Clone)]
+pub struct Datagram {
+    pub address: Address,
+    pub data: Bytes,
+}
+
+impl Datagram {
+    pub fn new(address: Address, data: Bytes) -> Self {
+        Self { address, data }
+    }
+}
+
+impl ToBytes for Datagram {
+    fn to_bytes(&self) -> BytesMut {
+        let mut bytes = BytesMut::new();
+
+        bytes.extend(self.address.to_bytes());
+        bytes.put_u16(self.data.len() as u16);
+        bytes.extend_from_slice(&self.data);
+
+        bytes
+    }
+}
+
+impl Streamable for Datagram {
+    async fn read<T>(stream: &mut T) -> Result<Self>
+    where
+        T: AsyncReadExt + Unpin + Send,
+    {
+        let address = Address::read(stream).await?;
+
+        let length = stream.read_u16().await? as usize;
+        let mut data = vec![0u8; length];
+        stream.read_exact(&mut data).await?;
+
+        Ok(Self {
+            address,
+            data: data.into(),
+        })
+    }
+}
@@ -0,0 +1,54 @@
+use std::io::Result;
+
+use bytes::BytesMut;
+use tokio::io::AsyncReadExt;
+
+use crate::request::Address;
+use crate::{Streamable, ToBytes};
+
+/// ## Bytes
+/// ```text
+///          +------+----------+------+------+----------+------+
+///          | ATYP |   ADDR   | PORT | ATYP |   ADDR   | PORT |
+///          +------+----------+------+------+----------+------+
+///          |  1   | Variable |  2   |  1   | Variable |  2   |
+///          +------+----------+------+------+----------+------+
+/// ```
+///
+/// Sent by the server first on the stream it opens for a connection accepted
+/// on a `TCPBind` listener, the listening address then the peer address.
+///
+#[derive(Debug, Clone)]
+pub struct Incoming {
+    pub listener: Address,
+    pub peer: Address,
+}
+
+impl Incoming {
+    pub fn new(listener: Address, peer: Address) -> Self {
+        Self { listener, peer }
+    }
+}
+
+impl ToBytes for Incoming {
+    fn to_bytes(&self) -> BytesMut {
+        let mut bytes = BytesMut::new();
+
+        bytes.extend(self.listener.to_bytes());
+        bytes.extend(self.peer.to_bytes());
+
+        bytes
+    }
+}
+
+impl Streamable for Incoming {
+    async fn read<T>(stream: &mut T) -> Result<Self>
+    where
+        T: AsyncReadExt + Unpin + Send,
+    {
+        let listener = Address::read(stream).await?;
+        let peer = Address::read(stream).await?;
+
+        Ok(Self { listener, peer })
+    }
+}
@@ -5,10 +5,16 @@ use std::net::SocketAddr;
 use bytes::BytesMut;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+pub mod auth;
 pub mod client;
+pub mod datagram;
+pub mod incoming;
+pub mod limit;
+mod metered;
 pub mod request;
 pub mod response;
 pub mod server;
+pub mod shutdown;
 
 pub trait Streamable {
     fn write<T>(&self, stream: &mut T) -> impl Future<Output = Result<()>> + Send
@@ -29,10 +35,69 @@ pub trait ToBytes {
     fn to_bytes(&self) -> BytesMut;
 }
 
+/// Local side of a proxied stream, told about the remote outcome before any data is relayed
+pub trait Reply {
+    fn reply(&mut self, response: &response::Response) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Opens streams towards the peer, on the connection it was handed over with
+pub trait Open<T> {
+    fn open(&mut self) -> impl Future<Output = Result<T>> + Send;
+
+    /// Closes the connection with an application error code
+    fn close(&self, code: u32);
+}
+
 pub trait Provider<T> {
     fn fetch(&mut self) -> impl Future<Output = Option<T>> + Send;
 }
 
+pub trait Authenticator {
+    fn authenticate(
+        &self,
+        authentication: &auth::Authentication,
+    ) -> impl Future<Output = Option<auth::User>> + Send;
+}
+
+/// Decides whether a user may reach a destination, given the address it resolved to,
+/// or listen on an address of the server
+pub trait Authorizer {
+    fn authorize(
+        &self,
+        user: &auth::User,
+        destination: &request::Address,
+        address: SocketAddr,
+    ) -> bool;
+
+    fn authorize_bind(&self, user: &auth::User, address: SocketAddr) -> bool;
+}
+
+/// Accounts the traffic of the users and admits their requests within quotas,
+/// the meter of a stream comes with the rate limit of its user
+pub trait Accountant {
+    type Meter: Meter + Limited + Send + Sync + 'static;
+
+    /// Starts a stream of the user, `None` when the user is over quota.
+    /// The stream ends once its meter is dropped.
+    fn admit(&self, user: &auth::User) -> Option<Self::Meter>;
+}
+
+/// Comes with a rate limit of its own, e.g. of a listener, of a connection or of
+/// a user, applied on top of the ones of the client or the server
+pub trait Limited {
+    fn limit(&self) -> Option<limit::Limit>;
+}
+
+/// Counts the bytes of a stream, uploaded by the client and downloaded to it
+pub trait Meter {
+    fn count(&self, destination: &request::Address, upload: u64, download: u64);
+}
+
+/// Told about every request of a server with the response it got, e.g. to export metrics
+pub trait Observer {
+    fn observe(&self, request: &request::Request, response: &response::Response);
+}
+
 pub trait Resolver {
     fn lookup(&self, domain: &str, port: u16) -> impl Future<Output = Result<SocketAddr>> + Send;
 }
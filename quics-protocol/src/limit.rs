@@ -0,0 +1,266 @@
+use std::future::Future;
+use std::io::Result;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// Token bucket of a direction, holding up to a second worth of bytes
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+
+        self.tokens = (self.tokens + (now - self.updated).as_secs_f64() * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    /// Time until the bytes already taken are paid back
+    fn delay(&mut self) -> Duration {
+        self.refill();
+
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+
+    /// Takes the bytes relayed, going into debt when they were more than the tokens
+    fn take(&mut self, bytes: usize) {
+        self.refill();
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// Bytes per second uploaded by the client and downloaded to it, each direction
+/// on its own. Clones share the buckets, so that the streams of a listener or of
+/// a user are limited together.
+#[derive(Debug, Clone)]
+pub struct Limit {
+    rate: u64,
+    upload: Arc<Mutex<Bucket>>,
+    download: Arc<Mutex<Bucket>>,
+}
+
+impl Limit {
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+
+        Self {
+            rate,
+            upload: Arc::new(Mutex::new(Bucket::new(rate))),
+            download: Arc::new(Mutex::new(Bucket::new(rate))),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+}
+
+/// Stream of a client shaped by limits, what is read from it is uploaded and
+/// what is written to it downloaded. A direction waits while any of its buckets
+/// is in debt.
+pub struct Shaped<S> {
+    inner: S,
+    limits: Vec<Limit>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Shaped<S> {
+    pub fn new(inner: S, limits: Vec<Limit>) -> Self {
+        Self {
+            inner,
+            limits,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+/// Ready once none of the buckets of the direction is in debt
+fn poll_delay(
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    limits: &[Limit],
+    direction: fn(&Limit) -> &Mutex<Bucket>,
+    cx: &mut Context<'_>,
+) -> Poll<()> {
+    loop {
+        if let Some(sleep) = delay {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+
+        let wait = limits
+            .iter()
+            .map(|limit| direction(limit).lock().unwrap().delay())
+            .max()
+            .unwrap_or_default();
+
+        if wait.is_zero() {
+            return Poll::Ready(());
+        }
+
+        *delay = Some(Box::pin(tokio::time::sleep(wait)));
+    }
+}
+
+impl<S> AsyncRead for Shaped<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+
+        ready!(poll_delay(
+            &mut this.read_delay,
+            &this.limits,
+            |limit| &limit.upload,
+            cx
+        ));
+
+        let filled = buf.filled().len();
+
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        let size = buf.filled().len() - filled;
+
+        for limit in &this.limits {
+            limit.upload.lock().unwrap().take(size);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for Shaped<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+
+        ready!(poll_delay(
+            &mut this.write_delay,
+            &this.limits,
+            |limit| &limit.download,
+            cx
+        ));
+
+        let size = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+
+        for limit in &this.limits {
+            limit.download.lock().unwrap().take(size);
+        }
+
+        Poll::Ready(Ok(size))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{copy, duplex, sink, AsyncWriteExt};
+
+    use super::*;
+
+    /// Time taken to upload the bytes through the limits, read 100 bytes at a time
+    async fn upload(limits: Vec<Limit>, bytes: usize) -> Duration {
+        let (mut writer, reader) = duplex(100);
+
+        tokio::spawn(async move { writer.write_all(&vec![0; bytes]).await });
+
+        let start = Instant::now();
+        let mut shaped = Shaped::new(reader, limits);
+
+        assert_eq!(copy(&mut shaped, &mut sink()).await.unwrap(), bytes as u64);
+
+        start.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate() {
+        // A second worth of bytes goes at once, the others at the rate
+        let elapsed = upload(vec![Limit::new(1000)], 5000).await;
+        assert!(
+            elapsed.abs_diff(Duration::from_secs(4)) < Duration::from_millis(100),
+            "{:?}",
+            elapsed
+        );
+
+        let elapsed = upload(vec![Limit::new(1000)], 1000).await;
+        assert!(elapsed < Duration::from_millis(100), "{:?}", elapsed);
+
+        // The lowest of several limits wins
+        let elapsed = upload(vec![Limit::new(1000), Limit::new(500)], 2500).await;
+        assert!(
+            elapsed.abs_diff(Duration::from_secs(4)) < Duration::from_millis(100),
+            "{:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shared() {
+        let limit = Limit::new(1000);
+
+        // Clones share the rate, 5000 bytes together take as long as on their own
+        let (first, second) = tokio::join!(
+            upload(vec![limit.clone()], 2500),
+            upload(vec![limit.clone()], 2500)
+        );
+        let elapsed = first.max(second);
+        assert!(
+            elapsed.abs_diff(Duration::from_secs(4)) < Duration::from_millis(100),
+            "{:?}",
+            elapsed
+        );
+
+        // Other limits do not
+        let (first, second) = tokio::join!(
+            upload(vec![Limit::new(1000)], 2500),
+            upload(vec![Limit::new(1000)], 2500)
+        );
+        let elapsed = first.max(second);
+        assert!(
+            elapsed.abs_diff(Duration::from_millis(1500)) < Duration::from_millis(100),
+            "{:?}",
+            elapsed
+        );
+
+        // Nor does the other direction
+        let mut shaped = Shaped::new(sink(), vec![limit]);
+        let start = Instant::now();
+        shaped.write_all(&[0; 1000]).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,78 @@
+use std::io::Result;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::request::Address;
+use crate::Meter;
+
+/// Stream of a client counting what is read from it as uploaded to the
+/// destination and what is written to it as downloaded
+pub struct Metered<S, M> {
+    inner: S,
+    meter: Arc<M>,
+    destination: Address,
+}
+
+impl<S, M> Metered<S, M> {
+    pub fn new(inner: S, meter: Arc<M>, destination: Address) -> Self {
+        Self {
+            inner,
+            meter,
+            destination,
+        }
+    }
+}
+
+impl<S, M> AsyncRead for Metered<S, M>
+where
+    S: AsyncRead + Unpin,
+    M: Meter,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        let size = buf.filled().len() - filled;
+
+        if size > 0 {
+            this.meter.count(&this.destination, size as u64, 0);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S, M> AsyncWrite for Metered<S, M>
+where
+    S: AsyncWrite + Unpin,
+    M: Meter,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+
+        let size = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+
+        if size > 0 {
+            this.meter.count(&this.destination, 0, size as u64);
+        }
+
+        Poll::Ready(Ok(size))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
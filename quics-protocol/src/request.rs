@@ -1,5 +1,7 @@
+use std::fmt::{Display, Formatter};
 use std::io::{Error, ErrorKind, Result};
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::str::FromStr;
 
 use bytes::{BufMut, BytesMut};
 use tokio::io::AsyncReadExt;
@@ -9,6 +11,9 @@ use crate::{Resolver, Streamable, ToBytes};
 #[rustfmt::skip]
 mod consts {
     pub const REQUEST_TYPE_TCP_CONNECT:         u8 = 0x01;
+    pub const REQUEST_TYPE_UDP_ASSOCIATE:       u8 = 0x02;
+    pub const REQUEST_TYPE_TCP_BIND:            u8 = 0x03;
+    pub const REQUEST_TYPE_PING:                u8 = 0x04;
 
     pub const ADDRESS_TYPE_DOMAIN:              u8 = 0x01;
     pub const ADDRESS_TYPE_IPV4:                u8 = 0x02;
@@ -24,9 +29,22 @@ mod consts {
 ///          +------+------+----------+------+
 /// ```
 ///
+/// `UDPAssociate` only carries the `RTYP`, the stream is then used to carry
+/// [`Datagram`](crate::datagram::Datagram) frames in both directions.
+///
+/// `TCPBind` asks the server to listen on the address for as long as the stream
+/// stays open, every accepted connection comes back on a stream opened by the
+/// server, starting with an [`Incoming`](crate::incoming::Incoming) header.
+///
+/// `Ping` only carries the `RTYP`, the server replies `Succeed` right away,
+/// measuring the round trip through the tunnel.
+///
 #[derive(Debug, Clone)]
 pub enum Request {
     TCPConnect(Address),
+    UDPAssociate,
+    TCPBind(Address),
+    Ping,
 }
 
 impl ToBytes for Request {
@@ -38,6 +56,16 @@ impl ToBytes for Request {
                 bytes.put_u8(consts::REQUEST_TYPE_TCP_CONNECT);
                 bytes.extend(value.to_bytes());
             }
+            Self::UDPAssociate => {
+                bytes.put_u8(consts::REQUEST_TYPE_UDP_ASSOCIATE);
+            }
+            Self::TCPBind(value) => {
+                bytes.put_u8(consts::REQUEST_TYPE_TCP_BIND);
+                bytes.extend(value.to_bytes());
+            }
+            Self::Ping => {
+                bytes.put_u8(consts::REQUEST_TYPE_PING);
+            }
         };
 
         bytes
@@ -53,6 +81,9 @@ impl Streamable for Request {
 
         let request = match request_type {
             consts::REQUEST_TYPE_TCP_CONNECT => Request::TCPConnect(Address::read(stream).await?),
+            consts::REQUEST_TYPE_UDP_ASSOCIATE => Request::UDPAssociate,
+            consts::REQUEST_TYPE_TCP_BIND => Request::TCPBind(Address::read(stream).await?),
+            consts::REQUEST_TYPE_PING => Request::Ping,
 
             _ => {
                 return Err(Error::new(
@@ -66,7 +97,7 @@ impl Streamable for Request {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Address {
     Domain(String, u16),
     IPv4(SocketAddrV4),
@@ -79,7 +110,7 @@ impl Address {
         R: Resolver,
     {
         let socket_address = match self {
-            Self::Domain(domain, port) => resolver.lookup(&domain, port).await?.into(),
+            Self::Domain(domain, port) => resolver.lookup(&domain, port).await?,
             Self::IPv4(addr) => addr.into(),
             Self::IPv6(addr) => addr.into(),
         };
@@ -88,6 +119,55 @@ impl Address {
     }
 }
 
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+            Self::IPv4(addr) => addr.fmt(f),
+            Self::IPv6(addr) => addr.fmt(f),
+        }
+    }
+}
+
+/// Parses `host:port`, IPv6 addresses are enclosed in brackets e.g. `[::1]:443`
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        if let Ok(address) = value.parse::<SocketAddr>() {
+            return Ok(address.into());
+        }
+
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid address '{}', expected host:port", value),
+            )
+        };
+
+        let (domain, port) = value.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse::<u16>().map_err(|_| invalid())?;
+
+        if domain.is_empty() || domain.len() > 255 || domain.contains([':', '[', ']', '/']) {
+            return Err(invalid());
+        }
+
+        Ok(Self::Domain(domain.to_string(), port))
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(value: SocketAddr) -> Self {
+        match value {
+            SocketAddr::V4(addr) => Self::IPv4(addr),
+            SocketAddr::V6(addr) => match addr.ip().to_ipv4_mapped() {
+                Some(ip) => Self::IPv4(SocketAddrV4::new(ip, addr.port())),
+                None => Self::IPv6(addr),
+            },
+        }
+    }
+}
+
 impl Streamable for Address {
     async fn read<T>(stream: &mut T) -> Result<Self>
     where
@@ -183,3 +263,73 @@ impl ToBytes for Address {
         bytes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let domain = |domain: &str, port| Some(Address::Domain(domain.to_string(), port));
+        let ipv4 = |a, b, c, d, port| {
+            Some(Address::IPv4(SocketAddrV4::new(
+                Ipv4Addr::new(a, b, c, d),
+                port,
+            )))
+        };
+        let ipv6 = |ip: &str, port| {
+            Some(Address::IPv6(SocketAddrV6::new(
+                ip.parse().unwrap(),
+                port,
+                0,
+                0,
+            )))
+        };
+
+        #[rustfmt::skip]
+        let addresses = [
+            ("example.com:443", domain("example.com", 443)),
+            ("localhost:0", domain("localhost", 0)),
+            ("example.com.:65535", domain("example.com.", 65535)),
+            ("192.0.2.1:80", ipv4(192, 0, 2, 1, 80)),
+            ("[2001:db8::1]:443", ipv6("2001:db8::1", 443)),
+            ("[::]:53", ipv6("::", 53)),
+            // IPv4-mapped IPv6 addresses are IPv4 ones
+            ("[::ffff:192.0.2.1]:80", ipv4(192, 0, 2, 1, 80)),
+            // Missing and invalid ports
+            ("example.com", None),
+            ("example.com:", None),
+            ("example.com:http", None),
+            ("example.com:65536", None),
+            ("192.0.2.1", None),
+            // IPv6 addresses without or with unbalanced brackets
+            ("2001:db8::1:443", None),
+            ("[2001:db8::1]", None),
+            ("[2001:db8::1:443", None),
+            ("2001:db8::1]:443", None),
+            ("[example.com]:443", None),
+            // Empty, too long or with a path
+            (":443", None),
+            ("", None),
+            ("example.com/path:443", None),
+        ];
+
+        for (value, expected) in addresses {
+            assert_eq!(value.parse::<Address>().ok(), expected, "{}", value);
+        }
+
+        let long = format!("{}:443", "a".repeat(256));
+        assert!(long.parse::<Address>().is_err());
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        for value in ["example.com:443", "192.0.2.1:80", "[2001:db8::1]:443"] {
+            let address = value.parse::<Address>().unwrap();
+            assert_eq!(address.to_string(), value);
+
+            let bytes = address.to_bytes();
+            assert_eq!(Address::read(&mut &bytes[..]).await.unwrap(), address);
+        }
+    }
+}
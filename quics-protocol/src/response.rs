@@ -1,18 +1,53 @@
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 
 use bytes::{BufMut, BytesMut};
 use tokio::io::AsyncReadExt;
 
+use crate::request::Address;
 use crate::{Streamable, ToBytes};
 
 #[rustfmt::skip]
 mod consts {
-    pub const SUCCEED: u8 = 0x01;
-    pub const NO_ACCEPTABLE_REQUEST: u8 = 0xFF;
+    pub const SUCCEED:                  u8 = 0x01;
+    pub const AUTHENTICATION_FAILED:    u8 = 0x02;
+    pub const GENERAL_FAILURE:          u8 = 0x03;
+    pub const NOT_ALLOWED:              u8 = 0x04;
+    pub const NETWORK_UNREACHABLE:      u8 = 0x05;
+    pub const HOST_UNREACHABLE:         u8 = 0x06;
+    pub const CONNECTION_REFUSED:       u8 = 0x07;
+    pub const TIMED_OUT:                u8 = 0x08;
+    pub const HOST_UNRESOLVED:          u8 = 0x09;
+    pub const CONNECTED:                u8 = 0x0A;
+    pub const QUOTA_EXCEEDED:           u8 = 0x0B;
+    pub const NO_ACCEPTABLE_REQUEST:    u8 = 0xFF;
 }
 
+/// ## Bytes
+/// ```text
+///          +------+------+----------+------+
+///          | CODE | ATYP |   ADDR   | PORT |
+///          +------+------+----------+------+
+///          |  1   |  1   | Variable |  2   |
+///          +------+------+----------+------+
+/// ```
+///
+/// Only `Connected` carries an address, the one the server bound for the outbound
+/// connection, or the listening address of a `TCPBind`.
+///
+#[derive(Debug, Clone)]
 pub enum Response {
     Succeed,
+    Connected(Address),
+    AuthenticationFailed,
+    GeneralFailure,
+    NotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TimedOut,
+    HostUnresolved,
+    /// The user is over a traffic quota or runs too many streams
+    QuotaExceeded,
     NoAcceptableMethod,
 }
 
@@ -24,6 +59,19 @@ impl ToBytes for Response {
             Self::Succeed => {
                 bytes.put_u8(consts::SUCCEED);
             }
+            Self::Connected(address) => {
+                bytes.put_u8(consts::CONNECTED);
+                bytes.extend(address.to_bytes());
+            }
+            Self::AuthenticationFailed => bytes.put_u8(consts::AUTHENTICATION_FAILED),
+            Self::GeneralFailure => bytes.put_u8(consts::GENERAL_FAILURE),
+            Self::NotAllowed => bytes.put_u8(consts::NOT_ALLOWED),
+            Self::NetworkUnreachable => bytes.put_u8(consts::NETWORK_UNREACHABLE),
+            Self::HostUnreachable => bytes.put_u8(consts::HOST_UNREACHABLE),
+            Self::ConnectionRefused => bytes.put_u8(consts::CONNECTION_REFUSED),
+            Self::TimedOut => bytes.put_u8(consts::TIMED_OUT),
+            Self::HostUnresolved => bytes.put_u8(consts::HOST_UNRESOLVED),
+            Self::QuotaExceeded => bytes.put_u8(consts::QUOTA_EXCEEDED),
             Self::NoAcceptableMethod => bytes.put_u8(consts::NO_ACCEPTABLE_REQUEST),
         };
 
@@ -31,6 +79,12 @@ impl ToBytes for Response {
     }
 }
 
+impl Response {
+    pub fn is_success(&self) -> bool {
+        matches!(self, Self::Succeed | Self::Connected(_))
+    }
+}
+
 impl Streamable for Response {
     async fn read<T>(stream: &mut T) -> Result<Self>
     where
@@ -38,9 +92,51 @@ impl Streamable for Response {
     {
         let response = match stream.read_u8().await? {
             consts::SUCCEED => Self::Succeed,
+            consts::CONNECTED => Self::Connected(Address::read(stream).await?),
+            consts::AUTHENTICATION_FAILED => Self::AuthenticationFailed,
+            consts::GENERAL_FAILURE => Self::GeneralFailure,
+            consts::NOT_ALLOWED => Self::NotAllowed,
+            consts::NETWORK_UNREACHABLE => Self::NetworkUnreachable,
+            consts::HOST_UNREACHABLE => Self::HostUnreachable,
+            consts::CONNECTION_REFUSED => Self::ConnectionRefused,
+            consts::TIMED_OUT => Self::TimedOut,
+            consts::HOST_UNRESOLVED => Self::HostUnresolved,
+            consts::QUOTA_EXCEEDED => Self::QuotaExceeded,
             _ => Self::NoAcceptableMethod,
         };
 
         Ok(response)
     }
 }
+
+/// Failure reported by the server for an outbound error
+impl From<&Error> for Response {
+    fn from(value: &Error) -> Self {
+        match value.kind() {
+            ErrorKind::PermissionDenied => Self::NotAllowed,
+            ErrorKind::NetworkUnreachable => Self::NetworkUnreachable,
+            ErrorKind::HostUnreachable => Self::HostUnreachable,
+            ErrorKind::ConnectionRefused => Self::ConnectionRefused,
+            ErrorKind::TimedOut => Self::TimedOut,
+            _ => Self::GeneralFailure,
+        }
+    }
+}
+
+/// Error surfaced by the client for a failure response
+impl From<&Response> for ErrorKind {
+    fn from(value: &Response) -> Self {
+        match value {
+            Response::Succeed | Response::Connected(_) => ErrorKind::Other,
+            Response::AuthenticationFailed | Response::NotAllowed | Response::QuotaExceeded => {
+                ErrorKind::PermissionDenied
+            }
+            Response::NetworkUnreachable => ErrorKind::NetworkUnreachable,
+            Response::HostUnreachable => ErrorKind::HostUnreachable,
+            Response::ConnectionRefused => ErrorKind::ConnectionRefused,
+            Response::TimedOut => ErrorKind::TimedOut,
+            Response::HostUnresolved => ErrorKind::NotFound,
+            Response::GeneralFailure | Response::NoAcceptableMethod => ErrorKind::Other,
+        }
+    }
+}
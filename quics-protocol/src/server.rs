@@ -1,62 +1,792 @@
 use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt, Result};
 
-use crate::{Provider, Resolver};
+use crate::auth::User;
+use crate::limit::{Limit, Shaped};
+use crate::metered::Metered;
+use crate::request::Request;
+use crate::response::Response;
+use crate::shutdown::Shutdown;
+use crate::{Accountant, Authenticator, Authorizer, Limited, Observer, Open, Provider, Resolver};
 
-pub struct Server<R, RE, RS>
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Per request settings of a server
+#[derive(Clone, Copy)]
+struct Settings {
+    allow_bind: bool,
+    connect_timeout: Duration,
+    outbound: Option<IpAddr>,
+    stream_rate: Option<u64>,
+}
+
+/// Shared by the streams of a server, cloned into each of them
+#[derive(Clone)]
+struct Services<RE, A, Z, C> {
+    resolver: RE,
+    authenticator: A,
+    authorizer: Z,
+    accountant: C,
+    limit: Option<Limit>,
+    observer: Option<Arc<dyn Observer + Send + Sync>>,
+}
+
+/// Accepted streams come with an opener of streams on their connection,
+/// used to carry back the connections accepted for a `TCPBind` and to close
+/// the connection of a client failing to authenticate.
+///
+/// Destinations of `TCPConnect` and of UDP datagrams are authorized once
+/// resolved, so that a domain cannot stand for a forbidden address, and so are
+/// the addresses of `TCPBind`. Every request but `Ping` is admitted by the
+/// accountant, which counts its bytes.
+///
+/// The relay of a stream is shaped by the limit of the server, shared by all
+/// the streams, the one of its connection, the one of its user and its own.
+pub struct Server<R, RE, A, Z, C, RS, O>
 where
-    R: Provider<RS>,
+    R: Provider<(RS, O)>,
     RE: Resolver + Clone + Send + 'static,
+    A: Authenticator + Clone + Send + 'static,
+    Z: Authorizer + Clone + Send + 'static,
+    C: Accountant + Clone + Send + 'static,
     RS: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
+    O: Open<RS> + Limited + Clone + Send + 'static,
 {
     accept: R,
-    resolver: RE,
-    _accept_stream: PhantomData<RS>,
+    services: Services<RE, A, Z, C>,
+    settings: Settings,
+    _accept_stream: PhantomData<(RS, O)>,
 }
 
-impl<R, RS, RE> Server<R, RE, RS>
+impl<R, RS, RE, A, Z, C, O> Server<R, RE, A, Z, C, RS, O>
 where
-    R: Provider<RS>,
+    R: Provider<(RS, O)>,
     RE: Resolver + Clone + Send + Sync + 'static,
+    A: Authenticator + Clone + Send + Sync + 'static,
+    Z: Authorizer + Clone + Send + Sync + 'static,
+    C: Accountant + Clone + Send + Sync + 'static,
     RS: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
+    O: Open<RS> + Limited + Clone + Send + 'static,
 {
-    pub fn with(accept: R, resolver: RE) -> Self {
+    pub fn with(accept: R, resolver: RE, authenticator: A, authorizer: Z, accountant: C) -> Self {
         Self {
             accept,
-            resolver,
+            services: Services {
+                resolver,
+                authenticator,
+                authorizer,
+                accountant,
+                limit: None,
+                observer: None,
+            },
+            settings: Settings {
+                allow_bind: false,
+                connect_timeout: CONNECT_TIMEOUT,
+                outbound: None,
+                stream_rate: None,
+            },
             _accept_stream: PhantomData,
         }
     }
 
-    pub async fn start(&mut self) {
-        while let Some(stream) = self.accept.fetch().await {
-            let resolver = self.resolver.clone();
-            tokio::spawn(async move { Self::handle(stream, resolver).await });
+    /// Lets clients listen on server ports with `TCPBind`, disallowed by default
+    pub fn with_bind(mut self, value: bool) -> Self {
+        self.settings.allow_bind = value;
+
+        self
+    }
+
+    /// Upper bound of a connection to a destination
+    pub fn with_connect_timeout(mut self, value: Duration) -> Self {
+        self.settings.connect_timeout = value;
+
+        self
+    }
+
+    /// Local address of the connections and UDP sockets towards destinations,
+    /// destinations of the other IP version are reached from any address
+    pub fn with_outbound(mut self, value: Option<IpAddr>) -> Self {
+        self.settings.outbound = value;
+
+        self
+    }
+
+    /// Bytes per second of all the streams together, in each direction
+    pub fn with_rate_limit(mut self, rate: u64) -> Self {
+        self.services.limit = Some(Limit::new(rate));
+
+        self
+    }
+
+    /// Bytes per second of every stream on its own, in each direction
+    pub fn with_stream_rate_limit(mut self, rate: u64) -> Self {
+        self.settings.stream_rate = Some(rate);
+
+        self
+    }
+
+    /// Observes the requests served and their responses
+    pub fn with_observer<V>(mut self, observer: V) -> Self
+    where
+        V: Observer + Send + Sync + 'static,
+    {
+        self.services.observer = Some(Arc::new(observer));
+
+        self
+    }
+
+    /// Serves accepted streams until the shutdown drains, the streams in flight
+    /// are tracked by it and listeners of `TCPBind` stop accepting
+    pub async fn start(&mut self, shutdown: Shutdown) {
+        loop {
+            let (stream, opener) = tokio::select! {
+                accepted = self.accept.fetch() => match accepted {
+                    Some(value) => value,
+                    None => break,
+                },
+                _ = shutdown.draining() => break,
+            };
+
+            let services = self.services.clone();
+            let settings = self.settings;
+            let shutdown = shutdown.clone();
+            let task = shutdown.track();
+
+            tokio::spawn(async move {
+                let _task = task;
+
+                Self::handle(stream, opener, services, settings, shutdown).await
+            });
         }
     }
 
-    async fn handle(mut stream: RS, resolver: RE) -> Result<()> {
-        use crate::request::Request;
-        use crate::response::Response;
+    async fn handle(
+        mut stream: RS,
+        opener: O,
+        services: Services<RE, A, Z, C>,
+        settings: Settings,
+        shutdown: Shutdown,
+    ) -> Result<()> {
+        use std::io::{Error, ErrorKind};
+
+        use crate::auth::{Authentication, AUTHENTICATION_ERROR_CODE};
         use crate::Streamable;
 
+        let Services {
+            resolver,
+            authenticator,
+            authorizer,
+            accountant,
+            limit,
+            observer,
+        } = services;
+
+        let authentication = <Authentication as Streamable>::read(&mut stream).await?;
+
+        let user = match authenticator.authenticate(&authentication).await {
+            Some(value) => value,
+            // The whole connection goes, not only this stream
+            None => {
+                let _ = Streamable::write(&Response::AuthenticationFailed, &mut stream).await;
+                opener.close(AUTHENTICATION_ERROR_CODE);
+
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "authentication failed",
+                ));
+            }
+        };
+
         let request = <Request as Streamable>::read(&mut stream).await?;
+        let observed = Observed {
+            observer: &observer,
+            request: request.clone(),
+        };
 
         match request {
             Request::TCPConnect(address) => {
                 use tokio::io::copy_bidirectional;
-                use tokio::net::TcpStream;
+                use tokio::time::timeout;
+
+                let meter = Self::admit(&mut stream, &accountant, &user, &observed).await?;
+                let limits = Self::limits(&limit, &opener, &*meter, settings);
+
+                let destination = address.clone();
+
+                let address = match address.to_socket_address(&resolver).await {
+                    Ok(value) => value,
+                    Err(error) => {
+                        reply(&mut stream, &Response::HostUnresolved, &observed).await?;
+                        return Err(error);
+                    }
+                };
+
+                if !authorizer.authorize(&user, &destination, address) {
+                    reply(&mut stream, &Response::NotAllowed, &observed).await?;
+                    return Err(Error::new(
+                        ErrorKind::PermissionDenied,
+                        format!("{} ({}) not allowed", destination, address),
+                    ));
+                }
 
-                let address = address.to_socket_address(&resolver).await?;
-                let mut connect = TcpStream::connect(address).await?;
+                let connect = timeout(
+                    settings.connect_timeout,
+                    connect(address, settings.outbound),
+                );
 
-                Streamable::write(&Response::Succeed, &mut stream).await?;
+                let connect = match connect.await {
+                    Ok(value) => value,
+                    Err(_) => Err(Error::new(ErrorKind::TimedOut, "connect timed out")),
+                };
 
-                copy_bidirectional(&mut stream, &mut connect).await?
+                let mut connect = match connect {
+                    Ok(value) => value,
+                    Err(error) => {
+                        reply(&mut stream, &Response::from(&error), &observed).await?;
+                        return Err(error);
+                    }
+                };
+
+                let response = Response::Connected(connect.local_addr()?.into());
+                reply(&mut stream, &response, &observed).await?;
+
+                let mut stream = Shaped::new(Metered::new(stream, meter, destination), limits);
+                copy_bidirectional(&mut stream, &mut connect).await?;
+            }
+
+            Request::UDPAssociate => {
+                let meter = Self::admit(&mut stream, &accountant, &user, &observed).await?;
+                let limits = Self::limits(&limit, &opener, &*meter, settings);
+
+                let socket = match udp::bind(settings.outbound).await {
+                    Ok(value) => value,
+                    Err(error) => {
+                        reply(&mut stream, &Response::from(&error), &observed).await?;
+                        return Err(error);
+                    }
+                };
+
+                reply(&mut stream, &Response::Succeed, &observed).await?;
+
+                let stream = Shaped::new(stream, limits);
+                udp::relay(stream, socket, resolver, authorizer, user, meter).await?;
+            }
+
+            Request::TCPBind(address) => {
+                use tokio::net::TcpListener;
+
+                let meter = Self::admit(&mut stream, &accountant, &user, &observed).await?;
+                let limits = Self::limits(&limit, &opener, &*meter, settings);
+
+                if !settings.allow_bind {
+                    reply(&mut stream, &Response::NotAllowed, &observed).await?;
+                    return Err(Error::new(ErrorKind::PermissionDenied, "bind not allowed"));
+                }
+
+                let address = match address.to_socket_address(&resolver).await {
+                    Ok(value) => value,
+                    Err(error) => {
+                        reply(&mut stream, &Response::HostUnresolved, &observed).await?;
+                        return Err(error);
+                    }
+                };
+
+                if !authorizer.authorize_bind(&user, address) {
+                    reply(&mut stream, &Response::NotAllowed, &observed).await?;
+                    return Err(Error::new(
+                        ErrorKind::PermissionDenied,
+                        format!("bind on {} not allowed", address),
+                    ));
+                }
+
+                let listener = match TcpListener::bind(address).await {
+                    Ok(value) => value,
+                    Err(error) => {
+                        reply(&mut stream, &Response::from(&error), &observed).await?;
+                        return Err(error);
+                    }
+                };
+
+                let response = Response::Connected(listener.local_addr()?.into());
+                reply(&mut stream, &response, &observed).await?;
+
+                bind::serve(stream, listener, opener, meter, limits, shutdown).await?;
+            }
+
+            // Not traffic of the user, answered over quota too
+            Request::Ping => {
+                reply(&mut stream, &Response::Succeed, &observed).await?;
             }
         };
 
         Ok(())
     }
+
+    /// Limits of a stream, the per stream one is new to it
+    fn limits(
+        limit: &Option<Limit>,
+        opener: &O,
+        meter: &C::Meter,
+        settings: Settings,
+    ) -> Vec<Limit> {
+        [
+            limit.clone(),
+            opener.limit(),
+            meter.limit(),
+            settings.stream_rate.map(Limit::new),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Starts a stream of the user, refused with `QuotaExceeded` over quota
+    async fn admit(
+        stream: &mut RS,
+        accountant: &C,
+        user: &User,
+        observed: &Observed<'_>,
+    ) -> Result<Arc<C::Meter>> {
+        use std::io::{Error, ErrorKind};
+
+        match accountant.admit(user) {
+            Some(meter) => Ok(Arc::new(meter)),
+            None => {
+                reply(stream, &Response::QuotaExceeded, observed).await?;
+
+                Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("user '{}' over quota", user.name()),
+                ))
+            }
+        }
+    }
+}
+
+/// Request of a stream, told to the observer of the server with its response
+struct Observed<'a> {
+    observer: &'a Option<Arc<dyn Observer + Send + Sync>>,
+    request: Request,
+}
+
+/// Writes the response to the request of the stream, once observed
+async fn reply<S>(stream: &mut S, response: &Response, observed: &Observed<'_>) -> Result<()>
+where
+    S: AsyncWriteExt + Unpin + Send,
+{
+    use crate::Streamable;
+
+    if let Some(observer) = observed.observer {
+        observer.observe(&observed.request, response);
+    }
+
+    Streamable::write(response, stream).await
+}
+
+/// Connects from the outbound address when it is of the same IP version
+async fn connect(
+    address: std::net::SocketAddr,
+    outbound: Option<IpAddr>,
+) -> Result<tokio::net::TcpStream> {
+    use std::net::SocketAddr;
+
+    use tokio::net::{TcpSocket, TcpStream};
+
+    let outbound = match outbound {
+        Some(value) if value.is_ipv4() == address.is_ipv4() => value,
+        _ => return TcpStream::connect(address).await,
+    };
+
+    let socket = match outbound {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    socket.bind(SocketAddr::new(outbound, 0))?;
+    socket.connect(address).await
+}
+
+mod bind {
+    use std::sync::Arc;
+
+    use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt, Result};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::incoming::Incoming;
+    use crate::limit::{Limit, Shaped};
+    use crate::metered::Metered;
+    use crate::request::Address;
+    use crate::shutdown::Shutdown;
+    use crate::{Meter, Open, Streamable};
+
+    /// Accepts on the listener until the client closes the stream of the `TCPBind`
+    /// or the server drains, the accepted connections are counted by the meter
+    /// of the `TCPBind`, by peer, and shaped by its limits
+    pub async fn serve<S, O, M>(
+        mut stream: S,
+        listener: TcpListener,
+        opener: O,
+        meter: Arc<M>,
+        limits: Vec<Limit>,
+        shutdown: Shutdown,
+    ) -> Result<()>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
+        O: Open<S> + Clone + Send + 'static,
+        M: Meter + Send + Sync + 'static,
+    {
+        let address: Address = listener.local_addr()?.into();
+        let mut buffer = [0u8; 64];
+
+        loop {
+            tokio::select! {
+                read = stream.read(&mut buffer) => {
+                    if read? == 0 {
+                        return Ok(());
+                    }
+                }
+
+                _ = shutdown.draining() => return Ok(()),
+
+                accept = listener.accept() => {
+                    let (connection, peer) = accept?;
+                    let incoming = Incoming::new(address.clone(), peer.into());
+
+                    tokio::spawn(forward(
+                        connection,
+                        incoming,
+                        opener.clone(),
+                        meter.clone(),
+                        peer.into(),
+                        limits.clone(),
+                    ));
+                }
+            }
+        }
+    }
+
+    async fn forward<S, O, M>(
+        mut connection: TcpStream,
+        incoming: Incoming,
+        mut opener: O,
+        meter: Arc<M>,
+        peer: Address,
+        limits: Vec<Limit>,
+    ) -> Result<()>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin + Send,
+        O: Open<S>,
+        M: Meter,
+    {
+        let mut stream = opener.open().await?;
+
+        Streamable::write(&incoming, &mut stream).await?;
+
+        let mut stream = Shaped::new(Metered::new(stream, meter, peer), limits);
+        copy_bidirectional(&mut stream, &mut connection).await?;
+
+        Ok(())
+    }
+}
+
+mod udp {
+    use std::collections::HashMap;
+    use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, Result, WriteHalf};
+    use tokio::net::UdpSocket;
+    use tokio::time::Instant;
+
+    use crate::auth::User;
+    use crate::datagram::Datagram;
+    use crate::{Authorizer, Meter, Resolver, Streamable};
+
+    /// Time after the last datagram sent to an address during which its datagrams are taken
+    const PEER_TIMEOUT: Duration = Duration::from_secs(300);
+
+    /// Upper bound of the addresses remembered by an association
+    const MAX_PEERS: usize = 4096;
+
+    /// Addresses the client sent to, with when it last did. Once full, expired
+    /// addresses are forgotten first, then the least recently sent to.
+    #[derive(Default)]
+    pub struct Peers(HashMap<SocketAddr, Instant>);
+
+    impl Peers {
+        pub fn insert(&mut self, address: SocketAddr) {
+            let now = Instant::now();
+
+            if self.0.len() >= MAX_PEERS && !self.0.contains_key(&address) {
+                self.0
+                    .retain(|_, sent| now.duration_since(*sent) < PEER_TIMEOUT);
+            }
+
+            if self.0.len() >= MAX_PEERS && !self.0.contains_key(&address) {
+                let oldest = self.0.iter().min_by_key(|(_, sent)| **sent);
+
+                if let Some((&oldest, _)) = oldest {
+                    self.0.remove(&oldest);
+                }
+            }
+
+            self.0.insert(address, now);
+        }
+
+        pub fn contains(&self, address: &SocketAddr) -> bool {
+            self.0
+                .get(address)
+                .is_some_and(|sent| sent.elapsed() < PEER_TIMEOUT)
+        }
+
+        #[cfg(test)]
+        pub fn len(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    /// Prefer a dual stack socket, IPv4 destinations are then sent as IPv4-mapped addresses.
+    /// An outbound address restricts the association to its IP version.
+    pub async fn bind(outbound: Option<IpAddr>) -> Result<UdpSocket> {
+        if let Some(address) = outbound {
+            return UdpSocket::bind(SocketAddr::new(address, 0)).await;
+        }
+
+        match UdpSocket::bind("[::]:0").await {
+            Ok(socket) => Ok(socket),
+            Err(_) => UdpSocket::bind("0.0.0.0:0").await,
+        }
+    }
+
+    fn to_mapped(socket: &UdpSocket, address: SocketAddr) -> Result<SocketAddr> {
+        match (socket.local_addr()?, address) {
+            (SocketAddr::V6(local), SocketAddr::V4(addr)) if local.ip().is_unspecified() => {
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    addr.ip().to_ipv6_mapped(),
+                    addr.port(),
+                    0,
+                    0,
+                )))
+            }
+            _ => Ok(address),
+        }
+    }
+
+    async fn handle_udp_request<S, R, Z, M>(
+        mut stream: ReadHalf<S>,
+        socket: &UdpSocket,
+        peers: &Mutex<Peers>,
+        resolver: R,
+        authorizer: Z,
+        user: User,
+        meter: &M,
+    ) -> Result<()>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin + Send,
+        R: Resolver,
+        Z: Authorizer,
+        M: Meter,
+    {
+        loop {
+            let datagram = <Datagram as Streamable>::read(&mut stream).await?;
+
+            // A single unresolvable or forbidden destination must not tear down the association
+            let address = match datagram.address.clone().to_socket_address(&resolver).await {
+                Ok(value) if authorizer.authorize(&user, &datagram.address, value) => {
+                    to_mapped(socket, value)?
+                }
+                _ => continue,
+            };
+
+            // Nor a destination out of reach of the outbound address
+            if address.is_ipv4() != socket.local_addr()?.is_ipv4() {
+                continue;
+            }
+
+            peers.lock().unwrap().insert(address);
+
+            let size = socket.send_to(&datagram.data, address).await?;
+
+            meter.count(&datagram.address, size as u64, 0);
+        }
+    }
+
+    async fn handle_udp_response<S, M>(
+        mut stream: WriteHalf<S>,
+        socket: &UdpSocket,
+        peers: &Mutex<Peers>,
+        meter: &M,
+    ) -> Result<()>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin + Send,
+        M: Meter,
+    {
+        let mut buffer = vec![0u8; 65535];
+
+        loop {
+            let (size, remote_addr) = socket.recv_from(&mut buffer).await?;
+
+            if !peers.lock().unwrap().contains(&remote_addr) {
+                continue;
+            }
+
+            let datagram =
+                Datagram::new(remote_addr.into(), Bytes::copy_from_slice(&buffer[..size]));
+
+            Streamable::write(&datagram, &mut stream).await?;
+
+            meter.count(&datagram.address, 0, size as u64);
+        }
+    }
+
+    /// Relays datagrams until either side fails, their payloads are counted
+    /// by destination. Like a port-restricted cone NAT, datagrams are only taken
+    /// from the addresses the client recently sent to, see [`Peers`].
+    pub async fn relay<S, R, Z, M>(
+        stream: S,
+        socket: UdpSocket,
+        resolver: R,
+        authorizer: Z,
+        user: User,
+        meter: Arc<M>,
+    ) -> Result<()>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin + Send,
+        R: Resolver,
+        Z: Authorizer,
+        M: Meter,
+    {
+        use tokio::io::split;
+        use tokio::try_join;
+
+        let (reader, writer) = split(stream);
+        let peers = Mutex::new(Peers::default());
+
+        try_join!(
+            handle_udp_request(reader, &socket, &peers, resolver, authorizer, user, &*meter),
+            handle_udp_response(writer, &socket, &peers, &*meter)
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::io::{duplex, Result};
+    use tokio::net::UdpSocket;
+
+    use crate::auth::User;
+    use crate::datagram::Datagram;
+    use crate::request::Address;
+    use crate::{Authorizer, Meter, Resolver, Streamable};
+
+    #[derive(Clone)]
+    struct Open;
+
+    impl Authorizer for Open {
+        fn authorize(&self, _user: &User, _destination: &Address, _address: SocketAddr) -> bool {
+            true
+        }
+
+        fn authorize_bind(&self, _user: &User, _address: SocketAddr) -> bool {
+            true
+        }
+    }
+
+    impl Resolver for Open {
+        async fn lookup(&self, _domain: &str, _port: u16) -> Result<SocketAddr> {
+            Err(std::io::Error::other("no lookup"))
+        }
+    }
+
+    impl Meter for Open {
+        fn count(&self, _destination: &Address, _upload: u64, _download: u64) {}
+    }
+
+    #[tokio::test]
+    async fn udp_from_destinations_only() -> Result<()> {
+        let socket = super::udp::bind(Some("127.0.0.1".parse().unwrap())).await?;
+        let relay = socket.local_addr()?;
+
+        let (mut client, stream) = duplex(4096);
+        tokio::spawn(super::udp::relay(
+            stream,
+            socket,
+            Open,
+            Open,
+            User::default(),
+            Arc::new(Open),
+        ));
+
+        let destination = UdpSocket::bind("127.0.0.1:0").await?;
+        let stranger = UdpSocket::bind("127.0.0.1:0").await?;
+
+        // Dropped, the client never sent to it
+        stranger.send_to(b"stranger", relay).await?;
+
+        let datagram = Datagram::new(destination.local_addr()?.into(), Bytes::from("ping"));
+        Streamable::write(&datagram, &mut client).await?;
+
+        let mut buffer = [0u8; 64];
+        let (size, from) = destination.recv_from(&mut buffer).await?;
+        assert_eq!(&buffer[..size], b"ping");
+
+        stranger.send_to(b"stranger", relay).await?;
+        destination.send_to(b"pong", from).await?;
+
+        let datagram = <Datagram as Streamable>::read(&mut client).await?;
+        assert_eq!(datagram.address, destination.local_addr()?.into());
+        assert_eq!(&datagram.data[..], b"pong");
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn udp_peers_expire_and_are_capped() {
+        use super::udp::Peers;
+
+        let address = |index: usize| SocketAddr::from(([192, 0, 2, 1], index as u16));
+        let mut peers = Peers::default();
+
+        peers.insert(address(0));
+        tokio::time::advance(Duration::from_secs(299)).await;
+        assert!(peers.contains(&address(0)));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(!peers.contains(&address(0)));
+
+        // Sending again takes the datagrams of the address again
+        peers.insert(address(0));
+        assert!(peers.contains(&address(0)));
+
+        // Once full, the least recently sent to address is forgotten
+        for index in 1..4096 {
+            tokio::time::advance(Duration::from_millis(1)).await;
+            peers.insert(address(index));
+        }
+        assert_eq!(peers.len(), 4096);
+
+        peers.insert(address(4096));
+        assert_eq!(peers.len(), 4096);
+        assert!(!peers.contains(&address(0)));
+        assert!(peers.contains(&address(1)));
+        assert!(peers.contains(&address(4096)));
+
+        // Expired addresses are all forgotten first
+        tokio::time::advance(Duration::from_secs(300)).await;
+        peers.insert(address(4097));
+        assert_eq!(peers.len(), 1);
+    }
 }
@@ -0,0 +1,161 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// Application error code of the CONNECTION_CLOSE sent once a peer shut down
+pub const SHUTDOWN_ERROR_CODE: u32 = 0x01;
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum State {
+    Running,
+    Draining,
+    Closing,
+}
+
+/// Shutdown handle shared by the tasks of a client or a server
+///
+/// A shutdown first drains: no new stream is taken while the streams in flight,
+/// tracked with [`Shutdown::track`], may finish. Connections are then closed.
+#[derive(Clone)]
+pub struct Shutdown {
+    state: Arc<watch::Sender<State>>,
+    tasks: Arc<watch::Sender<usize>>,
+}
+
+/// Keeps the shutdown draining until dropped
+pub struct Task {
+    tasks: Arc<watch::Sender<usize>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(watch::Sender::new(State::Running)),
+            tasks: Arc::new(watch::Sender::new(0)),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Stops taking new streams
+    pub fn drain(&self) {
+        self.advance(State::Draining);
+    }
+
+    /// Closes the connections, whether the streams in flight finished or not
+    pub fn close(&self) {
+        self.advance(State::Closing);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.state.borrow() >= State::Draining
+    }
+
+    /// Completes once draining started
+    pub async fn draining(&self) {
+        self.reached(State::Draining).await
+    }
+
+    /// Completes once the connections are to be closed
+    pub async fn closing(&self) {
+        self.reached(State::Closing).await
+    }
+
+    pub fn track(&self) -> Task {
+        self.tasks.send_modify(|tasks| *tasks += 1);
+
+        Task {
+            tasks: self.tasks.clone(),
+        }
+    }
+
+    /// Tracked tasks still running
+    pub fn tasks(&self) -> usize {
+        *self.tasks.borrow()
+    }
+
+    /// Completes once every tracked task is done
+    pub async fn idle(&self) {
+        let _ = self.tasks.subscribe().wait_for(|tasks| *tasks == 0).await;
+    }
+
+    fn advance(&self, state: State) {
+        self.state.send_if_modified(|current| {
+            let advanced = *current < state;
+
+            if advanced {
+                *current = state;
+            }
+
+            advanced
+        });
+    }
+
+    async fn reached(&self, state: State) {
+        let _ = self
+            .state
+            .subscribe()
+            .wait_for(|current| *current >= state)
+            .await;
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        self.tasks.send_modify(|tasks| *tasks -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    async fn completes<F>(future: F) -> bool
+    where
+        F: Future<Output = ()>,
+    {
+        timeout(Duration::from_secs(1), future).await.is_ok()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn steps() {
+        let shutdown = Shutdown::default();
+        let clone = shutdown.clone();
+
+        let first = shutdown.track();
+        let second = clone.track();
+        assert_eq!(shutdown.tasks(), 2);
+
+        assert!(!shutdown.is_draining());
+        assert!(!completes(shutdown.draining()).await);
+
+        // Draining wakes the waiters of every clone
+        let draining = tokio::spawn(async move { clone.draining().await });
+        tokio::task::yield_now().await;
+        shutdown.drain();
+        assert!(completes(async { draining.await.unwrap() }).await);
+        assert!(shutdown.is_draining());
+        assert!(!completes(shutdown.closing()).await);
+
+        // Idle once every task is dropped
+        drop(first);
+        assert!(!completes(shutdown.idle()).await);
+        drop(second);
+        assert!(completes(shutdown.idle()).await);
+        assert_eq!(shutdown.tasks(), 0);
+
+        shutdown.close();
+        assert!(completes(shutdown.closing()).await);
+        assert!(completes(shutdown.draining()).await);
+
+        // Never back to an earlier state
+        shutdown.drain();
+        assert!(shutdown.is_draining());
+        assert!(completes(shutdown.closing()).await);
+    }
+}
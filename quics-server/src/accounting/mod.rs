@@ -0,0 +1,580 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use quics_protocol::auth::User;
+use quics_protocol::limit::Limit;
+use quics_protocol::request::Address;
+use serde::{Deserialize, Serialize};
+
+use crate::error;
+
+/// Limits of a user, traffic quotas count the bytes uploaded and downloaded
+/// within the current UTC day or month
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Quota {
+    daily: Option<u64>,
+    monthly: Option<u64>,
+    streams: Option<usize>,
+    rate: Option<u64>,
+}
+
+impl Quota {
+    pub fn with_daily(mut self, bytes: u64) -> Self {
+        self.daily = Some(bytes);
+
+        self
+    }
+
+    pub fn with_monthly(mut self, bytes: u64) -> Self {
+        self.monthly = Some(bytes);
+
+        self
+    }
+
+    /// Streams of the user open at the same time
+    pub fn with_streams(mut self, value: usize) -> Self {
+        self.streams = Some(value);
+
+        self
+    }
+
+    /// Bytes per second of all the streams of the user together, in each direction
+    pub fn with_rate(mut self, value: u64) -> Self {
+        self.rate = Some(value);
+
+        self
+    }
+
+    /// Limits of these, the ones not set taken from the other
+    fn or(self, other: Self) -> Self {
+        Self {
+            daily: self.daily.or(other.daily),
+            monthly: self.monthly.or(other.monthly),
+            streams: self.streams.or(other.streams),
+            rate: self.rate.or(other.rate),
+        }
+    }
+}
+
+/// Quotas of the users, the ones of a user override the global ones.
+/// Clones share the quotas, see [`Quotas::replace`].
+#[derive(Clone, Default)]
+pub struct Quotas(Arc<RwLock<Table>>);
+
+#[derive(Default)]
+struct Table {
+    global: Quota,
+    users: HashMap<String, Quota>,
+}
+
+impl Quotas {
+    pub fn with_quota(self, quota: Quota) -> Self {
+        self.0.write().unwrap().global = quota;
+
+        self
+    }
+
+    pub fn with_user(self, username: &str, quota: Quota) -> Self {
+        self.0
+            .write()
+            .unwrap()
+            .users
+            .insert(username.to_string(), quota);
+
+        self
+    }
+
+    /// Swaps in other quotas, every clone of these admits with them from now on
+    pub fn replace(&self, other: Self) {
+        let table = std::mem::take(&mut *other.0.write().unwrap());
+
+        *self.0.write().unwrap() = table;
+    }
+
+    fn quota(&self, username: &str) -> Quota {
+        let table = self.0.read().unwrap();
+
+        match table.users.get(username) {
+            Some(quota) => quota.or(table.global),
+            None => table.global,
+        }
+    }
+}
+
+/// Bytes uploaded by the client and downloaded to it
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Traffic {
+    pub upload: u64,
+    pub download: u64,
+}
+
+impl Traffic {
+    fn add(&mut self, upload: u64, download: u64) {
+        self.upload = self.upload.saturating_add(upload);
+        self.download = self.download.saturating_add(download);
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.upload.saturating_add(self.download)
+    }
+}
+
+/// Hosts a user is accounted by, the traffic to the others is counted under [`OTHER_HOSTS`]
+const MAX_DESTINATIONS: usize = 1024;
+
+const OTHER_HOSTS: &str = "*";
+
+/// Traffic of a user, of the current day and month, in total and by destination
+/// host, with the streams in flight
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Usage {
+    /// `YYYY-MM-DD`
+    day: String,
+    /// `YYYY-MM`
+    month: String,
+    daily: Traffic,
+    monthly: Traffic,
+    total: Traffic,
+    destinations: BTreeMap<String, Traffic>,
+
+    #[serde(skip)]
+    streams: usize,
+
+    #[serde(skip)]
+    limit: Option<Limit>,
+
+    /// Traffic of the streams not yet folded in
+    #[serde(skip)]
+    meters: Vec<Weak<Counters>>,
+}
+
+impl Usage {
+    /// Starts the day and the month over once past
+    fn roll(&mut self, day: &str, month: &str) {
+        if self.day != day {
+            self.day = day.to_string();
+            self.daily = Traffic::default();
+        }
+
+        if self.month != month {
+            self.month = month.to_string();
+            self.monthly = Traffic::default();
+        }
+    }
+
+    /// Adds the traffic counted by the meters since the last time
+    fn fold(&mut self) {
+        let meters = std::mem::take(&mut self.meters);
+
+        for counters in meters.iter().filter_map(Weak::upgrade) {
+            self.add(&counters);
+        }
+
+        self.meters = meters;
+    }
+
+    fn add(&mut self, counters: &Counters) {
+        let upload = counters.upload.swap(0, Ordering::Relaxed);
+        let download = counters.download.swap(0, Ordering::Relaxed);
+
+        self.daily.add(upload, download);
+        self.monthly.add(upload, download);
+        self.total.add(upload, download);
+
+        let destinations = std::mem::take(&mut *counters.destinations.lock().unwrap());
+
+        for (destination, traffic) in destinations {
+            let host = match &destination {
+                Address::Domain(domain, _) => domain.clone(),
+                Address::IPv4(address) => address.ip().to_string(),
+                Address::IPv6(address) => address.ip().to_string(),
+            };
+
+            let host = match self.destinations.contains_key(&host)
+                || self.destinations.len() < MAX_DESTINATIONS
+            {
+                true => host,
+                false => OTHER_HOSTS.to_string(),
+            };
+
+            self.destinations
+                .entry(host)
+                .or_default()
+                .add(traffic.upload, traffic.download);
+        }
+    }
+}
+
+/// Traffic of a stream, folded into the usage of its user when the stream
+/// ends, when the user starts another one and before saving
+#[derive(Debug, Default)]
+struct Counters {
+    upload: AtomicU64,
+    download: AtomicU64,
+    destinations: Mutex<HashMap<Address, Traffic>>,
+}
+
+/// Traffic of the users as persisted, the anonymous one under an empty name
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Ledger {
+    users: BTreeMap<String, Usage>,
+
+    /// Days since the epoch, with the day and the month they stand for
+    #[serde(skip)]
+    today: (u64, String, String),
+}
+
+impl Ledger {
+    /// Usage of the user in the current day and month
+    fn usage(&mut self, username: &str) -> &mut Usage {
+        let days = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / 86400;
+
+        if self.today.1.is_empty() || self.today.0 != days {
+            let (year, month, day) = civil(days);
+
+            self.today = (
+                days,
+                format!("{:04}-{:02}-{:02}", year, month, day),
+                format!("{:04}-{:02}", year, month),
+            );
+        }
+
+        if !self.users.contains_key(username) {
+            self.users.insert(username.to_string(), Usage::default());
+        }
+
+        let usage = self.users.get_mut(username).unwrap();
+
+        usage.roll(&self.today.1, &self.today.2);
+        usage
+    }
+}
+
+/// Year, month and day of days since the epoch, in the Gregorian calendar
+fn civil(days: u64) -> (u64, u64, u64) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = match shifted_month < 10 {
+        true => shifted_month + 3,
+        false => shifted_month - 9,
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+
+    (year, month, day)
+}
+
+/// Counts the traffic of the users and admits their streams within their quotas,
+/// the totals are kept in memory unless loaded from a file, see [`Accounting::save`].
+#[derive(Clone, Default)]
+pub struct Accounting {
+    quotas: Quotas,
+    path: Option<PathBuf>,
+    ledger: Arc<Mutex<Ledger>>,
+}
+
+impl Accounting {
+    /// Loads the traffic saved to a file, a missing file is created on the first save
+    pub fn load<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let ledger = match std::fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content)
+                .map_err(|error| Error::new(ErrorKind::InvalidData, error.to_string()))?,
+            Err(error) if error.kind() == ErrorKind::NotFound => Ledger::default(),
+            Err(error) => return Err(error),
+        };
+
+        Ok(Self {
+            quotas: Quotas::default(),
+            path: Some(path.as_ref().to_path_buf()),
+            ledger: Arc::new(Mutex::new(ledger)),
+        })
+    }
+
+    pub fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = quotas;
+
+        self
+    }
+
+    /// Writes the traffic to the file it was loaded from, through a temporary
+    /// file so that it is never left half written
+    pub fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+
+        let content = {
+            let mut ledger = self.ledger.lock().unwrap();
+
+            for usage in ledger.users.values_mut() {
+                usage.fold();
+            }
+
+            toml::to_string(&*ledger)
+                .map_err(|error| Error::new(ErrorKind::InvalidData, error.to_string()))?
+        };
+
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+
+        std::fs::write(&temporary, content)?;
+        std::fs::rename(&temporary, path)
+    }
+
+    /// Saves the traffic periodically, failures are reported and retried on the next period
+    pub async fn persist(self, period: Duration) {
+        use tokio::time::{interval_at, Instant, MissedTickBehavior};
+
+        let mut interval = interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(_error) = self.save() {
+                error!("accounting: {}", _error);
+            }
+        }
+    }
+}
+
+impl quics_protocol::Accountant for Accounting {
+    type Meter = Meter;
+
+    fn admit(&self, user: &User) -> Option<Meter> {
+        let quota = self.quotas.quota(user.name());
+
+        let mut ledger = self.ledger.lock().unwrap();
+        let usage = ledger.usage(user.name());
+
+        usage.fold();
+
+        let over = |limit: Option<u64>, traffic: Traffic| {
+            limit.is_some_and(|limit| traffic.bytes() >= limit)
+        };
+
+        if over(quota.daily, usage.daily)
+            || over(quota.monthly, usage.monthly)
+            || quota.streams.is_some_and(|limit| usage.streams >= limit)
+        {
+            return None;
+        }
+
+        usage.streams += 1;
+
+        // The streams of the user share the limit until its rate is changed
+        usage.limit = match (quota.rate, usage.limit.take()) {
+            (Some(rate), Some(limit)) if limit.rate() == rate => Some(limit),
+            (rate, _) => rate.map(Limit::new),
+        };
+
+        let counters = Arc::new(Counters::default());
+        usage.meters.retain(|meter| meter.strong_count() > 0);
+        usage.meters.push(Arc::downgrade(&counters));
+
+        Some(Meter {
+            username: user.name().to_string(),
+            ledger: self.ledger.clone(),
+            counters,
+            limit: usage.limit.clone(),
+        })
+    }
+}
+
+/// Counts the traffic of a stream of a user, which ends when dropped
+pub struct Meter {
+    username: String,
+    ledger: Arc<Mutex<Ledger>>,
+    counters: Arc<Counters>,
+    limit: Option<Limit>,
+}
+
+impl quics_protocol::Limited for Meter {
+    fn limit(&self) -> Option<Limit> {
+        self.limit.clone()
+    }
+}
+
+impl quics_protocol::Meter for Meter {
+    fn count(&self, destination: &Address, upload: u64, download: u64) {
+        let counters = &*self.counters;
+
+        counters.upload.fetch_add(upload, Ordering::Relaxed);
+        counters.download.fetch_add(download, Ordering::Relaxed);
+
+        let mut destinations = counters.destinations.lock().unwrap();
+
+        match destinations.get_mut(destination) {
+            Some(traffic) => traffic.add(upload, download),
+            None => destinations
+                .entry(destination.clone())
+                .or_default()
+                .add(upload, download),
+        }
+    }
+}
+
+impl Drop for Meter {
+    fn drop(&mut self) {
+        let mut ledger = self.ledger.lock().unwrap();
+        let usage = ledger.usage(&self.username);
+
+        usage.add(&self.counters);
+        usage
+            .meters
+            .retain(|meter| !std::ptr::eq(meter.as_ptr(), Arc::as_ptr(&self.counters)));
+        usage.streams = usage.streams.saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quics_protocol::{Accountant, Meter as _};
+
+    use super::*;
+
+    fn usage<F, T>(accounting: &Accounting, username: &str, f: F) -> T
+    where
+        F: FnOnce(&Usage) -> T,
+    {
+        let mut ledger = accounting.ledger.lock().unwrap();
+        let usage = ledger.usage(username);
+
+        usage.fold();
+        f(usage)
+    }
+
+    #[test]
+    fn dates() {
+        #[rustfmt::skip]
+        let dates = [
+            (0, (1970, 1, 1)),
+            (58, (1970, 2, 28)),
+            (59, (1970, 3, 1)),
+            (364, (1970, 12, 31)),
+            (365, (1971, 1, 1)),
+            // Leap days of a leap year, of a year divisible by 400 but not of one by 100
+            (789, (1972, 2, 29)),
+            (790, (1972, 3, 1)),
+            (10956, (1999, 12, 31)),
+            (11016, (2000, 2, 29)),
+            (11017, (2000, 3, 1)),
+            (19782, (2024, 2, 29)),
+            (24855, (2038, 1, 19)),
+            (47540, (2100, 2, 28)),
+            (47541, (2100, 3, 1)),
+        ];
+
+        for (days, date) in dates {
+            assert_eq!(civil(days), date, "{}", days);
+        }
+
+        // Every following day is the next one of the month or the first of the next month
+        let leap = |year: u64| year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        let length = |year, month| match month {
+            2 if leap(year) => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        };
+
+        let mut previous = civil(0);
+        for days in 1..200_000 {
+            let (year, month, day) = previous;
+            let next = match (day == length(year, month), month) {
+                (false, _) => (year, month, day + 1),
+                (true, 12) => (year + 1, 1, 1),
+                (true, _) => (year, month + 1, 1),
+            };
+
+            previous = civil(days);
+            assert_eq!(previous, next, "{}", days);
+        }
+    }
+
+    #[test]
+    fn count() {
+        let accounting = Accounting::default();
+        let user = User::new("alice");
+
+        let first = accounting.admit(&user).unwrap();
+        let second = accounting.admit(&user).unwrap();
+
+        first.count(&"example.com:443".parse().unwrap(), 10, 100);
+        first.count(&"example.com:80".parse().unwrap(), 1, 0);
+        second.count(&"192.0.2.1:53".parse().unwrap(), 5, 50);
+        drop(first);
+
+        usage(&accounting, "alice", |usage| {
+            assert_eq!(usage.total.upload, 16);
+            assert_eq!(usage.total.download, 150);
+            assert_eq!(usage.daily.bytes(), 166);
+            assert_eq!(usage.destinations["example.com"].bytes(), 111);
+            assert_eq!(usage.destinations["192.0.2.1"].bytes(), 55);
+            assert_eq!(usage.streams, 1);
+            assert_eq!(usage.meters.len(), 1);
+        });
+
+        // Nothing counted twice once the meter is dropped
+        drop(second);
+        usage(&accounting, "alice", |usage| {
+            assert_eq!(usage.total.bytes(), 166);
+            assert_eq!(usage.streams, 0);
+            assert!(usage.meters.is_empty());
+        });
+    }
+
+    #[test]
+    fn quota() {
+        let quotas = Quotas::default().with_quota(Quota::default().with_daily(100).with_streams(2));
+        let accounting = Accounting::default().with_quotas(quotas);
+        let user = User::new("alice");
+
+        let first = accounting.admit(&user).unwrap();
+        let second = accounting.admit(&user).unwrap();
+        assert!(accounting.admit(&user).is_none());
+        drop(second);
+
+        // Counted by a stream still in flight
+        first.count(&"example.com:443".parse().unwrap(), 0, 100);
+        assert!(accounting.admit(&user).is_none());
+        assert!(accounting.admit(&User::new("bob")).is_some());
+    }
+
+    #[test]
+    fn destinations_capped() {
+        let accounting = Accounting::default();
+        let meter = accounting.admit(&User::default()).unwrap();
+
+        for index in 0..MAX_DESTINATIONS + 10 {
+            let destination = format!("host{}.example:443", index).parse().unwrap();
+            meter.count(&destination, 1, 0);
+        }
+
+        usage(&accounting, "", |usage| {
+            assert_eq!(usage.destinations.len(), MAX_DESTINATIONS + 1);
+            assert_eq!(usage.destinations[OTHER_HOSTS].bytes(), 10);
+            assert_eq!(usage.total.bytes(), MAX_DESTINATIONS as u64 + 10);
+        });
+    }
+}
@@ -0,0 +1,552 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, RwLock};
+
+use quics_protocol::auth::User;
+use quics_protocol::request::Address;
+
+/// Destinations of the server network, denied unless allowed
+#[rustfmt::skip]
+const PRIVATE_V4: [(Ipv4Addr, u8); 11] = [
+    (Ipv4Addr::new(0, 0, 0, 0),         8),     // This network
+    (Ipv4Addr::new(10, 0, 0, 0),        8),     // Private
+    (Ipv4Addr::new(100, 64, 0, 0),      10),    // Shared address space
+    (Ipv4Addr::new(127, 0, 0, 0),       8),     // Loopback
+    (Ipv4Addr::new(169, 254, 0, 0),     16),    // Link-local, e.g. cloud metadata endpoints
+    (Ipv4Addr::new(172, 16, 0, 0),      12),    // Private
+    (Ipv4Addr::new(192, 0, 0, 0),       24),    // IETF protocol assignments
+    (Ipv4Addr::new(192, 168, 0, 0),     16),    // Private
+    (Ipv4Addr::new(198, 18, 0, 0),      15),    // Benchmarking
+    (Ipv4Addr::new(224, 0, 0, 0),       4),     // Multicast
+    (Ipv4Addr::new(240, 0, 0, 0),       4),     // Reserved and broadcast
+];
+
+#[rustfmt::skip]
+const PRIVATE_V6: [(Ipv6Addr, u8); 8] = [
+    (Ipv6Addr::UNSPECIFIED,                         96),    // Unspecified, loopback and IPv4-compatible
+    (Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0), 96),    // NAT64, reaching any IPv4 address
+    (Ipv6Addr::new(0x64, 0xff9b, 1, 0, 0, 0, 0, 0), 48),    // Local-use NAT64
+    (Ipv6Addr::new(0x2002, 0, 0, 0, 0, 0, 0, 0),    16),    // 6to4, reaching any IPv4 address
+    (Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0),    7),     // Unique local
+    (Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0),    10),    // Link-local
+    (Ipv6Addr::new(0xfec0, 0, 0, 0, 0, 0, 0, 0),    10),    // Site-local
+    (Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0),    8),     // Multicast
+];
+
+/// Destination of an ACL entry
+#[derive(Debug, Clone)]
+enum Matcher {
+    /// The domain and its subdomains, only matched by requested domains
+    Domain(String),
+    Cidr(IpAddr, u8),
+    Port(u16, u16),
+}
+
+impl Matcher {
+    /// Parses `ADDRESS[/PREFIX]`, `:PORT[-PORT]` or a domain
+    fn parse(entry: &str) -> Result<Self> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "invalid entry '{}', expected ADDRESS[/PREFIX], :PORT[-PORT] or a domain",
+                    entry
+                ),
+            )
+        };
+
+        let (network, prefix) = entry.split_once('/').unwrap_or((entry, ""));
+
+        if let Ok(network) = network.parse::<IpAddr>() {
+            let bits = if network.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                "" => bits,
+                value => value.parse::<u8>().map_err(|_| invalid())?,
+            };
+
+            return match prefix <= bits {
+                true => Ok(Self::Cidr(network, prefix)),
+                false => Err(invalid()),
+            };
+        }
+
+        // Checked after networks, `::1` is not a port
+        if let Some(ports) = entry.strip_prefix(':') {
+            let (start, end) = ports.split_once('-').unwrap_or((ports, ports));
+            let start = start.parse::<u16>().map_err(|_| invalid())?;
+            let end = end.parse::<u16>().map_err(|_| invalid())?;
+
+            return match start <= end {
+                true => Ok(Self::Port(start, end)),
+                false => Err(invalid()),
+            };
+        }
+
+        let domain = entry.trim_start_matches("*.").trim_end_matches('.');
+
+        match !domain.is_empty()
+            && domain
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        {
+            true => Ok(Self::Domain(domain.to_ascii_lowercase())),
+            false => Err(invalid()),
+        }
+    }
+
+    fn matches(&self, destination: &Address, address: SocketAddr) -> bool {
+        match (self, destination) {
+            (Self::Domain(value), Address::Domain(domain, _)) => {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+
+                domain == *value || domain.ends_with(&format!(".{}", value))
+            }
+            (Self::Domain(_), _) => false,
+            (Self::Cidr(network, prefix), _) => {
+                contains(*network, *prefix, canonical(address.ip()))
+            }
+            (Self::Port(start, end), _) => (*start..=*end).contains(&address.port()),
+        }
+    }
+}
+
+/// Destinations allowed and denied in a scope, global or of a user
+///
+/// Denied destinations come first, then the private address ranges, only
+/// reached through a network entry or [`Rules::with_private`], then allowed
+/// destinations and last the default.
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    allow: Vec<Matcher>,
+    deny: Vec<Matcher>,
+    allow_private: Option<bool>,
+    allow_default: Option<bool>,
+}
+
+impl Rules {
+    /// Allowed destination, private addresses only when given as a network
+    pub fn with_allow(mut self, entry: &str) -> Result<Self> {
+        self.allow.push(Matcher::parse(entry)?);
+
+        Ok(self)
+    }
+
+    pub fn with_deny(mut self, entry: &str) -> Result<Self> {
+        self.deny.push(Matcher::parse(entry)?);
+
+        Ok(self)
+    }
+
+    /// Whether loopback, private, link-local and multicast addresses may be reached,
+    /// denied by default
+    pub fn with_private(mut self, value: bool) -> Self {
+        self.allow_private = Some(value);
+
+        self
+    }
+
+    /// Whether the other destinations may be reached, allowed by default
+    pub fn with_default(mut self, value: bool) -> Self {
+        self.allow_default = Some(value);
+
+        self
+    }
+
+    fn denies(&self, destination: &Address, address: SocketAddr) -> bool {
+        self.deny
+            .iter()
+            .any(|matcher| matcher.matches(destination, address))
+    }
+
+    /// Allowed by an entry, or by a network entry only when `networks`
+    fn allows(&self, destination: &Address, address: SocketAddr, networks: bool) -> bool {
+        self.allow
+            .iter()
+            .filter(|matcher| !networks || matches!(matcher, Matcher::Cidr(..)))
+            .any(|matcher| matcher.matches(destination, address))
+    }
+}
+
+/// Destinations the users may reach, and addresses they may listen on, a
+/// destination denied by the rules of the user or by the global ones is never
+/// reached. The settings of a user override
+/// the global ones. Clones share the rules, see [`Acl::replace`].
+#[derive(Clone, Default)]
+pub struct Acl(Arc<RwLock<Scopes>>);
+
+#[derive(Default)]
+struct Scopes {
+    global: Rules,
+    users: HashMap<String, Rules>,
+}
+
+impl Acl {
+    pub fn with_rules(self, rules: Rules) -> Self {
+        self.0.write().unwrap().global = rules;
+
+        self
+    }
+
+    pub fn with_user(self, username: &str, rules: Rules) -> Self {
+        self.0
+            .write()
+            .unwrap()
+            .users
+            .insert(username.to_string(), rules);
+
+        self
+    }
+
+    /// Swaps in other rules, every clone of these authorizes with them from now on
+    pub fn replace(&self, other: Self) {
+        let scopes = std::mem::take(&mut *other.0.write().unwrap());
+
+        *self.0.write().unwrap() = scopes;
+    }
+}
+
+impl Acl {
+    /// Private addresses are only checked for destinations, the addresses of
+    /// binds are on the server anyway
+    fn decide(&self, user: &User, destination: &Address, address: SocketAddr, bind: bool) -> bool {
+        let scopes = self.0.read().unwrap();
+
+        let global = &scopes.global;
+        let scoped = match user.is_anonymous() {
+            true => None,
+            false => scopes.users.get(user.name()),
+        };
+
+        let all = || scoped.into_iter().chain([global]);
+        let setting = |get: fn(&Rules) -> Option<bool>| scoped.and_then(get).or(get(global));
+
+        if all().any(|rules| rules.denies(destination, address)) {
+            return false;
+        }
+
+        // A domain or a port allowed does not open the server network, the
+        // domain may resolve to any address
+        if !bind && is_private(canonical(address.ip())) {
+            return all().any(|rules| rules.allows(destination, address, true))
+                || setting(|rules| rules.allow_private).unwrap_or(false);
+        }
+
+        if all().any(|rules| rules.allows(destination, address, false)) {
+            return true;
+        }
+
+        setting(|rules| rules.allow_default).unwrap_or(true)
+    }
+}
+
+impl quics_protocol::Authorizer for Acl {
+    fn authorize(&self, user: &User, destination: &Address, address: SocketAddr) -> bool {
+        self.decide(user, destination, address, false)
+    }
+
+    /// Listening addresses are matched by the network and port entries
+    fn authorize_bind(&self, user: &User, address: SocketAddr) -> bool {
+        self.decide(user, &address.into(), address, true)
+    }
+}
+
+/// IPv4 address of an IPv4-mapped IPv6 address, the same destination
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        ip => ip,
+    }
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(_) => PRIVATE_V4
+            .iter()
+            .any(|(network, prefix)| contains(IpAddr::V4(*network), *prefix, ip)),
+        IpAddr::V6(_) => PRIVATE_V6
+            .iter()
+            .any(|(network, prefix)| contains(IpAddr::V6(*network), *prefix, ip)),
+    }
+}
+
+fn contains(network: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quics_protocol::Authorizer;
+
+    use super::*;
+
+    fn domain(name: &str, address: &str) -> (Address, SocketAddr) {
+        let address = address.parse::<SocketAddr>().unwrap();
+
+        (Address::Domain(name.to_string(), address.port()), address)
+    }
+
+    fn ip(address: &str) -> (Address, SocketAddr) {
+        let address = address.parse::<SocketAddr>().unwrap();
+
+        (Address::from(address), address)
+    }
+
+    fn authorize(acl: &Acl, user: &User, (destination, address): (Address, SocketAddr)) -> bool {
+        acl.authorize(user, &destination, address)
+    }
+
+    #[test]
+    fn parse() {
+        for (entry, valid) in [
+            ("10.0.0.0/8", true),
+            ("10.0.0.1", true),
+            ("::1/128", true),
+            ("10.0.0.0/33", false),
+            ("::/129", false),
+            (":25", true),
+            (":6000-6063", true),
+            (":6063-6000", false),
+            (":65536", false),
+            (":", false),
+            ("example.org", true),
+            ("*.example.org", true),
+            ("example.org.", true),
+            ("exa mple.org", false),
+            ("", false),
+        ] {
+            assert_eq!(Matcher::parse(entry).is_ok(), valid, "{}", entry);
+        }
+    }
+
+    #[test]
+    fn matches() {
+        let (destination, address) = domain("WWW.Example.org.", "93.184.215.14:443");
+
+        for (entry, matched) in [
+            ("example.org", true),
+            ("www.example.org", true),
+            ("ample.org", false),
+            ("other.example.org", false),
+            ("93.184.0.0/16", true),
+            ("93.185.0.0/16", false),
+            (":443", true),
+            (":400-500", true),
+            (":80", false),
+        ] {
+            let matcher = Matcher::parse(entry).unwrap();
+            assert_eq!(matcher.matches(&destination, address), matched, "{}", entry);
+        }
+
+        // Domains are only matched by requested domains
+        let (destination, address) = ip("93.184.215.14:443");
+        let matcher = Matcher::parse("example.org").unwrap();
+        assert!(!matcher.matches(&destination, address));
+
+        // IPv4-mapped IPv6 addresses are matched by IPv4 networks
+        let (destination, address) = ip("[::ffff:10.1.2.3]:80");
+        let matcher = Matcher::parse("10.0.0.0/8").unwrap();
+        assert!(matcher.matches(&destination, address));
+    }
+
+    #[test]
+    fn private() {
+        for (address, private) in [
+            ("0.0.0.0", true),
+            ("10.1.2.3", true),
+            ("100.64.0.1", true),
+            ("127.0.0.1", true),
+            ("169.254.169.254", true),
+            ("172.31.255.255", true),
+            ("172.32.0.1", false),
+            ("192.0.0.170", true),
+            ("192.168.1.1", true),
+            ("198.18.0.1", true),
+            ("198.19.255.255", true),
+            ("198.20.0.1", false),
+            ("224.0.0.1", true),
+            ("255.255.255.255", true),
+            ("8.8.8.8", false),
+            ("::", true),
+            ("::1", true),
+            ("::127.0.0.1", true),
+            ("::ffff:127.0.0.1", true),
+            ("::ffff:8.8.8.8", false),
+            ("64:ff9b::a9fe:a9fe", true),
+            ("64:ff9b:1::1", true),
+            ("2002:7f00:1::", true),
+            ("fd00::1", true),
+            ("fe80::1", true),
+            ("fec0::1", true),
+            ("ff02::1", true),
+            ("2001:4860:4860::8888", false),
+        ] {
+            let ip = canonical(address.parse().unwrap());
+            assert_eq!(is_private(ip), private, "{}", address);
+        }
+    }
+
+    #[test]
+    fn private_denied_by_default() {
+        let acl = Acl::default();
+        let user = User::default();
+
+        assert!(authorize(&acl, &user, ip("8.8.8.8:53")));
+        assert!(!authorize(&acl, &user, ip("127.0.0.1:22")));
+        assert!(!authorize(&acl, &user, domain("localhost", "127.0.0.1:22")));
+        assert!(!authorize(&acl, &user, ip("[64:ff9b::a9fe:a9fe]:80")));
+
+        let acl = Acl::default().with_rules(Rules::default().with_private(true));
+        assert!(authorize(&acl, &user, ip("127.0.0.1:22")));
+    }
+
+    #[test]
+    fn private_reached_by_networks_only() {
+        let rules = Rules::default()
+            .with_allow("example.com")
+            .unwrap()
+            .with_allow(":443")
+            .unwrap()
+            .with_allow("10.0.0.0/8")
+            .unwrap();
+        let acl = Acl::default().with_rules(rules);
+        let user = User::default();
+
+        // A domain resolving to the server network, e.g. through DNS rebinding
+        assert!(!authorize(
+            &acl,
+            &user,
+            domain("example.com", "127.0.0.1:80")
+        ));
+        assert!(!authorize(
+            &acl,
+            &user,
+            domain("example.com", "169.254.169.254:80")
+        ));
+        assert!(!authorize(&acl, &user, ip("192.168.1.1:443")));
+
+        assert!(authorize(
+            &acl,
+            &user,
+            domain("example.com", "93.184.215.14:80")
+        ));
+        assert!(authorize(&acl, &user, domain("intranet", "10.1.2.3:80")));
+    }
+
+    #[test]
+    fn order() {
+        let rules = Rules::default()
+            .with_allow("example.org")
+            .unwrap()
+            .with_deny("bad.example.org")
+            .unwrap()
+            .with_default(false);
+        let acl = Acl::default().with_rules(rules);
+        let user = User::default();
+
+        assert!(authorize(
+            &acl,
+            &user,
+            domain("example.org", "93.184.215.14:80")
+        ));
+        assert!(!authorize(
+            &acl,
+            &user,
+            domain("bad.example.org", "93.184.215.14:80")
+        ));
+        assert!(!authorize(
+            &acl,
+            &user,
+            domain("example.com", "93.184.215.14:80")
+        ));
+    }
+
+    #[test]
+    fn users() {
+        let global = Rules::default()
+            .with_deny("169.254.169.254")
+            .unwrap()
+            .with_deny(":25")
+            .unwrap();
+        let admin = Rules::default()
+            .with_allow("metadata.internal")
+            .unwrap()
+            .with_allow("169.254.0.0/16")
+            .unwrap()
+            .with_allow(":25")
+            .unwrap()
+            .with_deny("example.org")
+            .unwrap()
+            .with_private(true);
+        let acl = Acl::default().with_rules(global).with_user("admin", admin);
+
+        let admin = User::new("admin");
+        let other = User::new("other");
+
+        // The global deny wins over the allow of a user
+        assert!(!authorize(
+            &acl,
+            &admin,
+            domain("metadata.internal", "169.254.169.254:80")
+        ));
+        assert!(!authorize(&acl, &admin, ip("93.184.215.14:25")));
+
+        // Entries and settings of a user only apply to the user
+        assert!(!authorize(
+            &acl,
+            &admin,
+            domain("example.org", "93.184.215.14:80")
+        ));
+        assert!(authorize(
+            &acl,
+            &other,
+            domain("example.org", "93.184.215.14:80")
+        ));
+        assert!(authorize(&acl, &admin, ip("169.254.1.1:80")));
+        assert!(authorize(&acl, &admin, ip("10.1.2.3:80")));
+        assert!(!authorize(&acl, &other, ip("10.1.2.3:80")));
+    }
+
+    #[test]
+    fn binds() {
+        let rules = Rules::default()
+            .with_allow(":6000-6063")
+            .unwrap()
+            .with_deny("127.0.0.1")
+            .unwrap()
+            .with_default(false);
+        let acl = Acl::default().with_rules(rules);
+        let user = User::default();
+        let bind = |address: &str| acl.authorize_bind(&user, address.parse().unwrap());
+
+        // Listening addresses are local, the private ranges do not apply
+        assert!(bind("0.0.0.0:6000"));
+        assert!(bind("[::]:6063"));
+        assert!(!bind("127.0.0.1:6000"));
+        assert!(!bind("0.0.0.0:22"));
+    }
+
+    #[test]
+    fn replace() {
+        let acl = Acl::default();
+        let clone = acl.clone();
+        let user = User::default();
+
+        acl.replace(Acl::default().with_rules(Rules::default().with_default(false)));
+
+        assert!(!authorize(&clone, &user, ip("8.8.8.8:53")));
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use quics_protocol::auth::{constant_time_eq, Authentication, User};
+
+/// Registered users, anonymous access is allowed only when there is none.
+/// Clones share the users, see [`Users::replace`].
+#[derive(Clone, Default)]
+pub struct Users(Arc<RwLock<HashMap<String, String>>>);
+
+impl Users {
+    /// Loads `username:password` lines, empty lines and lines starting with `#` are ignored.
+    /// A file without users is rejected rather than allowing anonymous access.
+    pub fn load<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let content = std::fs::read_to_string(path)?;
+
+        let mut users = Self::default();
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |message: String| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("line {}: {}", number + 1, message),
+                )
+            };
+
+            let (username, password) = line
+                .split_once(':')
+                .ok_or_else(|| invalid("expected `username:password`".to_string()))?;
+
+            users = users
+                .with_user(username, password)
+                .map_err(|error| invalid(error.to_string()))?;
+        }
+
+        if users.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "no users"));
+        }
+
+        Ok(users)
+    }
+
+    /// Whether anonymous access is allowed
+    pub fn is_empty(&self) -> bool {
+        self.0.read().unwrap().is_empty()
+    }
+
+    /// Adds a user, usernames are unique
+    pub fn with_user(self, username: &str, password: &str) -> Result<Self> {
+        if username.is_empty() || username.len() > 255 || password.len() > 255 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "username and password must be 1 to 255 bytes",
+            ));
+        }
+
+        if self
+            .0
+            .write()
+            .unwrap()
+            .insert(username.to_string(), password.to_string())
+            .is_some()
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("duplicate user '{}'", username),
+            ));
+        }
+
+        Ok(self)
+    }
+
+    /// Swaps in other users, every clone of these authenticates with them from now on
+    pub fn replace(&self, other: Self) {
+        let users = std::mem::take(&mut *other.0.write().unwrap());
+
+        *self.0.write().unwrap() = users;
+    }
+}
+
+impl quics_protocol::Authenticator for Users {
+    async fn authenticate(&self, authentication: &Authentication) -> Option<User> {
+        let users = self.0.read().unwrap();
+
+        if users.is_empty() {
+            return Some(User::default());
+        }
+
+        match authentication {
+            Authentication::None => None,
+            Authentication::Password(username, password) => {
+                let expected = users.get(username)?;
+
+                constant_time_eq(expected.as_bytes(), password.as_bytes())
+                    .then(|| User::new(username))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(content: &str) -> Result<Users> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "quics-users-{}-{}",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, content)?;
+
+        let users = Users::load(&path);
+        std::fs::remove_file(&path)?;
+
+        users
+    }
+
+    #[test]
+    fn load_users() {
+        let users = load("# users\n\nalice:secret\nbob:pass:word\n").unwrap();
+
+        assert!(!users.is_empty());
+        assert_eq!(users.0.read().unwrap()["bob"], "pass:word");
+    }
+
+    #[test]
+    fn load_rejects_no_users() {
+        for content in ["", "\n\n", "# alice:secret\n"] {
+            assert!(load(content).is_err(), "{:?}", content);
+        }
+    }
+
+    #[test]
+    fn load_rejects_invalid_lines() {
+        for content in ["alice\n", ":secret\n", "alice:a\nalice:b\n"] {
+            assert!(load(content).is_err(), "{:?}", content);
+        }
+    }
+}
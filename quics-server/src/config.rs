@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// Server settings of a TOML file, keys are named after the command line options
+///
+/// ```toml
+/// tls-cert = "cert.pem"
+/// tls-key = "key.pem"
+/// outbound-bind = "203.0.113.1"
+/// metrics = "127.0.0.1:9090"
+///
+/// [[listeners]]
+/// listen = "0.0.0.0:443"
+///
+/// [[listeners]]
+/// listen = "[::]:8443"
+/// tls-cert = "other.pem"
+/// tls-key = "other.key"
+///
+/// [[users]]
+/// username = "username"
+/// password = "password"
+///
+/// [acl]
+/// deny = [":25", "example.org"]
+///
+/// [acl.users.admin]
+/// allow = ["10.0.0.0/8"]
+///
+/// [quotas]
+/// monthly = 100000000000
+/// max-streams = 64
+/// rate-limit = 10000000
+///
+/// [quotas.users.admin]
+/// monthly = 1000000000000
+/// rate-limit = 100000000
+///
+/// [dns]
+/// servers = ["1.1.1.1", "8.8.8.8:53"]
+/// strategy = "ipv4-then-ipv6"
+///
+/// [limits]
+/// max-streams = 100
+/// idle-timeout = 30
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub listeners: Vec<Listener>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub initial_congestion_window: Option<u32>,
+
+    pub users: Vec<User>,
+    pub users_file: Option<String>,
+    pub allow_bind: Option<bool>,
+    pub drain_timeout: Option<u64>,
+    pub rate_limit: Option<u64>,
+    pub connection_rate_limit: Option<u64>,
+    pub stream_rate_limit: Option<u64>,
+    pub usage_file: Option<String>,
+    pub metrics: Option<String>,
+    pub tracing_level: Option<String>,
+
+    pub outbound_bind: Option<String>,
+    pub acl: Option<Acl>,
+    pub quotas: Option<Quotas>,
+    pub dns: Option<Dns>,
+    pub limits: Option<Limits>,
+}
+
+/// Listening address, TLS files default to the top-level ones
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Listener {
+    pub listen: String,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct User {
+    pub username: String,
+    pub password: String,
+}
+
+/// Destinations the users may reach, entries are `ADDRESS[/PREFIX]`,
+/// `:PORT[-PORT]` or a domain matching its subdomains too
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Acl {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub allow_private: Option<bool>,
+    /// `allow` or `deny`
+    pub default: Option<String>,
+    pub users: BTreeMap<String, AclRules>,
+}
+
+/// Rules of a user, applied before the global ones
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AclRules {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub allow_private: Option<bool>,
+    pub default: Option<String>,
+}
+
+/// Bytes a user may relay in the current UTC day and month, with the streams
+/// the user may have open at the same time and the bytes per second of all of
+/// them together
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Quotas {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+    pub max_streams: Option<usize>,
+    pub rate_limit: Option<u64>,
+    pub users: BTreeMap<String, Quota>,
+}
+
+/// Quota of a user, overriding the global one key by key
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Quota {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+    pub max_streams: Option<usize>,
+    pub rate_limit: Option<u64>,
+}
+
+/// Resolver of requested domains, name servers are `IP` or `IP:PORT`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Dns {
+    pub servers: Vec<String>,
+    pub timeout: Option<u64>,
+    pub strategy: Option<String>,
+    pub cache_size: Option<usize>,
+}
+
+/// Durations are in seconds
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Limits {
+    pub max_streams: Option<u64>,
+    pub idle_timeout: Option<u64>,
+    pub handshake_timeout: Option<u64>,
+    pub connect_timeout: Option<u64>,
+}
+
+impl Config {
+    /// Loads the file, unknown keys are rejected
+    pub fn load<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let content = std::fs::read_to_string(path)?;
+
+        toml::from_str(&content)
+            .map_err(|error| Error::new(ErrorKind::InvalidData, error.to_string()))
+    }
+}
@@ -4,8 +4,9 @@ pub struct Connection<T> {
     inner: T,
 }
 
+/// Connections of every listening server come out of a single provider
 pub struct Builder<T> {
-    server: T,
+    servers: Vec<T>,
 }
 
 mod s2n_quic {
@@ -17,20 +18,32 @@ mod s2n_quic {
 
     impl Builder<NoiseServer> {
         pub fn new(server: NoiseServer) -> Self {
-            Self { server }
+            Self {
+                servers: vec![server],
+            }
+        }
+
+        /// Another server listening on its own address
+        pub fn with_server(mut self, server: NoiseServer) -> Self {
+            self.servers.push(server);
+
+            self
         }
 
         pub fn build(self) -> impl Provider<NoiseConnection> {
             let (connection_sender, connection_receiver) = mpsc::channel(1);
-            let mut server = self.server;
 
-            tokio::spawn(async move {
-                while let Some(connection) = server.accept().await {
-                    if let Err(_error) = connection_sender.send(connection).await {
-                        break;
+            for mut server in self.servers {
+                let connection_sender = connection_sender.clone();
+
+                tokio::spawn(async move {
+                    while let Some(connection) = server.accept().await {
+                        if let Err(_error) = connection_sender.send(connection).await {
+                            break;
+                        }
                     }
-                }
-            });
+                });
+            }
 
             Connection {
                 inner: connection_receiver,
@@ -1,4 +1,6 @@
-use quics_protocol::Provider;
+use quics_protocol::limit::Limit;
+use quics_protocol::shutdown::Shutdown;
+use quics_protocol::{Limited, Provider};
 
 pub struct Stream<T> {
     inner: T,
@@ -6,36 +8,106 @@ pub struct Stream<T> {
 
 pub struct Builder<T> {
     connection: T,
+    shutdown: Shutdown,
+    connection_rate: Option<u64>,
+}
+
+/// Opens streams towards the client on the connection a stream was accepted on,
+/// with the limit shared by the streams of the connection
+#[derive(Clone)]
+pub struct Opener<T> {
+    inner: T,
+    limit: Option<Limit>,
+}
+
+impl<T> Limited for Opener<T> {
+    fn limit(&self) -> Option<Limit> {
+        self.limit.clone()
+    }
 }
 
 mod s2n_quic {
+    use quics_protocol::shutdown::SHUTDOWN_ERROR_CODE;
+    use s2n_quic::application;
+    use s2n_quic::connection::Handle;
     use s2n_quic::stream::BidirectionalStream;
     use s2n_quic::Connection as NoiseConnection;
+    use tokio::io::Result;
     use tokio::sync::mpsc;
     use tokio::sync::mpsc::Receiver;
 
-    use super::{Builder, Provider, Stream};
+    use super::{Builder, Limit, Opener, Provider, Shutdown, Stream};
 
     impl<T> Builder<T>
     where
         T: Provider<NoiseConnection> + Send + 'static,
     {
         pub fn new(connection: T) -> Self {
-            Self { connection }
+            Self {
+                connection,
+                shutdown: Shutdown::default(),
+                connection_rate: None,
+            }
+        }
+
+        /// Streams opened while draining are refused, connections are closed
+        /// with `SHUTDOWN_ERROR_CODE` at the end
+        pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+            self.shutdown = shutdown;
+
+            self
+        }
+
+        /// Bytes per second of all the streams of a connection together, in each direction
+        pub fn with_connection_rate_limit(mut self, rate: u64) -> Self {
+            self.connection_rate = Some(rate);
+
+            self
         }
 
-        pub fn build(self) -> impl Provider<BidirectionalStream> {
+        pub fn build(self) -> impl Provider<(BidirectionalStream, Opener<Handle>)> {
             let (stream_sender, stream_receiver) = mpsc::channel(1);
             let mut connection = self.connection;
+            let shutdown = self.shutdown;
+            let connection_rate = self.connection_rate;
 
             tokio::spawn(async move {
-                while let Some(mut connection) = connection.fetch().await {
+                while let Some(connection) = connection.fetch().await {
                     let stream_sender = stream_sender.clone();
+                    let shutdown = shutdown.clone();
+                    let (handle, mut acceptor) = connection.split();
+                    let limit = connection_rate.map(Limit::new);
 
                     tokio::spawn(async move {
-                        while let Ok(Some(stream)) = connection.accept_bidirectional_stream().await
-                        {
-                            let _ = stream_sender.send(stream).await;
+                        loop {
+                            let stream = tokio::select! {
+                                accepted = acceptor.accept_bidirectional_stream() => match accepted {
+                                    Ok(Some(value)) => value,
+                                    _ => break,
+                                },
+                                _ = shutdown.closing() => {
+                                    let error = application::Error::new(SHUTDOWN_ERROR_CODE.into())
+                                        .unwrap_or(application::Error::UNKNOWN);
+
+                                    handle.close(error);
+                                    break;
+                                }
+                            };
+
+                            // Dropped, the client sends the request again on another connection
+                            if shutdown.is_draining() {
+                                continue;
+                            }
+
+                            let opener = Opener {
+                                inner: handle.clone(),
+                                limit: limit.clone(),
+                            };
+
+                            tokio::select! {
+                                _ = stream_sender.send((stream, opener)) => {}
+                                _ = shutdown.draining() => {}
+                            }
                         }
                     });
                 }
@@ -47,9 +119,23 @@ mod s2n_quic {
         }
     }
 
-    impl Provider<BidirectionalStream> for Stream<Receiver<BidirectionalStream>> {
-        async fn fetch(&mut self) -> Option<BidirectionalStream> {
+    impl Provider<(BidirectionalStream, Opener<Handle>)>
+        for Stream<Receiver<(BidirectionalStream, Opener<Handle>)>>
+    {
+        async fn fetch(&mut self) -> Option<(BidirectionalStream, Opener<Handle>)> {
             self.inner.recv().await
         }
     }
+
+    impl quics_protocol::Open<BidirectionalStream> for Opener<Handle> {
+        async fn open(&mut self) -> Result<BidirectionalStream> {
+            Ok(self.inner.open_bidirectional_stream().await?)
+        }
+
+        fn close(&self, code: u32) {
+            let error = application::Error::new(code.into()).unwrap_or(application::Error::UNKNOWN);
+
+            self.inner.close(error);
+        }
+    }
 }
@@ -0,0 +1,106 @@
+use std::sync::{Arc, Mutex};
+
+#[cfg(not(unix))]
+use self::files::Inner;
+#[cfg(unix)]
+use self::s2n_quic::Inner;
+
+/// TLS certificate chain and private key of a listener. Once replaced, they
+/// are presented by the following handshakes, established connections go on.
+#[derive(Clone)]
+pub struct Certificate {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[cfg(unix)]
+mod s2n_quic {
+    use std::io::{Error, Result};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    use s2n_quic::provider::tls::s2n_tls::s2n_tls::config::{Builder, Config};
+    use s2n_quic::provider::tls::s2n_tls::s2n_tls::security::DEFAULT_TLS13;
+    use s2n_quic::provider::tls::s2n_tls::{ConfigLoader, ConnectionContext, Server};
+
+    use super::Certificate;
+
+    pub type Inner = Config;
+
+    impl Certificate {
+        pub fn read<P>(cert: P, key: P) -> Result<Self>
+        where
+            P: AsRef<Path>,
+        {
+            Ok(Self {
+                inner: Arc::new(Mutex::new(config(cert.as_ref(), key.as_ref())?)),
+            })
+        }
+
+        /// Swaps in another certificate, every clone of this one presents it from now on
+        pub fn replace(&self, other: Self) {
+            let config = other.inner.lock().unwrap().clone();
+
+            *self.inner.lock().unwrap() = config;
+        }
+
+        /// TLS provider of a server
+        pub fn provider(&self) -> Server<Self> {
+            Server::from_loader(self.clone())
+        }
+    }
+
+    impl ConfigLoader for Certificate {
+        fn load(&mut self, _context: ConnectionContext) -> Config {
+            self.inner.lock().unwrap().clone()
+        }
+    }
+
+    /// Same settings as the default server of s2n-quic
+    fn config(cert: &Path, key: &Path) -> Result<Config> {
+        let cert = std::fs::read(cert)?;
+        let key = std::fs::read(key)?;
+
+        let mut builder = Builder::new();
+
+        builder
+            .enable_quic()
+            .and_then(|builder| builder.set_security_policy(&DEFAULT_TLS13))
+            .and_then(|builder| builder.set_application_protocol_preference([b"h3"]))
+            .and_then(|builder| builder.load_pem(&cert, &key))
+            .map_err(|error| Error::other(error.to_string()))?;
+
+        builder
+            .build()
+            .map_err(|error| Error::other(error.to_string()))
+    }
+}
+
+/// Without s2n-tls the files are only read once
+#[cfg(not(unix))]
+mod files {
+    use std::io::Result;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    use super::Certificate;
+
+    pub type Inner = (PathBuf, PathBuf);
+
+    impl Certificate {
+        pub fn read<P>(cert: P, key: P) -> Result<Self>
+        where
+            P: AsRef<Path>,
+        {
+            Ok(Self {
+                inner: Arc::new(Mutex::new((
+                    cert.as_ref().to_path_buf(),
+                    key.as_ref().to_path_buf(),
+                ))),
+            })
+        }
+
+        pub fn files(&self) -> (PathBuf, PathBuf) {
+            self.inner.lock().unwrap().clone()
+        }
+    }
+}
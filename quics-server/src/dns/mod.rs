@@ -1,7 +1,10 @@
-use std::io::{Error, Result};
+use std::io::{Error, ErrorKind, Result};
 use std::net::SocketAddr;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use hickory_resolver::config::LookupIpStrategy;
 use hickory_resolver::TokioAsyncResolver;
 
 #[derive(Clone)]
@@ -9,12 +12,121 @@ pub struct Resolver(Arc<TokioAsyncResolver>);
 
 impl Default for Resolver {
     fn default() -> Self {
-        use hickory_resolver::config::{ResolverConfig, ResolverOpts};
+        Builder::default().build()
+    }
+}
+
+/// Order of the IP versions a domain is resolved to
+#[derive(Clone, Copy, Debug)]
+pub enum Strategy {
+    Ipv4Only,
+    Ipv6Only,
+    Ipv4AndIpv6,
+    Ipv4ThenIpv6,
+    Ipv6ThenIpv4,
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "ipv4-only" => Ok(Self::Ipv4Only),
+            "ipv6-only" => Ok(Self::Ipv6Only),
+            "ipv4-and-ipv6" => Ok(Self::Ipv4AndIpv6),
+            "ipv4-then-ipv6" => Ok(Self::Ipv4ThenIpv6),
+            "ipv6-then-ipv4" => Ok(Self::Ipv6ThenIpv4),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "unknown strategy '{}', expected ipv4-only, ipv6-only, ipv4-and-ipv6, ipv4-then-ipv6 or ipv6-then-ipv4",
+                    value
+                ),
+            )),
+        }
+    }
+}
+
+impl From<Strategy> for LookupIpStrategy {
+    fn from(value: Strategy) -> Self {
+        match value {
+            Strategy::Ipv4Only => Self::Ipv4Only,
+            Strategy::Ipv6Only => Self::Ipv6Only,
+            Strategy::Ipv4AndIpv6 => Self::Ipv4AndIpv6,
+            Strategy::Ipv4ThenIpv6 => Self::Ipv4thenIpv6,
+            Strategy::Ipv6ThenIpv4 => Self::Ipv6thenIpv4,
+        }
+    }
+}
+
+/// Name servers queried over UDP and TCP, Google Public DNS when there is none
+#[derive(Debug, Default)]
+pub struct Builder {
+    servers: Vec<SocketAddr>,
+    timeout: Option<Duration>,
+    strategy: Option<Strategy>,
+    cache_size: Option<usize>,
+}
+
+impl Builder {
+    pub fn with_server(mut self, value: SocketAddr) -> Self {
+        self.servers.push(value);
+
+        self
+    }
+
+    /// Upper bound of a single query
+    pub fn with_timeout(mut self, value: Duration) -> Self {
+        self.timeout = Some(value);
+
+        self
+    }
+
+    pub fn with_strategy(mut self, value: Strategy) -> Self {
+        self.strategy = Some(value);
+
+        self
+    }
+
+    /// Answers kept in the cache
+    pub fn with_cache_size(mut self, value: usize) -> Self {
+        self.cache_size = Some(value);
+
+        self
+    }
+
+    pub fn build(self) -> Resolver {
+        use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+
+        let config = match self.servers.is_empty() {
+            true => ResolverConfig::default(),
+            false => {
+                let mut config = ResolverConfig::new();
+
+                for address in self.servers {
+                    config.add_name_server(NameServerConfig::new(address, Protocol::Udp));
+                    config.add_name_server(NameServerConfig::new(address, Protocol::Tcp));
+                }
+
+                config
+            }
+        };
+
+        let mut options = ResolverOpts::default();
+
+        if let Some(value) = self.timeout {
+            options.timeout = value;
+        }
+
+        if let Some(value) = self.strategy {
+            options.ip_strategy = value.into();
+        }
+
+        if let Some(value) = self.cache_size {
+            options.cache_size = value;
+        }
 
-        Self(Arc::new(TokioAsyncResolver::tokio(
-            ResolverConfig::default(),
-            ResolverOpts::default(),
-        )))
+        Resolver(Arc::new(TokioAsyncResolver::tokio(config, options)))
     }
 }
 
@@ -1,2 +1,9 @@
+mod macros;
+
+pub mod accounting;
+pub mod acl;
+pub mod auth;
+pub mod config;
 pub mod connect;
 pub mod dns;
+pub mod metrics;
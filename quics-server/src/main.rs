@@ -1,40 +1,716 @@
-use clap::Parser;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
 use quics_protocol::server::Server;
+use quics_protocol::shutdown::Shutdown;
+use quics_server::accounting::{Accounting, Quota, Quotas};
+use quics_server::acl::{Acl, Rules};
+use quics_server::auth::Users;
+use quics_server::config::{Acl as AclConfig, Config, Limits, Quotas as QuotasConfig};
 use quics_server::connect::connection::Builder as ConnectionBuilder;
 use quics_server::connect::stream::Builder as StreamBuilder;
-use quics_server::dns::Resolver;
+use quics_server::connect::tls::Certificate;
+use quics_server::dns::{Builder as ResolverBuilder, Strategy};
+use quics_server::metrics::{Metrics, Observed};
+use quics_server::{info, warn};
+use tokio::net::TcpListener;
 
 /// QUICS Server
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// TOML configuration file, options given on the command line take precedence over it
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<String>,
+
     /// Server listening address
     #[arg(short, long)]
-    listen: String,
+    listen: Option<String>,
 
     /// TLS certificate file path
     #[arg(long)]
-    tls_cert: String,
+    tls_cert: Option<String>,
 
     /// TLS Private key file path
     #[arg(long)]
-    tls_key: String,
+    tls_key: Option<String>,
 
     /// Initial congestion window size in bytes
     #[arg(long, default_value = None)]
     initial_congestion_window: Option<u32>,
+
+    /// Users file path with `username:password` lines, at least one, if not provided, anonymous access is allowed
+    #[arg(long, default_value = None)]
+    users: Option<String>,
+
+    /// Allow clients to listen on server ports for reverse port forwarding
+    #[arg(long)]
+    allow_bind: bool,
+
+    /// Allow clients to reach loopback, private and link-local destinations, denied by default
+    #[arg(long)]
+    allow_private: bool,
+
+    /// Local IP address of the connections to destinations
+    #[arg(long, value_name = "IP")]
+    outbound_bind: Option<IpAddr>,
+
+    /// Bytes per second relayed by all the streams together, in each direction
+    #[arg(long, value_name = "BYTES")]
+    rate_limit: Option<u64>,
+
+    /// Bytes per second relayed by all the streams of a QUIC connection together, in each direction
+    #[arg(long, value_name = "BYTES")]
+    connection_rate_limit: Option<u64>,
+
+    /// Bytes per second relayed by every stream on its own, in each direction
+    #[arg(long, value_name = "BYTES")]
+    stream_rate_limit: Option<u64>,
+
+    /// File the traffic of the users is saved to, if not provided, it is only counted in memory
+    #[arg(long, value_name = "FILE")]
+    usage_file: Option<String>,
+
+    /// Address of the Prometheus metrics endpoint e.g. 127.0.0.1:9090, served without authentication, if not provided, metrics are not served
+    #[arg(long, value_name = "ADDRESS")]
+    metrics: Option<SocketAddr>,
+
+    /// Seconds the streams in flight may take to finish on SIGINT or SIGTERM
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    drain_timeout: u64,
+
+    /// Logging level e.g. INFO WARN ERROR
+    #[arg(long, default_value = "WARN")]
+    tracing_level: tracing::Level,
+
+    /// Listeners of the configuration file as listening address, TLS certificate and key
+    #[arg(skip)]
+    listeners: Vec<(String, String, String)>,
+
+    /// Users of the configuration file
+    #[arg(skip)]
+    accounts: Vec<(String, String)>,
+
+    /// Destinations allowed and denied by the configuration file
+    #[arg(skip)]
+    acl: Option<AclConfig>,
+
+    /// Quotas of the configuration file
+    #[arg(skip)]
+    quotas: Option<QuotasConfig>,
+
+    #[arg(skip)]
+    resolver: ResolverBuilder,
+
+    #[arg(skip)]
+    limits: Limits,
+}
+
+impl Args {
+    /// Takes from the configuration file what is not given on the command line,
+    /// errors name the offending key
+    fn merge(&mut self, config: Config, matches: &ArgMatches) -> Result<(), String> {
+        let cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        set(
+            &mut self.tls_cert,
+            config.tls_cert.map(Some),
+            cli("tls_cert"),
+        );
+        set(&mut self.tls_key, config.tls_key.map(Some), cli("tls_key"));
+
+        if !cli("listen") {
+            for (index, listener) in config.listeners.into_iter().enumerate() {
+                let key = |name: &str| format!("listeners[{}].{}", index, name);
+
+                listener
+                    .listen
+                    .parse::<SocketAddr>()
+                    .map_err(|error| format!("{}: {}", key("listen"), error))?;
+
+                let tls_cert = listener
+                    .tls_cert
+                    .or_else(|| self.tls_cert.clone())
+                    .ok_or_else(|| format!("{}: missing", key("tls-cert")))?;
+                let tls_key = listener
+                    .tls_key
+                    .or_else(|| self.tls_key.clone())
+                    .ok_or_else(|| format!("{}: missing", key("tls-key")))?;
+
+                self.listeners.push((listener.listen, tls_cert, tls_key));
+            }
+        }
+
+        set(
+            &mut self.initial_congestion_window,
+            config.initial_congestion_window.map(Some),
+            cli("initial_congestion_window"),
+        );
+
+        let mut users = Users::default();
+
+        for (index, user) in config.users.into_iter().enumerate() {
+            users = users
+                .with_user(&user.username, &user.password)
+                .map_err(|error| format!("users[{}]: {}", index, error))?;
+
+            self.accounts.push((user.username, user.password));
+        }
+
+        set(&mut self.users, config.users_file.map(Some), cli("users"));
+        set(&mut self.allow_bind, config.allow_bind, cli("allow_bind"));
+        set(
+            &mut self.drain_timeout,
+            config.drain_timeout,
+            cli("drain_timeout"),
+        );
+
+        if let (Some(value), false) = (&config.outbound_bind, cli("outbound_bind")) {
+            self.outbound_bind = Some(
+                value
+                    .parse()
+                    .map_err(|error| format!("outbound-bind: {}", error))?,
+            );
+        }
+
+        if let (Some(value), false) = (&config.metrics, cli("metrics")) {
+            self.metrics = Some(
+                value
+                    .parse()
+                    .map_err(|error| format!("metrics: {}", error))?,
+            );
+        }
+
+        if let Some(acl) = config.acl {
+            self.acl = Some(acl);
+            load_acl(self)?;
+        }
+
+        for (value, key) in [
+            (config.rate_limit, "rate-limit"),
+            (config.connection_rate_limit, "connection-rate-limit"),
+            (config.stream_rate_limit, "stream-rate-limit"),
+        ] {
+            if let Some(value) = value {
+                positive(value, key)?;
+            }
+        }
+
+        set(
+            &mut self.rate_limit,
+            config.rate_limit.map(Some),
+            cli("rate_limit"),
+        );
+        set(
+            &mut self.connection_rate_limit,
+            config.connection_rate_limit.map(Some),
+            cli("connection_rate_limit"),
+        );
+        set(
+            &mut self.stream_rate_limit,
+            config.stream_rate_limit.map(Some),
+            cli("stream_rate_limit"),
+        );
+
+        set(
+            &mut self.usage_file,
+            config.usage_file.map(Some),
+            cli("usage_file"),
+        );
+
+        if let Some(quotas) = config.quotas {
+            let scopes =
+                std::iter::once(("quotas".to_string(), quotas.max_streams, quotas.rate_limit))
+                    .chain(quotas.users.iter().map(|(username, quota)| {
+                        let key = format!("quotas.users.{}", username);
+                        (key, quota.max_streams, quota.rate_limit)
+                    }));
+
+            for (key, max_streams, rate_limit) in scopes {
+                if let Some(value) = max_streams {
+                    positive(value as u64, &format!("{}.max-streams", key))?;
+                }
+
+                if let Some(value) = rate_limit {
+                    positive(value, &format!("{}.rate-limit", key))?;
+                }
+            }
+
+            self.quotas = Some(quotas);
+        }
+
+        if let Some(dns) = config.dns {
+            let mut resolver = ResolverBuilder::default();
+
+            for (index, server) in dns.servers.iter().enumerate() {
+                let address = match server.parse::<IpAddr>() {
+                    Ok(value) => SocketAddr::new(value, 53),
+                    Err(_) => server
+                        .parse::<SocketAddr>()
+                        .map_err(|error| format!("dns.servers[{}]: {}", index, error))?,
+                };
+
+                resolver = resolver.with_server(address);
+            }
+
+            if let Some(seconds) = dns.timeout {
+                positive(seconds, "dns.timeout")?;
+                resolver = resolver.with_timeout(Duration::from_secs(seconds));
+            }
+
+            if let Some(value) = &dns.strategy {
+                let strategy = value
+                    .parse::<Strategy>()
+                    .map_err(|error| format!("dns.strategy: {}", error))?;
+
+                resolver = resolver.with_strategy(strategy);
+            }
+
+            if let Some(value) = dns.cache_size {
+                resolver = resolver.with_cache_size(value);
+            }
+
+            self.resolver = resolver;
+        }
+
+        if let (Some(value), false) = (&config.tracing_level, cli("tracing_level")) {
+            self.tracing_level = value
+                .parse::<tracing::Level>()
+                .map_err(|error| format!("tracing-level: {}", error))?;
+        }
+
+        if let Some(limits) = config.limits {
+            for (value, key) in [
+                (limits.max_streams, "limits.max-streams"),
+                (limits.idle_timeout, "limits.idle-timeout"),
+                (limits.handshake_timeout, "limits.handshake-timeout"),
+                (limits.connect_timeout, "limits.connect-timeout"),
+            ] {
+                if let Some(value) = value {
+                    positive(value, key)?;
+                }
+            }
+
+            self.limits = limits;
+        }
+
+        Ok(())
+    }
+}
+
+/// Overwrites the target with the file value unless it was given on the command line
+fn set<T>(target: &mut T, value: Option<T>, cli: bool) {
+    if let (Some(value), false) = (value, cli) {
+        *target = value;
+    }
+}
+
+fn positive(value: u64, key: &str) -> Result<(), String> {
+    match value {
+        0 => Err(format!("{}: must be positive", key)),
+        _ => Ok(()),
+    }
+}
+
+/// Period of the saves of the traffic to the usage file
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time given to the endpoints to send the CONNECTION_CLOSE frames before exiting
+const CLOSE_DELAY: Duration = Duration::from_millis(100);
+
+/// Arguments merged with the configuration file, as read at startup and on reload
+fn load(matches: &ArgMatches) -> Result<Args, Box<dyn std::error::Error>> {
+    let mut args = Args::from_arg_matches(matches)?;
+
+    if let Some(path) = args.config.clone() {
+        let config = Config::load(&path).map_err(|error| format!("{}: {}", path, error))?;
+
+        args.merge(config, matches)
+            .map_err(|error| format!("{}: {}", path, error))?;
+    }
+
+    if let Some(listen) = args.listen.take() {
+        let tls_cert = args.tls_cert.clone().ok_or("--tls-cert is required")?;
+        let tls_key = args.tls_key.clone().ok_or("--tls-key is required")?;
+
+        args.listeners = vec![(listen, tls_cert, tls_key)];
+    }
+
+    if args.listeners.is_empty() {
+        return Err(
+            "no listening address, use --listen or [[listeners]] of the configuration file".into(),
+        );
+    }
+
+    Ok(args)
+}
+
+/// Users of the users file and of the configuration file
+fn load_users(args: &Args) -> Result<Users, Box<dyn std::error::Error>> {
+    let users = match &args.users {
+        Some(path) => Users::load(path).map_err(|error| format!("{}: {}", path, error))?,
+        None => Users::default(),
+    };
+
+    let users = args.accounts.iter().enumerate().try_fold(
+        users,
+        |users, (index, (username, password))| {
+            users
+                .with_user(username, password)
+                .map_err(|error| format!("users[{}]: {}", index, error))
+        },
+    )?;
+
+    Ok(users)
+}
+
+/// Rules of an ACL scope, errors name the key of the entry
+fn acl_rules(
+    key: &str,
+    allow: &[String],
+    deny: &[String],
+    allow_private: Option<bool>,
+    default: Option<&String>,
+) -> Result<Rules, String> {
+    let mut rules = Rules::default();
+
+    for (index, entry) in allow.iter().enumerate() {
+        rules = rules
+            .with_allow(entry)
+            .map_err(|error| format!("{}.allow[{}]: {}", key, index, error))?;
+    }
+
+    for (index, entry) in deny.iter().enumerate() {
+        rules = rules
+            .with_deny(entry)
+            .map_err(|error| format!("{}.deny[{}]: {}", key, index, error))?;
+    }
+
+    if let Some(value) = allow_private {
+        rules = rules.with_private(value);
+    }
+
+    match default.map(String::as_str) {
+        Some("allow") => rules = rules.with_default(true),
+        Some("deny") => rules = rules.with_default(false),
+        Some(value) => {
+            return Err(format!(
+                "{}.default: expected allow or deny, got '{}'",
+                key, value
+            ))
+        }
+        None => {}
+    }
+
+    Ok(rules)
+}
+
+/// Destinations the users may reach, the private address ranges are denied
+/// unless allowed
+fn load_acl(args: &Args) -> Result<Acl, String> {
+    let mut acl = Acl::default();
+    let mut global = Rules::default();
+
+    if let Some(config) = &args.acl {
+        global = acl_rules(
+            "acl",
+            &config.allow,
+            &config.deny,
+            config.allow_private,
+            config.default.as_ref(),
+        )?;
+
+        for (username, rules) in &config.users {
+            let rules = acl_rules(
+                &format!("acl.users.{}", username),
+                &rules.allow,
+                &rules.deny,
+                rules.allow_private,
+                rules.default.as_ref(),
+            )?;
+
+            acl = acl.with_user(username, rules);
+        }
+    }
+
+    if args.allow_private {
+        global = global.with_private(true);
+    }
+
+    Ok(acl.with_rules(global))
+}
+
+/// Quotas of the configuration file, none without it
+fn load_quotas(args: &Args) -> Quotas {
+    let quota = |daily: Option<u64>,
+                 monthly: Option<u64>,
+                 max_streams: Option<usize>,
+                 rate_limit: Option<u64>| {
+        let mut quota = Quota::default();
+
+        if let Some(bytes) = daily {
+            quota = quota.with_daily(bytes);
+        }
+
+        if let Some(bytes) = monthly {
+            quota = quota.with_monthly(bytes);
+        }
+
+        if let Some(value) = max_streams {
+            quota = quota.with_streams(value);
+        }
+
+        if let Some(value) = rate_limit {
+            quota = quota.with_rate(value);
+        }
+
+        quota
+    };
+
+    let mut quotas = Quotas::default();
+
+    if let Some(config) = &args.quotas {
+        quotas = quotas.with_quota(quota(
+            config.daily,
+            config.monthly,
+            config.max_streams,
+            config.rate_limit,
+        ));
+
+        for (username, config) in &config.users {
+            quotas = quotas.with_user(
+                username,
+                quota(
+                    config.daily,
+                    config.monthly,
+                    config.max_streams,
+                    config.rate_limit,
+                ),
+            );
+        }
+    }
+
+    quotas
+}
+
+/// Reads the users, the ACL, the quotas and the certificates again on every
+/// SIGHUP, other settings need a restart, anonymous access included. Nothing
+/// is replaced when the configuration is invalid.
+#[cfg(unix)]
+async fn reload(
+    matches: ArgMatches,
+    users: Users,
+    acl: Acl,
+    quotas: Quotas,
+    certificates: Vec<Certificate>,
+) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    while hangup.recv().await.is_some() {
+        let result = load(&matches).and_then(|args| {
+            let reloaded = (load_users(&args)?, load_acl(&args)?, load_quotas(&args));
+
+            if reloaded.0.is_empty() != users.is_empty() {
+                return Err(match users.is_empty() {
+                    true => "users added, restart to require authentication".into(),
+                    false => "no users, restart to allow anonymous access".into(),
+                });
+            }
+
+            if args.listeners.len() != certificates.len() {
+                return Err("listeners changed, restart to apply".into());
+            }
+
+            // Every certificate is read before any is replaced
+            let read = args
+                .listeners
+                .iter()
+                .map(|(listen, tls_cert, tls_key)| {
+                    Certificate::read(tls_cert, tls_key)
+                        .map_err(|error| format!("{}: {}", listen, error))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            users.replace(reloaded.0);
+            acl.replace(reloaded.1);
+            quotas.replace(reloaded.2);
+
+            for (certificate, other) in certificates.iter().zip(read) {
+                certificate.replace(other);
+            }
+
+            Ok(())
+        });
+
+        match result {
+            Ok(()) => info!("RELOAD configuration reloaded"),
+            Err(_error) => warn!("RELOAD failed, configuration kept: {}", _error),
+        }
+    }
+
+    Ok(())
+}
+
+/// Starts draining on SIGINT or SIGTERM
+async fn terminate(shutdown: Shutdown) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    shutdown.drain();
+
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = load(&matches)?;
+
+    #[cfg(feature = "trace")]
+    tracing_subscriber::fmt()
+        .with_thread_ids(true)
+        .with_max_level(args.tracing_level)
+        .init();
 
-    let server = {
-        use std::path::Path;
+    let users = load_users(&args)?;
+    let acl = load_acl(&args)?;
+    let quotas = load_quotas(&args);
 
-        use s2n_quic::provider::congestion_controller;
-        use s2n_quic::Server as NoiseServer;
+    let accounting = match &args.usage_file {
+        Some(path) => Accounting::load(path).map_err(|error| format!("{}: {}", path, error))?,
+        None => Accounting::default(),
+    };
+    let accounting = accounting.with_quotas(quotas.clone());
+
+    let shutdown = Shutdown::default();
+    let metrics = Metrics::new(shutdown.clone());
+
+    let mut connection_builder = None;
+    let mut certificates = Vec::new();
+
+    for (listen, tls_cert, tls_key) in &args.listeners {
+        let certificate = Certificate::read(tls_cert, tls_key)
+            .map_err(|error| format!("{}: {}", listen, error))?;
+
+        let server = s2n_quic_server::build(&args, listen, &certificate, &metrics)
+            .map_err(|error| format!("{}: {}", listen, error))?;
+
+        connection_builder = Some(match connection_builder {
+            Some(builder) => builder.with_server(server),
+            None => ConnectionBuilder::new(server),
+        });
+
+        certificates.push(certificate);
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(reload(
+        matches,
+        users.clone(),
+        acl.clone(),
+        quotas,
+        certificates,
+    ));
+
+    let connection = connection_builder.ok_or("no listening address")?.build();
+    let mut stream = StreamBuilder::new(connection).with_shutdown(shutdown.clone());
+
+    if let Some(rate) = args.connection_rate_limit {
+        stream = stream.with_connection_rate_limit(rate);
+    }
+
+    let stream = stream.build();
+
+    tokio::spawn(terminate(shutdown.clone()));
+    tokio::spawn(accounting.clone().persist(SAVE_INTERVAL));
+
+    if let Some(address) = args.metrics {
+        if !address.ip().is_loopback() {
+            warn!(
+                "metrics: {} is not a loopback address, the endpoint has no authentication",
+                address
+            );
+        }
+
+        let listener = TcpListener::bind(address)
+            .await
+            .map_err(|error| format!("{}: {}", address, error))?;
+
+        tokio::spawn(quics_server::metrics::serve(listener, metrics.clone()));
+    }
+
+    let resolver = std::mem::take(&mut args.resolver).build();
+    let resolver = Observed::new(resolver, metrics.clone());
+    let accountant = Observed::new(accounting.clone(), metrics.clone());
+
+    let authenticator = Observed::new(users, metrics.clone());
+
+    let mut server = Server::with(stream, resolver, authenticator, acl, accountant)
+        .with_observer(metrics)
+        .with_bind(args.allow_bind)
+        .with_outbound(args.outbound_bind);
+
+    if let Some(seconds) = args.limits.connect_timeout {
+        server = server.with_connect_timeout(Duration::from_secs(seconds));
+    }
+
+    if let Some(rate) = args.rate_limit {
+        server = server.with_rate_limit(rate);
+    }
+
+    if let Some(rate) = args.stream_rate_limit {
+        server = server.with_stream_rate_limit(rate);
+    }
 
+    server.start(shutdown.clone()).await;
+
+    let _ = tokio::time::timeout(Duration::from_secs(args.drain_timeout), shutdown.idle()).await;
+
+    shutdown.close();
+    tokio::time::sleep(CLOSE_DELAY).await;
+
+    if let Some(path) = &args.usage_file {
+        accounting
+            .save()
+            .map_err(|error| format!("{}: {}", path, error))?;
+    }
+
+    Ok(())
+}
+
+mod s2n_quic_server {
+    use std::error::Error;
+    use std::time::Duration;
+
+    use quics_server::connect::tls::Certificate;
+    use quics_server::metrics::{Metrics, Subscriber};
+    use s2n_quic::provider::{congestion_controller, limits};
+    use s2n_quic::Server as NoiseServer;
+
+    use super::Args;
+
+    pub fn build(
+        args: &Args,
+        listen: &str,
+        certificate: &Certificate,
+        metrics: &Metrics,
+    ) -> Result<NoiseServer, Box<dyn Error>> {
         let controller = {
             let controller = congestion_controller::bbr::Builder::default();
             let controller = match args.initial_congestion_window {
@@ -44,20 +720,42 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             controller.build()
         };
 
-        NoiseServer::builder()
-            .with_io(args.listen.as_str())?
+        let limits = {
+            let mut limits = limits::Limits::new();
+
+            if let Some(value) = args.limits.max_streams {
+                limits = limits.with_max_open_remote_bidirectional_streams(value)?;
+            }
+
+            if let Some(seconds) = args.limits.idle_timeout {
+                limits = limits.with_max_idle_timeout(Duration::from_secs(seconds))?;
+            }
+
+            if let Some(seconds) = args.limits.handshake_timeout {
+                limits = limits.with_max_handshake_duration(Duration::from_secs(seconds))?;
+            }
+
+            limits
+        };
+
+        let server = NoiseServer::builder()
+            .with_io(listen)?
             .with_congestion_controller(controller)?
-            .with_tls((
-                Path::new(args.tls_cert.as_str()),
-                Path::new(args.tls_key.as_str()),
-            ))?
-            .start()?
-    };
+            .with_limits(limits)?
+            .with_event(Subscriber::new(metrics.clone()))?;
 
-    let connection = ConnectionBuilder::new(server).build();
-    let stream = StreamBuilder::new(connection).build();
+        #[cfg(unix)]
+        let server = server.with_tls(certificate.provider())?.start()?;
 
-    Server::with(stream, Resolver::default()).start().await;
+        #[cfg(not(unix))]
+        let server = {
+            let (tls_cert, tls_key) = certificate.files();
 
-    Ok(())
+            server
+                .with_tls((tls_cert.as_path(), tls_key.as_path()))?
+                .start()?
+        };
+
+        Ok(server)
+    }
 }
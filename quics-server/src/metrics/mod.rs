@@ -0,0 +1,584 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use quics_protocol::auth::{Authentication, User};
+use quics_protocol::limit::Limit;
+use quics_protocol::request::{Address, Request};
+use quics_protocol::response::Response;
+use quics_protocol::shutdown::Shutdown;
+use quics_protocol::{Accountant, Authenticator, Limited, Meter, Observer, Resolver};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::error;
+
+/// Upper bounds in seconds of the buckets of the DNS lookup latencies
+const LOOKUP_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Upper bounds in seconds of the buckets of the QUIC round trips
+const RTT_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// Upper bound of the head of a scrape request
+const MAX_HEAD: usize = 8192;
+
+/// Upper bound of a scrape, from its request to the end of its response
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause after a failed accept, e.g. out of file descriptors
+const ACCEPT_DELAY: Duration = Duration::from_millis(100);
+
+/// Observations in buckets of durations, rendered cumulative
+struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    /// Microseconds
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+
+        if let Some(index) = self.bounds.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+
+        let mut cumulative = 0;
+
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum.load(Ordering::Relaxed) as f64 / 1e6;
+
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, help: &str, kind: &str, value: u64) {
+    header(out, name, help, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+struct Registry {
+    shutdown: Shutdown,
+    connections: AtomicU64,
+    open_connections: AtomicU64,
+    /// Requests by type and response
+    requests: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    /// Streams refused before any request, closing their connection
+    authentication_failures: AtomicU64,
+    lookups: Histogram,
+    lookup_failures: AtomicU64,
+    upload: AtomicU64,
+    download: AtomicU64,
+    rtt: Histogram,
+    /// Sum of the congestion windows of the open connections
+    congestion_window: AtomicU64,
+    lost_packets: AtomicU64,
+    lost_bytes: AtomicU64,
+}
+
+/// Counters and gauges of a server, exported in the Prometheus text format.
+/// Clones share the values.
+///
+/// Requests are counted once answered, by type and response, so that failures
+/// of `TCPConnect` are counted by reason. Streams in flight are the tasks
+/// tracked by the shutdown.
+#[derive(Clone)]
+pub struct Metrics(Arc<Registry>);
+
+impl Metrics {
+    pub fn new(shutdown: Shutdown) -> Self {
+        Self(Arc::new(Registry {
+            shutdown,
+            connections: AtomicU64::new(0),
+            open_connections: AtomicU64::new(0),
+            requests: Mutex::new(BTreeMap::new()),
+            authentication_failures: AtomicU64::new(0),
+            lookups: Histogram::new(LOOKUP_BUCKETS),
+            lookup_failures: AtomicU64::new(0),
+            upload: AtomicU64::new(0),
+            download: AtomicU64::new(0),
+            rtt: Histogram::new(RTT_BUCKETS),
+            congestion_window: AtomicU64::new(0),
+            lost_packets: AtomicU64::new(0),
+            lost_bytes: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn render(&self) -> String {
+        let registry = &*self.0;
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+
+        let mut out = String::new();
+
+        sample(
+            &mut out,
+            "quics_connections_total",
+            "QUIC connections established, their handshake confirmed",
+            "counter",
+            load(&registry.connections),
+        );
+        sample(
+            &mut out,
+            "quics_connections",
+            "QUIC connections open",
+            "gauge",
+            load(&registry.open_connections),
+        );
+        sample(
+            &mut out,
+            "quics_streams",
+            "Streams in flight",
+            "gauge",
+            registry.shutdown.tasks() as u64,
+        );
+
+        header(
+            &mut out,
+            "quics_requests_total",
+            "Requests answered, by type and response",
+            "counter",
+        );
+
+        for ((kind, response), count) in registry.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "quics_requests_total{{type=\"{}\",response=\"{}\"}} {}",
+                kind, response, count
+            );
+        }
+
+        sample(
+            &mut out,
+            "quics_authentication_failures_total",
+            "Streams of clients failing to authenticate, whose connections were closed",
+            "counter",
+            load(&registry.authentication_failures),
+        );
+
+        registry.lookups.render(
+            &mut out,
+            "quics_dns_lookup_duration_seconds",
+            "Latency of the lookups of the domains requested",
+        );
+        sample(
+            &mut out,
+            "quics_dns_lookup_failures_total",
+            "Lookups of the domains requested that failed",
+            "counter",
+            load(&registry.lookup_failures),
+        );
+
+        header(
+            &mut out,
+            "quics_relayed_bytes_total",
+            "Bytes uploaded by the clients and downloaded to them",
+            "counter",
+        );
+        let _ = writeln!(
+            out,
+            "quics_relayed_bytes_total{{direction=\"upload\"}} {}",
+            load(&registry.upload)
+        );
+        let _ = writeln!(
+            out,
+            "quics_relayed_bytes_total{{direction=\"download\"}} {}",
+            load(&registry.download)
+        );
+
+        registry.rtt.render(
+            &mut out,
+            "quics_quic_rtt_seconds",
+            "Round trips sampled on the QUIC connections",
+        );
+        sample(
+            &mut out,
+            "quics_quic_congestion_window_bytes",
+            "Congestion windows of the QUIC connections open, summed",
+            "gauge",
+            load(&registry.congestion_window),
+        );
+        sample(
+            &mut out,
+            "quics_quic_lost_packets_total",
+            "Packets declared lost on the QUIC connections",
+            "counter",
+            load(&registry.lost_packets),
+        );
+        sample(
+            &mut out,
+            "quics_quic_lost_bytes_total",
+            "Bytes of the packets declared lost on the QUIC connections",
+            "counter",
+            load(&registry.lost_bytes),
+        );
+
+        out
+    }
+}
+
+impl Observer for Metrics {
+    fn observe(&self, request: &Request, response: &Response) {
+        let kind = match request {
+            Request::TCPConnect(_) => "tcp_connect",
+            Request::UDPAssociate => "udp_associate",
+            Request::TCPBind(_) => "tcp_bind",
+            Request::Ping => "ping",
+        };
+
+        let response = match response {
+            Response::Succeed => "succeed",
+            Response::Connected(_) => "connected",
+            Response::AuthenticationFailed => "authentication_failed",
+            Response::GeneralFailure => "general_failure",
+            Response::NotAllowed => "not_allowed",
+            Response::NetworkUnreachable => "network_unreachable",
+            Response::HostUnreachable => "host_unreachable",
+            Response::ConnectionRefused => "connection_refused",
+            Response::TimedOut => "timed_out",
+            Response::HostUnresolved => "host_unresolved",
+            Response::QuotaExceeded => "quota_exceeded",
+            Response::NoAcceptableMethod => "no_acceptable_method",
+        };
+
+        *self
+            .0
+            .requests
+            .lock()
+            .unwrap()
+            .entry((kind, response))
+            .or_default() += 1;
+    }
+}
+
+/// Resolver timing its lookups, accountant counting the bytes of its meters, or
+/// authenticator counting its failures, feeding the metrics
+#[derive(Clone)]
+pub struct Observed<T> {
+    inner: T,
+    metrics: Metrics,
+}
+
+impl<T> Observed<T> {
+    pub fn new(inner: T, metrics: Metrics) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+impl<R> Resolver for Observed<R>
+where
+    R: Resolver + Sync,
+{
+    async fn lookup(&self, domain: &str, port: u16) -> Result<SocketAddr> {
+        let registry = &*self.metrics.0;
+        let start = Instant::now();
+
+        let result = self.inner.lookup(domain, port).await;
+
+        registry.lookups.observe(start.elapsed());
+
+        if result.is_err() {
+            registry.lookup_failures.fetch_add(1, Ordering::Relaxed);
+        }
+
+        result
+    }
+}
+
+impl<A> Authenticator for Observed<A>
+where
+    A: Authenticator + Sync,
+{
+    async fn authenticate(&self, authentication: &Authentication) -> Option<User> {
+        let user = self.inner.authenticate(authentication).await;
+
+        if user.is_none() {
+            let registry = &*self.metrics.0;
+            registry
+                .authentication_failures
+                .fetch_add(1, Ordering::Relaxed);
+        }
+
+        user
+    }
+}
+
+impl<C> Accountant for Observed<C>
+where
+    C: Accountant,
+{
+    type Meter = Observed<C::Meter>;
+
+    fn admit(&self, user: &User) -> Option<Self::Meter> {
+        self.inner
+            .admit(user)
+            .map(|meter| Observed::new(meter, self.metrics.clone()))
+    }
+}
+
+impl<M> Meter for Observed<M>
+where
+    M: Meter,
+{
+    fn count(&self, destination: &Address, upload: u64, download: u64) {
+        let registry = &*self.metrics.0;
+
+        registry.upload.fetch_add(upload, Ordering::Relaxed);
+        registry.download.fetch_add(download, Ordering::Relaxed);
+
+        self.inner.count(destination, upload, download);
+    }
+}
+
+impl<M> Limited for Observed<M>
+where
+    M: Limited,
+{
+    fn limit(&self) -> Option<Limit> {
+        self.inner.limit()
+    }
+}
+
+/// Answers `GET /metrics` with the metrics, any other request with 404
+pub async fn serve(listener: TcpListener, metrics: Metrics) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_error) => {
+                error!("metrics: {}", _error);
+                tokio::time::sleep(ACCEPT_DELAY).await;
+                continue;
+            }
+        };
+
+        let metrics = metrics.clone();
+
+        tokio::spawn(async move {
+            let _ = tokio::time::timeout(SCRAPE_TIMEOUT, scrape(stream, &metrics)).await;
+        });
+    }
+}
+
+async fn scrape(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD {
+            return Err(Error::new(ErrorKind::InvalidData, "request head too large"));
+        }
+
+        let size = stream.read(&mut buffer).await?;
+
+        if size == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "request head incomplete",
+            ));
+        }
+
+        head.extend_from_slice(&buffer[..size]);
+    }
+
+    let line =
+        String::from_utf8_lossy(head.split(|byte| *byte == b'\n').next().unwrap_or_default());
+    let path = line
+        .split_whitespace()
+        .nth(1)
+        .map(|target| target.split('?').next().unwrap_or_default());
+
+    let (status, body) = match (line.starts_with("GET "), path) {
+        (true, Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Event subscriber feeding the metrics with the QUIC connections of a server
+pub struct Subscriber {
+    metrics: Metrics,
+}
+
+impl Subscriber {
+    pub fn new(metrics: Metrics) -> Self {
+        Self { metrics }
+    }
+}
+
+/// Last congestion window of a connection, taken off the sum once closed
+#[derive(Debug, Default)]
+pub struct Window {
+    congestion_window: u64,
+}
+
+mod s2n_quic {
+    use std::sync::atomic::Ordering;
+
+    use s2n_quic::provider::event::{
+        events, ConnectionInfo, ConnectionMeta, Subscriber as EventSubscriber,
+    };
+
+    use super::{Subscriber, Window};
+
+    impl EventSubscriber for Subscriber {
+        type ConnectionContext = Window;
+
+        fn create_connection_context(
+            &mut self,
+            _meta: &ConnectionMeta,
+            _info: &ConnectionInfo,
+        ) -> Self::ConnectionContext {
+            let registry = &*self.metrics.0;
+
+            registry.open_connections.fetch_add(1, Ordering::Relaxed);
+
+            Window::default()
+        }
+
+        /// Handshakes failing, e.g. on TLS, are not counted as connections
+        fn on_handshake_status_updated(
+            &mut self,
+            _context: &mut Self::ConnectionContext,
+            _meta: &ConnectionMeta,
+            event: &events::HandshakeStatusUpdated,
+        ) {
+            if let events::HandshakeStatus::Confirmed { .. } = event.status {
+                let registry = &*self.metrics.0;
+
+                registry.connections.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        fn on_recovery_metrics(
+            &mut self,
+            context: &mut Self::ConnectionContext,
+            _meta: &ConnectionMeta,
+            event: &events::RecoveryMetrics,
+        ) {
+            let registry = &*self.metrics.0;
+            let window = event.congestion_window as u64;
+
+            registry.rtt.observe(event.latest_rtt);
+
+            // The sum moves by the change of the window of the connection
+            registry
+                .congestion_window
+                .fetch_add(window, Ordering::Relaxed);
+            registry
+                .congestion_window
+                .fetch_sub(context.congestion_window, Ordering::Relaxed);
+
+            context.congestion_window = window;
+        }
+
+        fn on_packet_lost(
+            &mut self,
+            _context: &mut Self::ConnectionContext,
+            _meta: &ConnectionMeta,
+            event: &events::PacketLost,
+        ) {
+            let registry = &*self.metrics.0;
+
+            registry.lost_packets.fetch_add(1, Ordering::Relaxed);
+            registry
+                .lost_bytes
+                .fetch_add(event.bytes_lost as u64, Ordering::Relaxed);
+        }
+
+        fn on_connection_closed(
+            &mut self,
+            context: &mut Self::ConnectionContext,
+            _meta: &ConnectionMeta,
+            _event: &events::ConnectionClosed,
+        ) {
+            let registry = &*self.metrics.0;
+
+            registry.open_connections.fetch_sub(1, Ordering::Relaxed);
+            registry
+                .congestion_window
+                .fetch_sub(context.congestion_window, Ordering::Relaxed);
+
+            context.congestion_window = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quics_protocol::Authenticator;
+
+    use super::*;
+    use crate::auth::Users;
+
+    #[tokio::test]
+    async fn authentication_failures() {
+        let metrics = Metrics::new(Shutdown::default());
+        let users = Users::default().with_user("alice", "secret").unwrap();
+        let authenticator = Observed::new(users, metrics.clone());
+
+        let password = |password: &str| Authentication::Password("alice".into(), password.into());
+
+        assert!(authenticator
+            .authenticate(&password("secret"))
+            .await
+            .is_some());
+        assert!(authenticator
+            .authenticate(&password("wrong"))
+            .await
+            .is_none());
+        assert!(authenticator
+            .authenticate(&Authentication::None)
+            .await
+            .is_none());
+
+        assert!(metrics
+            .render()
+            .lines()
+            .any(|line| line == "quics_authentication_failures_total 2"));
+    }
+}
@@ -0,0 +1,86 @@
+#!/bin/sh
+# End-to-end test of the TUN inbound of the client, run as root:
+#
+#   scripts/tun-netns.sh [DIRECTORY OF THE BINARIES]
+#
+# The server runs in the current network namespace on one end of a veth pair,
+# the client in a namespace of its own on the other end. Every packet of that
+# namespace is routed to the TUN interface but the ones of the client, which
+# carry its firewall mark, so that its connections to the server do not loop
+# back into the tunnel.
+set -eu
+
+BIN=${1:-target/debug}
+NS=quics-test
+MARK=81
+DIR=$(mktemp -d)
+PIDS=""
+
+cleanup() {
+    for pid in $PIDS; do
+        kill "$pid" 2>/dev/null || true
+    done
+
+    ip netns del "$NS" 2>/dev/null || true
+    ip link del quics-veth0 2>/dev/null || true
+    rm -rf "$DIR"
+}
+
+trap cleanup EXIT
+
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes -days 1 \
+    -subj /CN=quics.test -addext subjectAltName=DNS:quics.test \
+    -keyout "$DIR/key.pem" -out "$DIR/cert.pem" 2>/dev/null
+
+ip netns add "$NS"
+ip link add quics-veth0 type veth peer name quics-veth1
+ip link set quics-veth1 netns "$NS"
+ip addr add 10.200.0.1/24 dev quics-veth0
+ip link set quics-veth0 up
+ip netns exec "$NS" ip addr add 10.200.0.2/24 dev quics-veth1
+ip netns exec "$NS" ip link set quics-veth1 up
+ip netns exec "$NS" ip link set lo up
+
+# Destinations, reached from the namespace through the server
+head -c 1048576 /dev/urandom >"$DIR/blob"
+python3 -m http.server 8000 --bind 10.200.0.1 --directory "$DIR" >/dev/null 2>&1 &
+PIDS="$PIDS $!"
+
+python3 -c '
+import socket
+s = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+s.bind(("10.200.0.1", 9000))
+while True:
+    data, peer = s.recvfrom(2048)
+    s.sendto(b"echo:" + data, peer)
+' &
+PIDS="$PIDS $!"
+
+"$BIN/quics-server" -l 10.200.0.1:4433 --tls-cert "$DIR/cert.pem" --tls-key "$DIR/key.pem" \
+    --allow-private &
+PIDS="$PIDS $!"
+
+ip netns exec "$NS" "$BIN/quics-client" -r 10.200.0.1:4433 --tls-sni quics.test \
+    --tls-cert "$DIR/cert.pem" --inbound none --tun tun0 --tun-address 198.18.0.1/16 \
+    --fwmark "$MARK" &
+PIDS="$PIDS $!"
+
+sleep 2
+
+ip netns exec "$NS" ip route add default dev tun0 table 100
+ip netns exec "$NS" ip rule add not fwmark "$MARK" table 100 priority 100
+
+ip netns exec "$NS" curl -sf -m 10 -o "$DIR/download" http://10.200.0.1:8000/blob
+cmp "$DIR/blob" "$DIR/download"
+echo "TCP through the TUN interface: ok"
+
+ip netns exec "$NS" python3 -c '
+import socket
+s = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+s.settimeout(5)
+for message in (b"hello", b"again"):
+    s.sendto(message, ("10.200.0.1", 9000))
+    data, _ = s.recvfrom(2048)
+    assert data == b"echo:" + message, data
+'
+echo "UDP through the TUN interface: ok"